@@ -1,3 +1,5 @@
+#![allow(non_snake_case)]
+
 extern crate rustfs;
 extern crate rand;
 #[macro_use]
@@ -8,10 +10,11 @@ use criterion::Criterion;
 use rustfs::{Vfs, FileFlags, FileDescriptor};
 use std::string::String;
 use rand::random;
-use std::iter::repeat;
+use std::iter::repeat_n;
 
 static NUM: usize = 100;
 
+#[allow(unused_macros)]
 macro_rules! bench {
   ($wrap:ident, $name:ident, $time:expr, |$p:ident, $filenames:ident| $task:stmt) => ({
     let $filenames = generate_names(NUM);
@@ -26,114 +29,108 @@ macro_rules! bench {
 }
 
 fn open_close_one() {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let fd = p.open("test", FileFlags::O_CREAT).unwrap();
     p.close(fd);
 }
 
 fn open_close_unlink() {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    close_all(&mut p, &fds);
-    unlink_all(&mut p, &filenames);
+    let fds = open_many(&p, &filenames);
+    close_all(&p, &fds);
+    unlink_all(&p, &filenames);
 }
 
 fn open_write_close_unlink(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
-        p.write(fd, content);
+        p.write(fd, content).unwrap();
         p.close(fd);
     }
 }
 
 fn open_write_large_close(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
-        p.write(fd, content);
+        p.write(fd, content).unwrap();
         p.close(fd);
     }
 }
 
 fn open_write_large_close_unlink(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
-        p.write(fd, content);
+        p.write(fd, content).unwrap();
         p.close(fd);
-        p.unlink(filename);
+        p.unlink(filename).unwrap();
     }
 }
 
 fn open_write_modify_small_close(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
         for _ in 0..100 {
-            p.write(fd, content);
+            p.write(fd, content).unwrap();
         }
         p.close(fd);
     }
 }
 
 fn open_write_modify_small_close_unlink(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
         for _ in 0..100 {
-            p.write(fd, content);
+            p.write(fd, content).unwrap();
         }
         p.close(fd);
-        p.unlink(filename);
+        p.unlink(filename).unwrap();
     }
 }
 
 fn open_write_modify_big_close(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
         for _ in 0..32 {
-            p.write(fd, content);
+            p.write(fd, content).unwrap();
         }
         p.close(fd);
     }
 }
 
 fn open_write_modify_big_close_unlink(content: &[u8]) {
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let filenames = generate_names(NUM);
-    let fds = open_many(&mut p, &filenames);
-    for i in 0..NUM {
-        let filename = &filenames[i];
+    open_many(&p, &filenames);
+    for filename in filenames.iter() {
         let fd = p.open(filename, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
         for _ in 0..32 {
-            p.write(fd, content);
+            p.write(fd, content).unwrap();
         }
         p.close(fd);
-        p.unlink(filename);
+        p.unlink(filename).unwrap();
     }
 }
 
+#[allow(unused_macros)]
 macro_rules! bench_many {
   ($wrap:ident, $name:ident, $time:expr, |$p:ident, $fd:ident, $filename:ident| $op:stmt) => ({
     let filenames = generate_names(NUM);
@@ -151,17 +148,13 @@ macro_rules! bench_many {
   })
 }
 
-fn ceil_div(x:usize, y: usize) -> usize {
-    return (x + y - 1) / y;
-}
-
 fn rand_array(size: usize) -> Vec<u8> {
   (0..size).map(|_| random::<u8>()).collect()
 }
 
 fn generate_names(n: usize) -> Vec<String> {
-  let name_length = ceil_div(n, 26);
-  let mut name: Vec<_> = repeat('@' as u8).take(name_length).collect();
+  let name_length = n.div_ceil(26);
+  let mut name: Vec<_> = repeat_n(b'@', name_length).collect();
 
   (0..n).map(|i| {
     let next = name[i / 26] + 1;
@@ -175,31 +168,30 @@ fn generate_names(n: usize) -> Vec<String> {
   }).collect()
 }
 
-fn open_many<'a>(p: &mut Vfs<'a>, names: &'a Vec<String>) -> Vec<FileDescriptor> {
-  (0..names.len()).map(|i| {
-    let fd = p.open(&names[i], FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
-    fd
+fn open_many<'a>(p: &Vfs<'a>, names: &'a [String]) -> Vec<FileDescriptor> {
+  names.iter().map(|name| {
+    p.open(name, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap()
   }).collect()
 }
 
-fn close_all(p: &mut Vfs, fds: &Vec<FileDescriptor>) {
+fn close_all(p: &Vfs, fds: &[FileDescriptor]) {
   for fd in fds.iter() {
     p.close(*fd);
   }
 }
 
-fn unlink_all<'a>(p: &mut Vfs<'a>, names: &'a Vec<String>) {
+fn unlink_all<'a>(p: &Vfs<'a>, names: &'a [String]) {
   for filename in names.iter() {
-    p.unlink(&filename);
+    p.unlink(filename).unwrap();
   }
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("Open Close 1", |b| b.iter(|| open_close_one()));
+    c.bench_function("Open Close 1", |b| b.iter(open_close_one));
 }
 
 fn bench_OtCtU(c: &mut Criterion) {
-    c.bench_function("Open Close Unlink", |b| b.iter(|| open_close_unlink()));
+    c.bench_function("Open Close Unlink", |b| b.iter(open_close_unlink));
 }
 
 fn bench_OWsCU(c: &mut Criterion) {
@@ -244,15 +236,15 @@ fn bench_OWMbCU(c: &mut Criterion) {
     c.bench_function("Open Write Modify big Close Unlink", move |b| b.iter(|| open_write_modify_big_close_unlink(&content)));
 }
 
-criterion_group!(benches, criterion_benchmark, bench_OtCtU, bench_OWsCU, bench_OWbC, bench_OWbCU, bench_OWMsC, bench_OWMsCU, bench_OWMbC);
+criterion_group!(benches, criterion_benchmark, bench_OtCtU, bench_OWsCU, bench_OWbC, bench_OWbCU, bench_OWMsC, bench_OWMsCU, bench_OWMbC, bench_OWMbCU);
 criterion_main!(benches);
 
 //#[allow(non_snake_case)]
 //fn main() {
 //
 //  bench!(bench_OtC, OtC, 100, |p, filenames| {
-//    let fds = open_many(&mut p, &filenames);
-//    close_all(&mut p, &fds);
+//    let fds = open_many(&p, &filenames);
+//    close_all(&p, &fds);
 //  });
 //
 //  bench_many!(bench_OC, OC, 100, |p, fd, _f| {
@@ -0,0 +1,92 @@
+#![allow(non_snake_case)]
+
+extern crate rustfs;
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Bencher, Criterion};
+use rustfs::{Vfs, FileFlags, FileDescriptor};
+use std::io::{IoSlice, IoSliceMut};
+use std::thread;
+
+// Each thread works on its own file, named by its index.
+static NAMES: [&str; 8] = ["0", "1", "2", "3", "4", "5", "6", "7"];
+static THREADS: [usize; 4] = [1, 2, 4, 8];
+static OPS: usize = 1000;
+static SIZE: usize = 4096;
+
+fn setup(p: &Vfs<'static>, threads: usize) -> Vec<FileDescriptor> {
+    NAMES[..threads].iter().map(|name| {
+        let fd = p.open(name, FileFlags::O_CREAT | FileFlags::O_RDWR).unwrap();
+        p.write(fd, &[1u8; SIZE]).unwrap();
+        fd
+    }).collect()
+}
+
+// Every thread does the same amount of work, so with perfect scaling the
+// time per iteration stays flat as threads are added.
+fn in_parallel<F: Fn(FileDescriptor) + Sync>(fds: &[FileDescriptor], op: F) {
+    thread::scope(|s| {
+        for &fd in fds {
+            let op = &op;
+            s.spawn(move || {
+                for _ in 0..OPS {
+                    op(fd);
+                }
+            });
+        }
+    });
+}
+
+fn read_own_files(b: &mut Bencher, threads: &usize) {
+    let p = Vfs::new();
+    let fds = setup(&p, *threads);
+    b.iter(|| in_parallel(&fds, |fd| {
+        let mut buf = [0u8; SIZE];
+        p.preadv(fd, &mut [IoSliceMut::new(&mut buf)], 0).unwrap();
+    }));
+}
+
+fn write_own_files(b: &mut Bencher, threads: &usize) {
+    let p = Vfs::new();
+    let fds = setup(&p, *threads);
+    b.iter(|| in_parallel(&fds, |fd| {
+        p.pwritev(fd, &[IoSlice::new(&[2u8; SIZE])], 0).unwrap();
+    }));
+}
+
+fn read_shared_file(b: &mut Bencher, threads: &usize) {
+    let p = Vfs::new();
+    setup(&p, 1);
+    // One handle per thread, all on the same inode.
+    let fds: Vec<_> = (0..*threads).map(|_| p.open(NAMES[0], FileFlags::O_RDONLY).unwrap()).collect();
+    b.iter(|| in_parallel(&fds, |fd| {
+        let mut buf = [0u8; SIZE];
+        p.preadv(fd, &mut [IoSliceMut::new(&mut buf)], 0).unwrap();
+    }));
+}
+
+fn lookup_while_creating(b: &mut Bencher, threads: &usize) {
+    let p = Vfs::new();
+    p.mkdir("busy", 0o755).unwrap();
+    let fds = setup(&p, *threads);
+    b.iter(|| in_parallel(&fds, |fd| {
+        // Odd threads churn a directory; even ones look up their own files.
+        if fd % 2 == 1 {
+            p.close(p.open("busy/file", FileFlags::O_CREAT).unwrap());
+            p.unlink("busy/file").unwrap_or(());
+        } else {
+            p.stat(NAMES[0]).unwrap();
+        }
+    }));
+}
+
+fn bench_parallel(c: &mut Criterion) {
+    c.bench_function_over_inputs("Parallel Read Own Files", read_own_files, THREADS.to_vec());
+    c.bench_function_over_inputs("Parallel Write Own Files", write_own_files, THREADS.to_vec());
+    c.bench_function_over_inputs("Parallel Read Shared File", read_shared_file, THREADS.to_vec());
+    c.bench_function_over_inputs("Parallel Lookup While Creating", lookup_while_creating, THREADS.to_vec());
+}
+
+criterion_group!(benches, bench_parallel);
+criterion_main!(benches);
@@ -0,0 +1,237 @@
+use std::future;
+use std::io::Result;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use crate::{Vfs, FileDescriptor, FileFlags, FileLock, FlockOp, LockCmd, DirEntry};
+use crate::pipe::is_blocked;
+
+impl<'r> Vfs<'r> {
+  // Has `waker` woken once whatever `fd` blocks on might have moved.
+  fn park(&self, fd: FileDescriptor, waker: &Waker) -> Result<()> {
+    self.get_handle(fd)?.park(waker);
+    Ok(())
+  }
+}
+
+/// An async facade over a `Vfs`, for callers that mustn't block a thread.
+/// Its futures do their work when polled and work with any executor.
+///
+/// Where a `Vfs` call would block, as reading an empty pipe or `SetLkW` on
+/// a taken lock do, the future parks its task instead, to be woken once the
+/// pipe or the lock table changes. Descriptors opened with `O_NONBLOCK`
+/// still fail with EAGAIN rather than parking. Everything else completes
+/// on the first poll; use `vfs` for the operations not mirrored here.
+#[derive(Clone)]
+pub struct AsyncVfs<'r> {
+  vfs: Arc<Vfs<'r>>
+}
+
+impl<'r> From<Arc<Vfs<'r>>> for AsyncVfs<'r> {
+  fn from(vfs: Arc<Vfs<'r>>) -> AsyncVfs<'r> {
+    AsyncVfs { vfs }
+  }
+}
+
+impl<'r> AsyncVfs<'r> {
+  pub fn new(vfs: Vfs<'r>) -> AsyncVfs<'r> {
+    AsyncVfs::from(Arc::new(vfs))
+  }
+
+  pub fn vfs(&self) -> &Arc<Vfs<'r>> {
+    &self.vfs
+  }
+
+  pub async fn open(&self, path: &'r str, flags: FileFlags) -> Result<FileDescriptor> {
+    self.vfs.open(path, flags)
+  }
+
+  pub async fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> Result<usize> {
+    future::poll_fn(|cx| self.poll_io(cx, fd, || self.vfs.read(fd, dst))).await
+  }
+
+  pub async fn write(&self, fd: FileDescriptor, src: &[u8]) -> Result<usize> {
+    future::poll_fn(|cx| self.poll_io(cx, fd, || self.vfs.write(fd, src))).await
+  }
+
+  pub async fn fsync(&self, fd: FileDescriptor) -> Result<()> {
+    self.vfs.fsync(fd)
+  }
+
+  pub async fn read_dir(&self, path: &'r str) -> Result<Vec<DirEntry<'r>>> {
+    self.vfs.read_dir(path)
+  }
+
+  pub async fn flock(&self, fd: FileDescriptor, op: FlockOp) -> Result<()> {
+    future::poll_fn(|cx| {
+      parked(self.vfs.flock_parked(fd, op, Some(cx.waker())))
+    }).await
+  }
+
+  pub async fn fcntl_lock(&self, fd: FileDescriptor, cmd: LockCmd, lock: &mut FileLock) -> Result<()> {
+    // Dropping the future while it waits must not leave the process counted
+    // as waiting, or later requests could see deadlocks that aren't there.
+    struct Waiting<'a, 'r>(&'a Vfs<'r>, u32);
+    impl<'a, 'r> Drop for Waiting<'a, 'r> {
+      fn drop(&mut self) {
+        self.0.locks.cancel(self.1);
+      }
+    }
+
+    let _waiting = Waiting(&self.vfs, lock.pid);
+    future::poll_fn(|cx| {
+      parked(self.vfs.fcntl_lock_parked(fd, cmd, lock, Some(cx.waker())))
+    }).await
+  }
+
+  // Runs `op`, parking the task on `fd` if it would block. The task is
+  // parked before `op` is retried, so a change in between isn't missed.
+  fn poll_io<T, F: FnMut() -> Result<T>>(&self, cx: &mut Context, fd: FileDescriptor, mut op: F)
+      -> Poll<Result<T>> {
+    match op() {
+      Err(ref e) if is_blocked(e) => {}
+      result => return Poll::Ready(result)
+    }
+
+    if let Err(e) = self.vfs.park(fd, cx.waker()) {
+      return Poll::Ready(Err(e));
+    }
+    parked(op())
+  }
+}
+
+// A call that parked its task is pending; anything else is done.
+fn parked<T>(result: Result<T>) -> Poll<Result<T>> {
+  match result {
+    Err(ref e) if is_blocked(e) => Poll::Pending,
+    result => Poll::Ready(result)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::AsyncVfs;
+  use crate::{Vfs, FileFlags, FileLock, FileType, LockCmd, LockType, Whence};
+  use std::cell::RefCell;
+  use std::future::Future;
+  use std::pin::Pin;
+  use std::rc::Rc;
+  use std::sync::{Arc, Mutex};
+  use std::task::{Context, Poll, Wake, Waker};
+
+  // Wakes a task by queueing its index.
+  struct TaskWaker(usize, Arc<Mutex<Vec<usize>>>);
+
+  impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+      self.1.lock().unwrap().push(self.0);
+    }
+  }
+
+  // A single-threaded executor: polls woken tasks in turn until all are
+  // done, and panics if they're all stuck.
+  fn run(tasks: Vec<Pin<Box<dyn Future<Output = ()> + '_>>>) {
+    let mut tasks: Vec<_> = tasks.into_iter().map(Some).collect();
+    let queue = Arc::new(Mutex::new((0..tasks.len()).collect::<Vec<_>>()));
+    while tasks.iter().any(Option::is_some) {
+      let woken: Vec<_> = queue.lock().unwrap().drain(..).collect();
+      assert!(!woken.is_empty(), "every task is parked");
+      for i in woken {
+        let waker = Waker::from(Arc::new(TaskWaker(i, queue.clone())));
+        let done = match tasks[i] {
+          Some(ref mut task) => task.as_mut().poll(&mut Context::from_waker(&waker)).is_ready(),
+          None => false
+        };
+        if done {
+          tasks[i] = None;
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_pipe_read_parks() {
+    let p = AsyncVfs::new(Vfs::new());
+    let (read_fd, write_fd) = p.vfs().pipe().unwrap();
+    let got = Rc::new(RefCell::new(Vec::new()));
+
+    // The reader runs first and finds the pipe empty.
+    let reader = {
+      let (p, got) = (&p, got.clone());
+      async move {
+        let mut buf = [0u8; 16];
+        let len = p.read(read_fd, &mut buf).await.unwrap();
+        got.borrow_mut().extend_from_slice(&buf[..len]);
+        assert_eq!(p.read(read_fd, &mut buf).await.unwrap(), 0);
+      }
+    };
+    let writer = async {
+      assert_eq!(p.write(write_fd, b"hello").await.unwrap(), 5);
+      p.vfs().close(write_fd);
+    };
+
+    run(vec![Box::pin(reader), Box::pin(writer)]);
+    assert_eq!(&got.borrow()[..], b"hello");
+  }
+
+  #[test]
+  fn test_lock_parks() {
+    let p = AsyncVfs::new(Vfs::new());
+    let lock = |kind, pid| FileLock { kind, whence: Whence::SeekSet, start: 0, len: 0, pid };
+    let order = Rc::new(RefCell::new(Vec::new()));
+
+    let first = {
+      let (p, order) = (&p, order.clone());
+      async move {
+        let fd = p.open("lock", FileFlags::O_RDWR | FileFlags::O_CREAT).await.unwrap();
+        p.fcntl_lock(fd, LockCmd::SetLkW, &mut lock(LockType::Write, 1)).await.unwrap();
+        order.borrow_mut().push(1);
+        // Let the other task queue up behind the lock before dropping it.
+        let mut yielded = false;
+        std::future::poll_fn(|cx| {
+          if yielded {
+            return Poll::Ready(());
+          }
+          yielded = true;
+          cx.waker().wake_by_ref();
+          Poll::Pending
+        }).await;
+        p.fsync(fd).await.unwrap();
+        p.vfs().close(fd);
+      }
+    };
+    let second = {
+      let (p, order) = (&p, order.clone());
+      async move {
+        let fd = p.open("lock", FileFlags::O_RDWR).await.unwrap();
+        p.fcntl_lock(fd, LockCmd::SetLkW, &mut lock(LockType::Write, 2)).await.unwrap();
+        order.borrow_mut().push(2);
+      }
+    };
+
+    run(vec![Box::pin(first), Box::pin(second)]);
+    assert_eq!(&order.borrow()[..], &[1, 2]);
+  }
+
+  #[test]
+  fn test_read_dir() {
+    fn sendable<T: Send>(_: &T) {}
+    let p = AsyncVfs::new(Vfs::new());
+    p.vfs().mkdir("dir", 0o755).unwrap();
+    p.vfs().mkfifo("dir/fifo", 0o644).unwrap();
+    p.vfs().open("dir/file", FileFlags::O_CREAT).unwrap();
+
+    let listing = p.read_dir("dir");
+    sendable(&listing);
+    let entries = Rc::new(RefCell::new(Vec::new()));
+    let task = {
+      let entries = entries.clone();
+      async move { *entries.borrow_mut() = listing.await.unwrap(); }
+    };
+    run(vec![Box::pin(task)]);
+
+    let entries = entries.borrow();
+    let names: Vec<_> = entries.iter().map(|e| (e.name, e.file_type)).collect();
+    assert_eq!(names, [("fifo", FileType::Fifo), ("file", FileType::RegularFile)]);
+    assert_eq!(entries[1].ino, p.vfs().stat("dir/file").unwrap().ino);
+  }
+}
@@ -0,0 +1,113 @@
+use std::io::{Result, Error, IoSlice, IoSliceMut};
+use libc::ENOSPC;
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+// Device numbers of the built-in devices, the same as Linux gives them.
+pub const DEV_NULL: u64 = libc::makedev(1, 3);
+pub const DEV_ZERO: u64 = libc::makedev(1, 5);
+pub const DEV_FULL: u64 = libc::makedev(1, 7);
+pub const DEV_URANDOM: u64 = libc::makedev(1, 9);
+
+/// The driver behind a character device. Device nodes only name a device
+/// number; opening one finds the driver registered for that number with
+/// `Vfs::register_device`, and reads and writes on the handle go to it.
+/// `offset` is the handle's offset, which most devices ignore. Handles on
+/// different threads may use a device at once, so drivers must be `Send`;
+/// calls into one driver are serialized.
+pub trait Device: Send {
+  fn read(&mut self, offset: usize, dst: &mut [u8]) -> Result<usize>;
+  fn write(&mut self, offset: usize, src: &[u8]) -> Result<usize>;
+}
+
+// Fills `dsts` one buffer at a time, stopping early at a short read.
+pub fn read_vectored(device: &mut dyn Device, offset: usize, dsts: &mut [IoSliceMut]) -> Result<usize> {
+  let mut read = 0;
+  for dst in dsts.iter_mut() {
+    let len = device.read(offset + read, dst)?;
+    read += len;
+    if len < dst.len() {
+      break;
+    }
+  }
+
+  Ok(read)
+}
+
+pub fn write_vectored(device: &mut dyn Device, offset: usize, srcs: &[IoSlice]) -> Result<usize> {
+  let mut written = 0;
+  for src in srcs {
+    let len = device.write(offset + written, src)?;
+    written += len;
+    if len < src.len() {
+      break;
+    }
+  }
+
+  Ok(written)
+}
+
+/// `/dev/null`: reads are at EOF, writes vanish.
+pub struct Null;
+
+impl Device for Null {
+  fn read(&mut self, _offset: usize, _dst: &mut [u8]) -> Result<usize> {
+    Ok(0)
+  }
+
+  fn write(&mut self, _offset: usize, src: &[u8]) -> Result<usize> {
+    Ok(src.len())
+  }
+}
+
+/// `/dev/zero`: reads return zeros, writes vanish.
+pub struct Zero;
+
+impl Device for Zero {
+  fn read(&mut self, _offset: usize, dst: &mut [u8]) -> Result<usize> {
+    dst.iter_mut().for_each(|b| *b = 0);
+    Ok(dst.len())
+  }
+
+  fn write(&mut self, _offset: usize, src: &[u8]) -> Result<usize> {
+    Ok(src.len())
+  }
+}
+
+/// `/dev/full`: reads return zeros, writes fail with ENOSPC.
+pub struct Full;
+
+impl Device for Full {
+  fn read(&mut self, offset: usize, dst: &mut [u8]) -> Result<usize> {
+    Zero.read(offset, dst)
+  }
+
+  fn write(&mut self, _offset: usize, _src: &[u8]) -> Result<usize> {
+    Err(Error::from_raw_os_error(ENOSPC))
+  }
+}
+
+/// `/dev/urandom`, except that it's seeded: the same seed always produces
+/// the same bytes, so runs can be reproduced. Writes are accepted and
+/// ignored, as they don't add entropy to anything.
+pub struct Urandom {
+  rng: XorShiftRng
+}
+
+impl Urandom {
+  pub fn new(seed: u64) -> Urandom {
+    // XorShift can't start from all zeros, hence the fixed upper words.
+    let seed = [seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15];
+    Urandom { rng: XorShiftRng::from_seed(seed) }
+  }
+}
+
+impl Device for Urandom {
+  fn read(&mut self, _offset: usize, dst: &mut [u8]) -> Result<usize> {
+    self.rng.fill_bytes(dst);
+    Ok(dst.len())
+  }
+
+  fn write(&mut self, _offset: usize, src: &[u8]) -> Result<usize> {
+    Ok(src.len())
+  }
+}
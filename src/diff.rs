@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use crate::Vfs;
+use crate::directory::DirectoryHandle;
+use crate::file::File;
+use crate::inode::Inode;
+
+/// What changed between two trees, as found by `Vfs::diff`. Paths are
+/// relative to the root, which is "/"; each list is sorted.
+///
+/// Files are told apart by inode number, so a file that turns up under
+/// another name is `renamed`, from its old path to its new one, rather than
+/// removed and added. A file with several names has each looked at apart:
+/// a name it gained or lost is added or removed. Everything else is under
+/// the path it has in the newer tree, except `removed`. Every entry inside
+/// an added or removed directory is listed too.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+  pub added: Vec<String>,
+  pub removed: Vec<String>,
+  pub renamed: Vec<(String, String)>,
+  /// Data files holding different bytes.
+  pub content_modified: Vec<String>,
+  /// Files whose type, permission bits, owner, device number or extended
+  /// attributes differ. Timestamps and link counts change along with
+  /// nearly everything else, so aren't compared.
+  pub metadata_modified: Vec<String>
+}
+
+impl TreeDiff {
+  pub fn is_empty(&self) -> bool {
+    *self == TreeDiff::default()
+  }
+}
+
+impl<'r> Vfs<'r> {
+  /// Compares this tree, the older one, with `newer`. Either may be a
+  /// snapshot or a live tree; a live one is compared as a snapshot of it
+  /// taken now, which copies nothing.
+  ///
+  /// Whatever hasn't changed in between is skipped without being looked at:
+  /// a directory nothing under has changed is passed over whole, and so are
+  /// the pages two files still share.
+  pub fn diff(&self, newer: &Vfs<'r>) -> TreeDiff {
+    let mut differ = Differ { diff: TreeDiff::default(), old_only: HashMap::new(), new_only: Vec::new() };
+    differ.compare(&self.frozen_root(), &newer.frozen_root(), "/", "/");
+
+    // Whatever's only in the newer tree was either moved there or added.
+    // Where a file lost several names, the first is taken as the old one,
+    // for the first of its new names.
+    differ.new_only.sort_by(|a, b| b.0.cmp(&a.0));
+    while let Some((path, file)) = differ.new_only.pop() {
+      let old_name = differ.old_only.get(&ino(&file)).and_then(|names| names.iter().min_by(|a, b| a.0.cmp(&b.0)));
+      match old_name.cloned() {
+        Some((old_path, old)) => {
+          differ.diff.renamed.push((old_path.clone(), path.clone()));
+          differ.compare(&old, &file, &old_path, &path);
+        }
+        None => {
+          if file.is_dir() {
+            differ.new_only.extend(file.list().into_iter().map(|(name, child)| (join(&path, name), child)));
+          }
+          differ.diff.added.push(path);
+        }
+      }
+    }
+
+    let mut diff = differ.diff;
+    diff.removed.extend(differ.old_only.into_values().flatten().map(|(path, _)| path));
+    diff.added.sort();
+    diff.removed.sort();
+    diff.renamed.sort();
+    diff.content_modified.sort();
+    diff.metadata_modified.sort();
+    diff
+  }
+
+  // The root of a tree that holds still.
+  fn frozen_root(&self) -> File<'r> {
+    if self.read_only {
+      self.cwd.clone()
+    } else {
+      self.snapshot().cwd.clone()
+    }
+  }
+}
+
+struct Differ<'r> {
+  diff: TreeDiff,
+  // Names only found in the older tree, by the inode number of the file
+  // they were for. Directories' contents are entered along with them.
+  old_only: HashMap<u64, Vec<(String, File<'r>)>>,
+  // Likewise, for the newer tree, but only the top of each subtree.
+  new_only: Vec<(String, File<'r>)>
+}
+
+impl<'r> Differ<'r> {
+  // Compares what's at `old_path` in the older tree with the same file at
+  // `new_path` in the newer one.
+  fn compare(&mut self, old: &File<'r>, new: &File<'r>, old_path: &str, new_path: &str) {
+    // Files under one only found in the older tree were entered along with it.
+    let entered = self.take_old(ino(old), old_path);
+    if old.is_same(new) {
+      if entered {
+        self.forget_under(old, old_path);
+      }
+      return;
+    }
+
+    let (attributes, contents) = old.with_inode(|a| new.with_inode(|b| {
+      (Inode::same_attributes(a, b), Inode::same_contents(a, b))
+    }));
+    if !attributes {
+      self.diff.metadata_modified.push(new_path.to_string());
+    }
+    if !contents {
+      self.diff.content_modified.push(new_path.to_string());
+    }
+    if !old.is_dir() || !new.is_dir() {
+      return;
+    }
+
+    let old_entries: HashMap<_, _> = old.list().into_iter().collect();
+    let new_entries: HashMap<_, _> = new.list().into_iter().collect();
+    for (name, child) in &new_entries {
+      match old_entries.get(name) {
+        Some(old_child) if ino(old_child) == ino(child) =>
+          self.compare(old_child, child, &join(old_path, name), &join(new_path, name)),
+        _ => self.new_only.push((join(new_path, name), child.clone()))
+      }
+    }
+    if !entered {
+      for (name, child) in &old_entries {
+        if new_entries.get(name).is_none_or(|new_child| ino(new_child) != ino(child)) {
+          self.enter_old(join(old_path, name), child);
+        }
+      }
+    }
+  }
+
+  fn enter_old(&mut self, path: String, file: &File<'r>) {
+    if file.is_dir() {
+      for (name, child) in file.list() {
+        self.enter_old(join(&path, name), &child);
+      }
+    }
+    self.old_only.entry(ino(file)).or_default().push((path, file.clone()));
+  }
+
+  // Takes the name `path` for inode `ino` out of `old_only`, if it's there.
+  fn take_old(&mut self, ino: u64, path: &str) -> bool {
+    let names = match self.old_only.get_mut(&ino) {
+      Some(names) => names,
+      None => return false
+    };
+    let before = names.len();
+    names.retain(|(name, _)| name != path);
+    let taken = names.len() < before;
+    if names.is_empty() {
+      self.old_only.remove(&ino);
+    }
+    taken
+  }
+
+  // Takes back everything under `dir`, at `path`, from `old_only`, as it's
+  // all still there in the newer tree.
+  fn forget_under(&mut self, dir: &File<'r>, path: &str) {
+    if dir.is_dir() {
+      for (name, child) in dir.list() {
+        let child_path = join(path, name);
+        self.take_old(ino(&child), &child_path);
+        self.forget_under(&child, &child_path);
+      }
+    }
+  }
+}
+
+fn ino(file: &File) -> u64 {
+  file.with_inode(Inode::ino)
+}
+
+fn join(dir: &str, name: &str) -> String {
+  if dir == "/" { name.to_string() } else { format!("{}/{}", dir, name) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::TreeDiff;
+  use crate::{Vfs, FileFlags, XattrFlags};
+  use crate::directory::DirectoryHandle;
+
+  fn strings(paths: &[&str]) -> Vec<String> {
+    paths.iter().map(|path| path.to_string()).collect()
+  }
+
+  #[test]
+  fn test_diff() {
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    p.mkdir("dir/sub", 0o755).unwrap();
+    p.mkdir("gone", 0o755).unwrap();
+    for path in &["dir/file", "dir/sub/moved", "gone/file", "edited", "chmodded", "same"] {
+      let fd = p.open(path, FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+      p.write(fd, b"contents").unwrap();
+    }
+    let before = p.snapshot();
+
+    p.rename("dir/sub/moved", "dir/renamed").unwrap();
+    p.rename("gone/file", "rescued").unwrap();
+    p.rmdir("gone").unwrap();
+    p.mkdir("new", 0o755).unwrap();
+    p.open("new/file", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    let fd = p.open("edited", FileFlags::O_WRONLY).unwrap();
+    p.write(fd, b"CON").unwrap();
+    p.chmod("chmodded", 0o600).unwrap();
+    p.setxattr("dir", "user.tag", b"x", XattrFlags::empty()).unwrap();
+
+    let expected = TreeDiff {
+      added: strings(&["new", "new/file"]),
+      removed: strings(&["gone"]),
+      renamed: vec![("dir/sub/moved".into(), "dir/renamed".into()), ("gone/file".into(), "rescued".into())],
+      content_modified: strings(&["edited"]),
+      metadata_modified: strings(&["chmodded", "dir"])
+    };
+    assert_eq!(before.diff(&p), expected);
+    assert_eq!(before.diff(&p.snapshot()), expected);
+    assert!(p.diff(&p).is_empty());
+
+    // Writing the same bytes back leaves nothing to report.
+    let after = p.snapshot();
+    p.pwritev(fd, &[std::io::IoSlice::new(b"CON")], 0).unwrap();
+    assert!(after.diff(&p).is_empty());
+  }
+
+  #[test]
+  fn test_diff_skips_unchanged_subtrees() {
+    let names: Vec<String> = (0..100).map(|i| format!("big/{}", i)).collect();
+    let p = Vfs::new();
+    p.mkdir("big", 0o755).unwrap();
+    p.mkdir("changed", 0o755).unwrap();
+    for name in &names {
+      p.mkdir(name, 0o755).unwrap();
+    }
+    let fd = p.open("changed/file", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    let before = p.snapshot();
+    let unfilled = |p: &Vfs, path| p.cwd.get(path).unwrap().get_dir_arc().read().unwrap().unfilled();
+
+    // Only the way down to what changed is looked at; nothing under the
+    // rest is even made.
+    p.write(fd, b"new").unwrap();
+    assert_eq!(before.diff(&p), TreeDiff { content_modified: strings(&["changed/file"]), ..TreeDiff::default() });
+    assert!(unfilled(&before, "big"));
+    assert!(!unfilled(&before, "changed"));
+
+    // Where nothing changed, not even the root is.
+    let after = p.snapshot();
+    assert!(after.diff(&p).is_empty() && p.diff(&p).is_empty());
+    assert!(after.cwd.get_dir_arc().read().unwrap().unfilled());
+  }
+
+  #[test]
+  fn test_diff_renamed_directory() {
+    let p = Vfs::new();
+    p.mkdir("a", 0o755).unwrap();
+    p.mkdir("a/b", 0o755).unwrap();
+    let fd = p.open("a/b/file", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    p.open("a/b/other", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    let before = p.snapshot();
+
+    // Only the directory is renamed; what's in it is compared where it went.
+    p.rename("a", "z").unwrap();
+    p.write(fd, b"new").unwrap();
+    p.unlink("z/b/other").unwrap();
+    let diff = before.diff(&p);
+    assert_eq!(diff.renamed, [("a".to_string(), "z".to_string())]);
+    assert_eq!(diff.content_modified, ["z/b/file"]);
+    assert_eq!(diff.removed, ["a/b/other"]);
+    assert!(diff.added.is_empty() && diff.metadata_modified.is_empty());
+
+    // And the other way round.
+    let undo = p.diff(&before);
+    assert_eq!(undo.renamed, [("z".to_string(), "a".to_string())]);
+    assert_eq!(undo.added, ["a/b/other"]);
+
+    // Nothing under a directory renamed without changes shows up.
+    let moved = p.snapshot();
+    p.rename("z", "y").unwrap();
+    assert_eq!(moved.diff(&p), TreeDiff { renamed: vec![("z".into(), "y".into())], ..TreeDiff::default() });
+  }
+
+  #[test]
+  fn test_diff_hard_links() {
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    p.open("dir/a", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    p.link("dir/a", "dir/b").unwrap();
+    p.link("dir/a", "c").unwrap();
+    let before = p.snapshot();
+
+    // Losing one name of a file that keeps another is a removal.
+    p.unlink("dir/a").unwrap();
+    assert_eq!(before.diff(&p), TreeDiff { removed: strings(&["dir/a"]), ..TreeDiff::default() });
+
+    // Names gained are paired with names lost, first with first, as renames;
+    // once those run out they're added.
+    p.unlink("dir/b").unwrap();
+    p.rename("c", "d").unwrap();
+    p.link("d", "dir/e").unwrap();
+    p.link("d", "f").unwrap();
+    p.link("d", "g").unwrap();
+    let expected = TreeDiff {
+      added: strings(&["g"]),
+      renamed: vec![("c".into(), "d".into()), ("dir/a".into(), "dir/e".into()), ("dir/b".into(), "f".into())],
+      ..TreeDiff::default()
+    };
+    assert_eq!(before.diff(&p), expected);
+  }
+}
@@ -0,0 +1,481 @@
+// Differential testing: random sequences of operations are run against both
+// a Vfs and a scratch directory on the host, and the two must agree on every
+// result, every errno and the final tree. A sequence that doesn't is cut
+// down to a minimal reproducer before being reported.
+
+extern crate rand;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io::{Error, Result};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{Vfs, FileDescriptor, FileFlags, Whence};
+use self::rand::{Rng, SeedableRng, XorShiftRng};
+
+// Every path an operation may name. Keeping the pool small makes operations
+// collide often, and as no other path can ever exist, comparing these is
+// comparing whole trees.
+static PATHS: [&str; 9] = ["a", "b", "d", "e", "d/a", "d/b", "d/e", "e/a", "d/e/a"];
+
+static SEQUENCES: u32 = 200;
+static SEQUENCE_LEN: usize = 40;
+static MAX_WRITE: usize = 6000; // Over a page, so writes cross pages
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+  Open(usize, &'static str, FileFlags), // Numbers the fd, for the ops using it
+  Close(usize),
+  Read(usize, usize),
+  Write(usize, Vec<u8>),
+  Seek(usize, isize, Whence),
+  Ftruncate(usize, usize),
+  Mkdir(&'static str),
+  Rmdir(&'static str),
+  Unlink(&'static str),
+  Rename(&'static str, &'static str)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Outcome {
+  Done,
+  Count(usize),
+  Data(Bytes),
+  Errno(i32),
+  Other(String), // An error without an errno
+  Skipped // The fd named was never opened, or already closed
+}
+
+// What a path names once a sequence is done.
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+  Missing(Outcome),
+  Dir,
+  File(Bytes)
+}
+
+// File contents, shown as runs of the same byte.
+#[derive(Clone, PartialEq)]
+struct Bytes(Vec<u8>);
+
+impl fmt::Debug for Bytes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let runs: Vec<_> = self.0.chunk_by(|a, b| a == b)
+      .map(|run| format!("[{}; {}]", run[0], run.len()))
+      .collect();
+    write!(f, "{}", runs.join(" "))
+  }
+}
+
+#[derive(Debug, PartialEq)]
+enum Divergence {
+  Step(usize, Outcome, Outcome), // Which op, then what Vfs and the host did
+  Tree(&'static str, Node, Node)
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Divergence::Step(i, ref vfs, ref host) =>
+        write!(f, "op {} gave {:?} on Vfs but {:?} on the host", i, vfs, host),
+      Divergence::Tree(path, ref vfs, ref host) =>
+        write!(f, "{:?} ended up {:?} on Vfs but {:?} on the host", path, vfs, host)
+    }
+  }
+}
+
+fn outcome<T, F: FnOnce(T) -> Outcome>(result: Result<T>, ok: F) -> Outcome {
+  match result {
+    Ok(value) => ok(value),
+    Err(e) => match e.raw_os_error() {
+      Some(errno) => Outcome::Errno(errno),
+      None => Outcome::Other(e.to_string())
+    }
+  }
+}
+
+// Where the host and Vfs agree in all but the error: Vfs still reports
+// these two as they predate errnos.
+fn legacy_errno(outcome: Outcome) -> Outcome {
+  match outcome {
+    Outcome::Other(ref e) if e == "EmptyFile" => Outcome::Errno(libc::ENOENT),
+    Outcome::Other(ref e) if e == "Directory" => Outcome::Errno(libc::EISDIR),
+    outcome => outcome
+  }
+}
+
+// The two sides an op can run on.
+trait System {
+  fn open(&mut self, path: &'static str, flags: FileFlags) -> Result<FileDescriptor>;
+  fn close(&mut self, fd: FileDescriptor);
+  fn read(&mut self, fd: FileDescriptor, len: usize) -> Result<Vec<u8>>;
+  fn write(&mut self, fd: FileDescriptor, data: &[u8]) -> Result<usize>;
+  fn seek(&mut self, fd: FileDescriptor, offset: isize, whence: Whence) -> Result<usize>;
+  fn ftruncate(&mut self, fd: FileDescriptor, len: usize) -> Result<()>;
+  fn mkdir(&mut self, path: &'static str) -> Result<()>;
+  fn rmdir(&mut self, path: &'static str) -> Result<()>;
+  fn unlink(&mut self, path: &'static str) -> Result<()>;
+  fn rename(&mut self, from: &'static str, to: &'static str) -> Result<()>;
+  fn node(&mut self, path: &'static str) -> Node;
+}
+
+fn run<S: System>(system: &mut S, ops: &[Op]) -> (Vec<Outcome>, Vec<Node>) {
+  let mut fds: HashMap<usize, FileDescriptor> = HashMap::new();
+  let mut outcomes = Vec::with_capacity(ops.len());
+  for op in ops {
+    let fd = match *op {
+      Op::Close(n) | Op::Read(n, _) | Op::Write(n, _) | Op::Seek(n, ..) | Op::Ftruncate(n, _) =>
+        match fds.get(&n).cloned() {
+          Some(fd) => Some(fd),
+          None => {
+            outcomes.push(Outcome::Skipped);
+            continue;
+          }
+        },
+      _ => None
+    };
+    let fd = fd.unwrap_or(-1);
+
+    let result = match *op {
+      Op::Open(n, path, flags) => {
+        let result = system.open(path, flags);
+        if let Ok(fd) = result {
+          fds.insert(n, fd);
+        }
+        outcome(result, |_| Outcome::Done)
+      }
+      Op::Close(n) => {
+        system.close(fd);
+        fds.remove(&n);
+        Outcome::Done
+      }
+      Op::Read(_, len) => outcome(system.read(fd, len), |data| Outcome::Data(Bytes(data))),
+      Op::Write(_, ref data) => outcome(system.write(fd, data), Outcome::Count),
+      Op::Seek(_, offset, whence) => outcome(system.seek(fd, offset, whence), Outcome::Count),
+      Op::Ftruncate(_, len) => outcome(system.ftruncate(fd, len), |_| Outcome::Done),
+      Op::Mkdir(path) => outcome(system.mkdir(path), |_| Outcome::Done),
+      Op::Rmdir(path) => outcome(system.rmdir(path), |_| Outcome::Done),
+      Op::Unlink(path) => outcome(system.unlink(path), |_| Outcome::Done),
+      Op::Rename(from, to) => outcome(system.rename(from, to), |_| Outcome::Done)
+    };
+    outcomes.push(legacy_errno(result));
+  }
+
+  for fd in fds.into_values() {
+    system.close(fd);
+  }
+  (outcomes, PATHS.iter().map(|path| system.node(path)).collect())
+}
+
+impl<'r> System for Vfs<'r> {
+  fn open(&mut self, path: &'static str, flags: FileFlags) -> Result<FileDescriptor> {
+    Vfs::open(self, path, flags)
+  }
+
+  fn close(&mut self, fd: FileDescriptor) {
+    Vfs::close(self, fd)
+  }
+
+  fn read(&mut self, fd: FileDescriptor, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let read = Vfs::read(self, fd, &mut buf)?;
+    buf.truncate(read);
+    Ok(buf)
+  }
+
+  fn write(&mut self, fd: FileDescriptor, data: &[u8]) -> Result<usize> {
+    Vfs::write(self, fd, data)
+  }
+
+  fn seek(&mut self, fd: FileDescriptor, offset: isize, whence: Whence) -> Result<usize> {
+    Vfs::seek(self, fd, offset, whence)
+  }
+
+  fn ftruncate(&mut self, fd: FileDescriptor, len: usize) -> Result<()> {
+    Vfs::ftruncate(self, fd, len)
+  }
+
+  fn mkdir(&mut self, path: &'static str) -> Result<()> {
+    Vfs::mkdir(self, path, 0o755)
+  }
+
+  fn rmdir(&mut self, path: &'static str) -> Result<()> {
+    Vfs::rmdir(self, path)
+  }
+
+  fn unlink(&mut self, path: &'static str) -> Result<()> {
+    Vfs::unlink(self, path)
+  }
+
+  fn rename(&mut self, from: &'static str, to: &'static str) -> Result<()> {
+    Vfs::rename(self, from, to)
+  }
+
+  fn node(&mut self, path: &'static str) -> Node {
+    match self.stat(path) {
+      Ok(ref stat) if stat.file_type == crate::FileType::Directory => Node::Dir,
+      Ok(stat) => {
+        let fd = Vfs::open(self, path, FileFlags::O_RDONLY).unwrap();
+        let data = System::read(self, fd, stat.size as usize).unwrap();
+        Vfs::close(self, fd);
+        Node::File(Bytes(data))
+      }
+      Err(e) => Node::Missing(outcome::<(), _>(Err(e), |_| Outcome::Done))
+    }
+  }
+}
+
+// A scratch directory on the host, worked on through libc so that errnos
+// come back untouched. It's removed when dropped.
+struct Host {
+  root: PathBuf
+}
+
+impl Host {
+  fn new() -> Host {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("rustfs-differential-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+    let root = std::env::temp_dir().join(name);
+    fs::create_dir(&root).unwrap();
+    Host { root }
+  }
+
+  fn path(&self, path: &str) -> CString {
+    CString::new(self.root.join(path).into_os_string().into_string().unwrap()).unwrap()
+  }
+
+  fn check(ret: libc::c_int) -> Result<()> {
+    if ret < 0 { Err(Error::last_os_error()) } else { Ok(()) }
+  }
+}
+
+impl Drop for Host {
+  fn drop(&mut self) {
+    fs::remove_dir_all(&self.root).unwrap();
+  }
+}
+
+impl System for Host {
+  fn open(&mut self, path: &'static str, flags: FileFlags) -> Result<FileDescriptor> {
+    let mut host_flags = if flags.contains(FileFlags::O_RDWR) {
+      libc::O_RDWR
+    } else if flags.contains(FileFlags::O_WRONLY) {
+      libc::O_WRONLY
+    } else {
+      libc::O_RDONLY
+    };
+    for &(flag, host_flag) in &[(FileFlags::O_NONBLOCK, libc::O_NONBLOCK),
+                                (FileFlags::O_APPEND, libc::O_APPEND),
+                                (FileFlags::O_CREAT, libc::O_CREAT)] {
+      if flags.contains(flag) {
+        host_flags |= host_flag;
+      }
+    }
+
+    let fd = unsafe { libc::open(self.path(path).as_ptr(), host_flags, 0o666) };
+    Host::check(fd)?;
+
+    // Vfs only lists directories, through read_dir, so it opens none.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    Host::check(unsafe { libc::fstat(fd, &mut stat) })?;
+    if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+      unsafe { libc::close(fd) };
+      return Err(Error::from_raw_os_error(libc::EISDIR));
+    }
+    Ok(fd as FileDescriptor)
+  }
+
+  fn close(&mut self, fd: FileDescriptor) {
+    unsafe { libc::close(fd as libc::c_int) };
+  }
+
+  fn read(&mut self, fd: FileDescriptor, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    let read = unsafe { libc::read(fd as libc::c_int, buf.as_mut_ptr() as *mut libc::c_void, len) };
+    Host::check(read as libc::c_int)?;
+    buf.truncate(read as usize);
+    Ok(buf)
+  }
+
+  fn write(&mut self, fd: FileDescriptor, data: &[u8]) -> Result<usize> {
+    let written = unsafe { libc::write(fd as libc::c_int, data.as_ptr() as *const libc::c_void, data.len()) };
+    Host::check(written as libc::c_int)?;
+    Ok(written as usize)
+  }
+
+  fn seek(&mut self, fd: FileDescriptor, offset: isize, whence: Whence) -> Result<usize> {
+    let whence = match whence {
+      Whence::SeekSet => libc::SEEK_SET,
+      Whence::SeekCur => libc::SEEK_CUR,
+      Whence::SeekEnd => libc::SEEK_END
+    };
+    let pos = unsafe { libc::lseek(fd as libc::c_int, offset as libc::off_t, whence) };
+    Host::check(pos as libc::c_int)?;
+    Ok(pos as usize)
+  }
+
+  fn ftruncate(&mut self, fd: FileDescriptor, len: usize) -> Result<()> {
+    Host::check(unsafe { libc::ftruncate(fd as libc::c_int, len as libc::off_t) })
+  }
+
+  fn mkdir(&mut self, path: &'static str) -> Result<()> {
+    Host::check(unsafe { libc::mkdir(self.path(path).as_ptr(), 0o755) })
+  }
+
+  fn rmdir(&mut self, path: &'static str) -> Result<()> {
+    Host::check(unsafe { libc::rmdir(self.path(path).as_ptr()) })
+  }
+
+  fn unlink(&mut self, path: &'static str) -> Result<()> {
+    Host::check(unsafe { libc::unlink(self.path(path).as_ptr()) })
+  }
+
+  fn rename(&mut self, from: &'static str, to: &'static str) -> Result<()> {
+    Host::check(unsafe { libc::rename(self.path(from).as_ptr(), self.path(to).as_ptr()) })
+  }
+
+  fn node(&mut self, path: &'static str) -> Node {
+    let path = self.root.join(path);
+    match fs::symlink_metadata(&path) {
+      Ok(ref meta) if meta.is_dir() => Node::Dir,
+      Ok(_) => Node::File(Bytes(fs::read(&path).unwrap())),
+      Err(e) => Node::Missing(outcome::<(), _>(Err(e), |_| Outcome::Done))
+    }
+  }
+}
+
+/// Runs `ops` on both sides, returning the first place they part ways.
+fn diverges(ops: &[Op]) -> Option<Divergence> {
+  let (vfs_outcomes, vfs_tree) = run(&mut Vfs::new(), ops);
+  let (host_outcomes, host_tree) = run(&mut Host::new(), ops);
+  let steps = vfs_outcomes.into_iter().zip(host_outcomes).enumerate();
+  let nodes = PATHS.iter().zip(vfs_tree.into_iter().zip(host_tree));
+  steps.filter(|(_, (vfs, host))| vfs != host)
+    .map(|(i, (vfs, host))| Divergence::Step(i, vfs, host))
+    .chain(nodes.filter(|(_, (vfs, host))| vfs != host)
+      .map(|(&path, (vfs, host))| Divergence::Tree(path, vfs, host)))
+    .next()
+}
+
+/// Cuts `ops` down while `fails` still holds, until no single op can go.
+/// Ops past the first failing step can't matter to it, so they go first.
+fn minimize<F: Fn(&[Op]) -> Option<Divergence>>(ops: &[Op], fails: F) -> Vec<Op> {
+  let mut ops = ops.to_vec();
+  if let Some(Divergence::Step(i, ..)) = fails(&ops) {
+    ops.truncate(i + 1);
+  }
+
+  loop {
+    let len = ops.len();
+    for i in (0..ops.len()).rev() {
+      let mut fewer = ops.clone();
+      fewer.remove(i);
+      if fails(&fewer).is_some() {
+        ops = fewer;
+      }
+    }
+
+    if ops.len() == len {
+      return ops;
+    }
+  }
+}
+
+/// Writes `ops` out as the Vfs calls they make, ready to paste into a test.
+fn reproducer(ops: &[Op]) -> String {
+  let mut lines = vec!["let p = Vfs::new();".to_string()];
+  for op in ops {
+    lines.push(match *op {
+      Op::Open(n, path, flags) => {
+        let flags: Vec<_> = format!("{:?}", flags).split(" | ").map(|f| format!("FileFlags::{}", f)).collect();
+        format!("let fd{} = p.open({:?}, {}).unwrap_or(-1);", n, path, flags.join(" | "))
+      }
+      Op::Close(n) => format!("p.close(fd{});", n),
+      Op::Read(n, len) => format!("p.read(fd{}, &mut [0u8; {}]);", n, len),
+      Op::Write(n, ref data) => format!("p.write(fd{}, &[{}u8; {}]);", n, data[0], data.len()),
+      Op::Seek(n, offset, whence) => format!("p.seek(fd{}, {}, Whence::{:?});", n, offset, whence),
+      Op::Ftruncate(n, len) => format!("p.ftruncate(fd{}, {});", n, len),
+      Op::Mkdir(path) => format!("p.mkdir({:?}, 0o755);", path),
+      Op::Rmdir(path) => format!("p.rmdir({:?});", path),
+      Op::Unlink(path) => format!("p.unlink({:?});", path),
+      Op::Rename(from, to) => format!("p.rename({:?}, {:?});", from, to)
+    });
+  }
+
+  lines.join("\n")
+}
+
+// A random op. Fds are picked among those opened so far, closed or not.
+fn random_op<R: Rng>(rng: &mut R, opened: usize) -> Op {
+  let path = |rng: &mut R| *rng.choose(&PATHS).unwrap();
+  let fd = |rng: &mut R| rng.gen_range(0, opened);
+  match rng.gen_range(0, if opened > 0 { 10 } else { 5 }) {
+    0 | 1 => {
+      let access = *rng.choose(&[FileFlags::O_RDONLY, FileFlags::O_WRONLY, FileFlags::O_RDWR]).unwrap();
+      let mut flags = access;
+      for &flag in &[FileFlags::O_CREAT, FileFlags::O_APPEND] {
+        if rng.gen() {
+          flags |= flag;
+        }
+      }
+      Op::Open(opened, path(rng), flags)
+    }
+    2 => if rng.gen() { Op::Mkdir(path(rng)) } else { Op::Rmdir(path(rng)) },
+    3 => Op::Unlink(path(rng)),
+    4 => Op::Rename(path(rng), path(rng)),
+    5 => Op::Close(fd(rng)),
+    6 => Op::Read(fd(rng), rng.gen_range(0, MAX_WRITE)),
+    7 => {
+      let byte = rng.gen_range(1, 255);
+      Op::Write(fd(rng), vec![byte; rng.gen_range(1, MAX_WRITE)])
+    }
+    8 => {
+      let whence = *rng.choose(&[Whence::SeekSet, Whence::SeekCur, Whence::SeekEnd]).unwrap();
+      Op::Seek(fd(rng), rng.gen_range(-100, MAX_WRITE as isize), whence)
+    }
+    _ => Op::Ftruncate(fd(rng), rng.gen_range(0, MAX_WRITE))
+  }
+}
+
+fn random_ops(seed: u32, len: usize) -> Vec<Op> {
+  let mut rng = XorShiftRng::from_seed([seed, 1, 2, 3]);
+  let mut ops = Vec::with_capacity(len);
+  let mut opened = 0;
+  for _ in 0..len {
+    let op = random_op(&mut rng, opened);
+    if let Op::Open(..) = op {
+      opened += 1;
+    }
+    ops.push(op);
+  }
+
+  ops
+}
+
+#[test]
+fn test_against_host() {
+  for seed in 1..=SEQUENCES {
+    let ops = random_ops(seed, SEQUENCE_LEN);
+    if diverges(&ops).is_some() {
+      let ops = minimize(&ops, diverges);
+      panic!("seed {}: {}\n{}", seed, diverges(&ops).unwrap(), reproducer(&ops));
+    }
+  }
+}
+
+#[test]
+fn test_minimize() {
+  // Fails whenever "d" is made and "a" unlinked, in that order.
+  let fails = |ops: &[Op]| {
+    let mkdir = ops.iter().position(|op| *op == Op::Mkdir("d"))?;
+    let unlink = ops.iter().rposition(|op| *op == Op::Unlink("a"))?;
+    if mkdir < unlink { Some(Divergence::Step(unlink, Outcome::Done, Outcome::Done)) } else { None }
+  };
+
+  let mut ops = random_ops(7, 30);
+  ops.insert(5, Op::Mkdir("d"));
+  ops.insert(20, Op::Unlink("a"));
+  assert_eq!(minimize(&ops, fails), [Op::Mkdir("d"), Op::Unlink("a")]);
+}
@@ -1,39 +1,92 @@
-use crate::file::File;
+use std::sync::RwLockReadGuard;
+use crate::file::{DirectoryContent, File};
 use crate::file::File::Directory;
+use crate::inode::FileType;
+use crate::notify::WatchMask;
+
+/// One name in a directory, as `Vfs::read_dir` lists them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirEntry<'r> {
+  pub name: &'r str,
+  pub ino: u64,
+  pub file_type: FileType
+}
 
 pub trait DirectoryHandle<'r>: Sized {
   fn is_dir(&self) -> bool;
   fn insert(&mut self, name: &'r str, file: Self);
   fn remove(&mut self, name: &'r str);
+  // The halves of a rename, which watchers see as a move tied by `cookie`
+  // rather than as a delete and a create.
+  fn insert_moved(&mut self, name: &'r str, file: Self, cookie: u32);
+  fn remove_moved(&mut self, name: &'r str, cookie: u32);
   fn get(&self, name: &'r str) -> Option<Self>;
+  fn is_empty(&self) -> bool;
+  fn list(&self) -> Vec<(&'r str, Self)>;
 }
 
 impl<'r> DirectoryHandle<'r> for File<'r> {
   fn is_dir(&self) -> bool {
-    match self {
-      &Directory(_) => true,
-      _ => false
-    }
+    matches!(*self, Directory(_))
   }
 
   fn insert(&mut self, name: &'r str, file: File<'r>) {
-    let rc = self.get_dir_rc();
-    let mut content = rc.borrow_mut();
-    content.entries.insert(name, file);
+    insert_entry(self, name, file, WatchMask::IN_CREATE, 0);
   }
 
   fn remove(&mut self, name: &'r str) {
-    let rc = self.get_dir_rc();
-    let mut content = rc.borrow_mut();
-    content.entries.remove(&name);
+    remove_entry(self, name, WatchMask::IN_DELETE, 0);
+  }
+
+  fn insert_moved(&mut self, name: &'r str, file: File<'r>, cookie: u32) {
+    insert_entry(self, name, file, WatchMask::IN_MOVED_TO, cookie);
+  }
+
+  fn remove_moved(&mut self, name: &'r str, cookie: u32) {
+    remove_entry(self, name, WatchMask::IN_MOVED_FROM, cookie);
   }
 
   fn get(&self, name: &'r str) -> Option<File<'r>> {
-    let rc = self.get_dir_rc();
-    let content = rc.borrow();
-    match content.entries.get(&name) {
-      None => None,
-      Some(ref file) => Some((*file).clone()) // It's RC
-    }
+    let content = read_content(self);
+    content.entries.get(&name).cloned() // It's an Arc
+  }
+
+  fn is_empty(&self) -> bool {
+    let content = read_content(self);
+    content.entries.is_empty()
+  }
+
+  fn list(&self) -> Vec<(&'r str, File<'r>)> {
+    let content = read_content(self);
+    content.entries.iter().map(|(name, file)| (*name, file.clone())).collect()
+  }
+}
+
+// A snapshot's directories have their entries filled in the first time
+// they're read.
+fn read_content<'a, 'r>(dir: &'a File<'r>) -> RwLockReadGuard<'a, Box<DirectoryContent<'r>>> {
+  let arc = dir.get_dir_arc();
+  if arc.read().unwrap().unfilled() {
+    arc.write().unwrap().fill();
+  }
+  arc.read().unwrap()
+}
+
+fn insert_entry<'r>(dir: &File<'r>, name: &'r str, file: File<'r>, event: WatchMask, cookie: u32) {
+  let mut content = dir.get_dir_arc().write().unwrap();
+  file.link();
+  file.with_inode(|child| content.inode.notify_entry(event, cookie, name, child));
+  if let Some(old) = content.entries.insert(name, file) {
+    old.unlink();
+  }
+  content.inode.touch_modified();
+}
+
+fn remove_entry<'r>(dir: &File<'r>, name: &'r str, event: WatchMask, cookie: u32) {
+  let mut content = dir.get_dir_arc().write().unwrap();
+  if let Some(old) = content.entries.remove(&name) {
+    old.with_inode(|child| content.inode.notify_entry(event, cookie, name, child));
+    old.unlink();
+    content.inode.touch_modified();
   }
 }
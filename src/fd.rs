@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::io::{Result, Error, ErrorKind};
+use std::sync::Arc;
+use libc::EMFILE;
+use crate::FileDescriptor;
+use crate::file::FileHandle;
+
+// Descriptors run from 3 (after stdin, stdout and stderr) up to this.
+const MAX_FD: FileDescriptor = 256;
+
+/// The open file descriptors of a filesystem. Handles are reference counted
+/// so that a read or write can carry on without holding the table, while
+/// other threads open and close descriptors; closing one while it's in use
+/// only drops the handle once that use is done.
+pub struct FdTable<'r> {
+  handles: HashMap<FileDescriptor, Arc<FileHandle<'r>>>,
+  free: Vec<FileDescriptor> // Lowest last, so it's handed out first
+}
+
+impl<'r> Default for FdTable<'r> {
+  fn default() -> FdTable<'r> {
+    FdTable::new()
+  }
+}
+
+impl<'r> FdTable<'r> {
+  pub fn new() -> FdTable<'r> {
+    FdTable {
+      handles: HashMap::new(),
+      free: (0..(MAX_FD - 2)).map(|i| MAX_FD - i).collect()
+    }
+  }
+
+  pub fn free_count(&self) -> usize {
+    self.free.len()
+  }
+
+  pub fn insert(&mut self, handle: FileHandle<'r>) -> Result<FileDescriptor> {
+    let fd = self.free.pop().ok_or_else(|| Error::from_raw_os_error(EMFILE))?;
+    self.handles.insert(fd, Arc::new(handle));
+    Ok(fd)
+  }
+
+  pub fn get(&self, fd: FileDescriptor) -> Result<Arc<FileHandle<'r>>> {
+    match self.handles.get(&fd) {
+      Some(h) => Ok(h.clone()),
+      None => Err(Error::new(ErrorKind::NotFound, "fd not found")),
+    }
+  }
+
+  pub fn remove(&mut self, fd: FileDescriptor) -> Option<Arc<FileHandle<'r>>> {
+    let handle = self.handles.remove(&fd)?;
+    self.free.push(fd);
+    Some(handle)
+  }
+}
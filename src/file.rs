@@ -1,34 +1,80 @@
-use time;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::{Cell, RefCell};
-use crate::inode::{Inode};
-use self::File::{DataFile, Directory};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Waker;
+use std::io::{Result, Error, IoSlice, IoSliceMut};
+use libc::{EBADF, EINVAL, ESPIPE};
+use crate::FileFlags;
+use crate::device::{self, Device};
+use crate::inode::{AtimePolicy, Inode, FileType, Stat};
+use crate::perm::{Access, Credentials};
+use crate::pipe::Pipe;
+use crate::shared::Shared;
+use crate::snapshot::Source;
+use self::File::{DataFile, Directory, Fifo, CharDevice, EmptyFile};
 
-pub type RcDirContent<'r> = Rc<RefCell<Box<DirectoryContent<'r>>>>;
-pub type RcInode = Rc<RefCell<Box<Inode>>>;
+pub type ArcDirContent<'r> = Arc<RwLock<Box<DirectoryContent<'r>>>>;
+pub type ArcInode = Arc<RwLock<Shared<Inode>>>;
+pub type ArcFifo = Arc<Mutex<Box<FifoContent>>>;
+pub type ArcDevice = Arc<Mutex<Box<dyn Device>>>;
+pub type Entries<'r> = HashMap<&'r str, File<'r>>;
+
+// Numbers every FileHandle, so that locks can tell open files apart.
+static NEXT_HANDLE_ID: AtomicUsize = AtomicUsize::new(1);
 
 // File is a thin wrapper around Inodes and Directories. The whole point is to
 // provide a layer of indirection. FileHandles and Directory entries, then,
 // point to these guys instead of directly to Inodes/Directories
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum File<'r> {
-  DataFile(RcInode),
-  Directory(RcDirContent<'r>),
+  DataFile(ArcInode),
+  Directory(ArcDirContent<'r>),
+  Fifo(ArcFifo),
+  CharDevice(ArcInode), // Just the node; drivers are found when it's opened
   EmptyFile
 }
 
-#[derive(Clone)]
+// A File that doesn't keep what it points to alive.
+pub enum WeakFile<'r> {
+  DataFile(Weak<RwLock<Shared<Inode>>>),
+  Directory(Weak<RwLock<Box<DirectoryContent<'r>>>>),
+  Fifo(Weak<Mutex<Box<FifoContent>>>),
+  CharDevice(Weak<RwLock<Shared<Inode>>>)
+}
+
 pub struct FileHandle<'r> {
   pub(crate) file: File<'r>,
-  seek: Cell<usize>
+  id: usize,
+  flags: FileFlags,
+  seek: Mutex<usize>, // Held for a whole read or write, as Linux does
+  fifo: Option<FifoEnd>, // Only set for FIFOs
+  device: Option<ArcDevice> // Only set for devices
+}
+
+// Counts a handle towards its FIFO's readers and/or writers for as long as
+// the handle is open.
+struct FifoEnd {
+  fifo: ArcFifo,
+  read: bool,
+  write: bool
 }
 
-#[derive(Clone)]
 pub struct DirectoryContent<'r> {
-  pub entries: HashMap<&'r str, File<'r>>
+  pub entries: Shared<Entries<'r>>,
+  pub inode: Shared<Inode>, // Directory metadata; its data pages are unused
+  // For a snapshot's directory, where its entries come from
+  pub(crate) source: Option<Source<'r>>
+}
+
+// A FIFO's contents only live as long as it's open, so they're kept apart
+// from its inode, which only holds metadata.
+pub struct FifoContent {
+  pub pipe: Pipe,
+  pub inode: Shared<Inode>
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Whence {
   SeekSet,
   SeekCur,
@@ -36,81 +82,304 @@ pub enum Whence {
 }
 
 impl<'r> File<'r> {
-  pub fn new_dir(_parent: Option<File<'r>>) -> File<'r> {
-    let content = Box::new(DirectoryContent { entries: HashMap::new() });
-    let rc = Rc::new(RefCell::new(content));
-    let dir = Directory(rc);
+  pub fn new_dir(parent: Option<File<'r>>, mode: u32) -> File<'r> {
+    // A directory is linked from "." and from its entry in the parent. The
+    // root has no parent entry, but its ".." points back at itself instead.
+    let mut inode = Inode::with_mode(FileType::Directory, mode);
+    inode.link();
+    match parent {
+      None => inode.link(),
+      Some(parent) => parent.link() // For the new directory's ".."
+    }
 
-    // Note that dir is RCd, so this is cheap
-    // Used to borrow dir and mut_dir at "same time"
-    // RefCell makes sure we're not doing anything wrong
-    // let mut mut_dir = dir.clone();
+    let content = DirectoryContent { entries: Shared::new(HashMap::new()), inode: Shared::new(inode), source: None };
+    let content = Box::new(content);
+    Directory(Arc::new(RwLock::new(content)))
+  }
 
-    // // Setting up "." and ".."
-    // mut_dir.insert(".", dir.clone());
-    // match parent {
-    //   None => mut_dir.insert("..", dir.clone()),
-    //   Some(f) => mut_dir.insert("..", f)
-    // }
+  pub fn new_data_file(inode: ArcInode) -> File<'r> {
+    DataFile(inode)
+  }
 
-    dir
+  pub fn new_char_device(mode: u32, rdev: u64) -> File<'r> {
+    let mut inode = Inode::with_mode(FileType::CharDevice, mode);
+    inode.set_rdev(rdev);
+    CharDevice(Arc::new(RwLock::new(Shared::new(inode))))
   }
 
-  pub fn new_data_file(inode: RcInode) -> File<'r> {
-    DataFile(inode)
+  pub fn new_fifo(mode: u32) -> File<'r> {
+    let inode = Inode::with_mode(FileType::Fifo, mode);
+    let content = Box::new(FifoContent { pipe: Pipe::new(), inode: Shared::new(inode) });
+    Fifo(Arc::new(Mutex::new(content)))
+  }
+
+  pub fn downgrade(&self) -> WeakFile<'r> {
+    match *self {
+      DataFile(ref arc) => WeakFile::DataFile(Arc::downgrade(arc)),
+      Directory(ref arc) => WeakFile::Directory(Arc::downgrade(arc)),
+      Fifo(ref arc) => WeakFile::Fifo(Arc::downgrade(arc)),
+      CharDevice(ref arc) => WeakFile::CharDevice(Arc::downgrade(arc)),
+      EmptyFile => panic!("no such file")
+    }
   }
 
-  pub fn get_dir_rc<'a>(&'a self) -> &'a RcDirContent<'r> {
-    match self {
-      &Directory(ref rc) => rc,
+  /// Whether `self` and `other` are the same file or directory, rather than
+  /// merely equal ones. Files two snapshots made from the same file count as
+  /// the same if it didn't change in between, nor did anything under it.
+  pub fn is_same(&self, other: &File<'r>) -> bool {
+    let same = match (self, other) {
+      (DataFile(a), DataFile(b)) => Arc::ptr_eq(a, b),
+      (Directory(a), Directory(b)) => Arc::ptr_eq(a, b),
+      (Fifo(a), Fifo(b)) => Arc::ptr_eq(a, b),
+      (CharDevice(a), CharDevice(b)) => Arc::ptr_eq(a, b),
+      _ => false
+    };
+    same || self.same_version(other)
+  }
+
+  pub fn get_dir_arc(&self) -> &ArcDirContent<'r> {
+    match *self {
+      Directory(ref arc) => arc,
       _ => panic!("not a directory")
     }
   }
 
-  pub fn get_inode_rc<'a>(&'a self) -> &'a RcInode {
-    match self {
-      &DataFile(ref rc) => rc,
+  // Every kind of file is backed by an inode. These give access to it
+  // without caring which kind of file this is.
+  pub fn with_inode<T, F: FnOnce(&Inode) -> T>(&self, f: F) -> T {
+    match *self {
+      DataFile(ref arc) | CharDevice(ref arc) => f(&arc.read().unwrap()),
+      Directory(ref arc) => f(&arc.read().unwrap().inode),
+      Fifo(ref arc) => f(&arc.lock().unwrap().inode),
+      EmptyFile => panic!("no such file")
+    }
+  }
+
+  pub fn with_inode_mut<T, F: FnOnce(&mut Inode) -> T>(&self, f: F) -> T {
+    match *self {
+      DataFile(ref arc) | CharDevice(ref arc) => f(&mut arc.write().unwrap()),
+      Directory(ref arc) => f(&mut arc.write().unwrap().inode),
+      Fifo(ref arc) => f(&mut arc.lock().unwrap().inode),
+      EmptyFile => panic!("no such file")
+    }
+  }
+
+  pub fn stat(&self) -> Stat {
+    self.with_inode(Inode::stat)
+  }
+
+  pub fn check_access(&self, cred: &Credentials, want: Access) -> Result<()> {
+    self.with_inode(|inode| inode.check_access(cred, want))
+  }
+
+  // Records a read, subject to `policy`. The inode is only locked for
+  // writing when its access time does change.
+  pub fn accessed(&self, policy: AtimePolicy) {
+    if self.with_inode(|inode| inode.access_due(policy)) {
+      self.with_inode_mut(Inode::touch_accessed);
+    }
+  }
+
+  // Directory entries count as links; these keep nlink in step with them.
+  pub fn link(&self) {
+    self.with_inode_mut(Inode::link)
+  }
+
+  pub fn unlink(&self) {
+    self.with_inode_mut(Inode::unlink)
+  }
+
+  pub fn get_inode_arc(&self) -> &ArcInode {
+    match *self {
+      DataFile(ref arc) => arc,
       _ => panic!("not a directory")
     }
   }
 }
 
+impl<'r> WeakFile<'r> {
+  pub fn upgrade(&self) -> Option<File<'r>> {
+    match *self {
+      WeakFile::DataFile(ref weak) => weak.upgrade().map(DataFile),
+      WeakFile::Directory(ref weak) => weak.upgrade().map(Directory),
+      WeakFile::Fifo(ref weak) => weak.upgrade().map(Fifo),
+      WeakFile::CharDevice(ref weak) => weak.upgrade().map(CharDevice)
+    }
+  }
+
+  pub fn is_alive(&self) -> bool {
+    match *self {
+      WeakFile::DataFile(ref weak) => weak.strong_count() > 0,
+      WeakFile::Directory(ref weak) => weak.strong_count() > 0,
+      WeakFile::Fifo(ref weak) => weak.strong_count() > 0,
+      WeakFile::CharDevice(ref weak) => weak.strong_count() > 0
+    }
+  }
+}
+
 impl<'r> FileHandle<'r> {
   // Probably not the right type.
-  pub fn new(file: File<'r>) -> FileHandle<'r> {
+  // `device` is the driver behind a device node, and None for other files.
+  pub fn new(file: File<'r>, flags: FileFlags, device: Option<ArcDevice>) -> FileHandle<'r> {
+    let fifo = match file {
+      Fifo(ref arc) => Some(FifoEnd::open(arc, flags)),
+      _ => None
+    };
+
     FileHandle {
-      file: file,
-      seek: Cell::new(0)
+      file,
+      id: NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed),
+      flags,
+      seek: Mutex::new(0),
+      fifo,
+      device
     }
   }
 
-  pub fn read(&self, dst: &mut [u8]) -> usize {
-    let offset = self.seek.get();
-    let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow().read(offset, dst);
-    self.seek.set(offset + changed);
-    changed
+  pub fn id(&self) -> usize {
+    self.id
   }
 
-  pub fn write(&mut self, src: &[u8]) -> usize {
-    let offset = self.seek.get();
-    let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow_mut().write(offset, src).unwrap();
-    self.seek.set(offset + changed);
-    changed
+  pub fn ino(&self) -> u64 {
+    self.file.with_inode(|inode| inode.ino())
   }
 
-  pub fn seek(&mut self, offset: isize, whence: Whence) -> usize {
-    let inode_rc = self.file.get_inode_rc();
+  // What the handle was opened for.
+  pub fn access(&self) -> Access {
+    self.flags.access()
+  }
+
+  pub fn flags(&self) -> FileFlags {
+    self.flags
+  }
+
+  // Has `waker` woken once the handle's FIFO is next read, written or
+  // closed. Nothing else blocks, so other handles have nothing to wait for.
+  pub fn park(&self, waker: &Waker) {
+    if let Some(ref end) = self.fifo {
+      end.fifo.lock().unwrap().pipe.park(waker);
+    }
+  }
 
-    let seek = self.seek.get();
-    let new_seek = match whence {
-      Whence::SeekSet => offset as usize,
-      Whence::SeekCur => (seek as isize + offset) as usize,
-      Whence::SeekEnd => (inode_rc.borrow().size() as isize + offset) as usize
+  // Reading or writing needs the handle to have been opened for it.
+  fn check_open_for(&self, want: Access) -> Result<()> {
+    if self.access().contains(want) {
+      Ok(())
+    } else {
+      Err(Error::from_raw_os_error(EBADF))
+    }
+  }
+
+  fn nonblocking(&self) -> bool {
+    self.flags.contains(FileFlags::O_NONBLOCK)
+  }
+
+  pub fn read(&self, dst: &mut [u8]) -> Result<usize> {
+    self.read_vectored(&mut [IoSliceMut::new(dst)])
+  }
+
+  pub fn write(&self, src: &[u8]) -> Result<usize> {
+    self.write_vectored(&[IoSlice::new(src)])
+  }
+
+  pub fn read_vectored(&self, dsts: &mut [IoSliceMut]) -> Result<usize> {
+    self.check_open_for(Access::READ)?;
+    if let Some(ref end) = self.fifo {
+      return end.fifo.lock().unwrap().pipe.read(dsts, self.nonblocking());
+    }
+
+    let mut seek = self.seek.lock().unwrap();
+    let changed = self.read_vectored_at(dsts, *seek)?;
+    *seek += changed;
+    Ok(changed)
+  }
+
+  pub fn write_vectored(&self, srcs: &[IoSlice]) -> Result<usize> {
+    self.check_open_for(Access::WRITE)?;
+    if let Some(ref end) = self.fifo {
+      let mut content = end.fifo.lock().unwrap();
+      let written = content.pipe.write(srcs, self.nonblocking())?;
+      content.inode.touch_modified();
+      return Ok(written);
+    }
+
+    let mut seek = self.seek.lock().unwrap();
+    let changed = match self.file {
+      // Appends find EOF under the same lock they write under, so that
+      // appending handles can't overwrite each other.
+      DataFile(ref arc) if self.flags.contains(FileFlags::O_APPEND) => {
+        let mut inode = arc.write().unwrap();
+        *seek = inode.size();
+        inode.write_vectored(*seek, srcs)?
+      }
+      _ => self.write_vectored_at(srcs, *seek)?
     };
+    *seek += changed;
+    Ok(changed)
+  }
+
+  // The positional variants leave the seek pointer alone. FIFOs have no
+  // positions to read or write at.
+  pub fn read_vectored_at(&self, dsts: &mut [IoSliceMut], offset: usize) -> Result<usize> {
+    self.check_open_for(Access::READ)?;
+    if let Some(ref device) = self.device {
+      return device::read_vectored(&mut **device.lock().unwrap(), offset, dsts);
+    }
+
+    match self.file {
+      DataFile(ref arc) => Ok(arc.read().unwrap().read_vectored(offset, dsts)),
+      _ => Err(Error::from_raw_os_error(ESPIPE))
+    }
+  }
+
+  pub fn write_vectored_at(&self, srcs: &[IoSlice], offset: usize) -> Result<usize> {
+    self.check_open_for(Access::WRITE)?;
+    if let Some(ref device) = self.device {
+      return device::write_vectored(&mut **device.lock().unwrap(), offset, srcs);
+    }
+
+    match self.file {
+      DataFile(ref arc) => arc.write().unwrap().write_vectored(offset, srcs),
+      _ => Err(Error::from_raw_os_error(ESPIPE))
+    }
+  }
+
+  pub fn tell(&self) -> usize {
+    *self.seek.lock().unwrap()
+  }
+
+  pub fn seek(&self, offset: isize, whence: Whence) -> Result<usize> {
+    let inode_arc = match self.file {
+      DataFile(ref arc) | CharDevice(ref arc) => arc,
+      _ => return Err(Error::from_raw_os_error(ESPIPE))
+    };
+
+    let mut seek = self.seek.lock().unwrap();
+    let pos = match whence {
+      Whence::SeekSet => offset,
+      Whence::SeekCur => *seek as isize + offset,
+      Whence::SeekEnd => inode_arc.read().unwrap().size() as isize + offset
+    };
+    if pos < 0 {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    *seek = pos as usize;
+    Ok(*seek)
+  }
+}
+
+impl FifoEnd {
+  fn open(fifo: &ArcFifo, flags: FileFlags) -> FifoEnd {
+    let access = flags.access();
+    let (read, write) = (access.contains(Access::READ), access.contains(Access::WRITE));
+    fifo.lock().unwrap().pipe.open(read, write);
+    FifoEnd { fifo: fifo.clone(), read, write }
+  }
+}
 
-    self.seek.set(new_seek);
-    new_seek
+impl Drop for FifoEnd {
+  fn drop(&mut self) {
+    self.fifo.lock().unwrap().pipe.close(self.read, self.write);
   }
 }
@@ -1,53 +1,354 @@
-use time;
-use time::Timespec;
-use std::mem;
-use std::ptr;
-use std::ptr::copy_nonoverlapping;
-use std::io::{Result, Error, ErrorKind};
+use std::cmp;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::io::{Result, Error, IoSlice, IoSliceMut};
+use crate::xattr::{Xattrs, XattrFlags};
+use crate::perm::{self, Access, Credentials};
+use crate::usage::{InodeCharge, ArcUsage};
+use crate::notify::{ArcNotifier, WatchMask};
+
+pub const PAGE_SIZE: usize = 4096;
+// The largest a file may grow, as many pages as the singly- and
+// doubly-indirect page lists once held. Going past it is EFBIG.
+pub const FILE_SIZE: usize = (256 + 256 * 256) * PAGE_SIZE;
+
+// Pages are reference counted so that cloned files can share them. A shared
+// page is only copied when one of its owners writes to it. Every page, copies
+// included, counts as a block used for as long as it exists.
+pub(crate) struct Page {
+  data: [u8; PAGE_SIZE],
+  usage: Option<ArcUsage>
+}
+
+pub(crate) type ArcPage = Arc<Page>;
+
+impl Page {
+  fn new(usage: Option<ArcUsage>) -> Page {
+    if let Some(ref usage) = usage {
+      usage.alloc_block();
+    }
+
+    Page { data: [0u8; PAGE_SIZE], usage }
+  }
+
+  // The page in `slot` to write to, copied first if anyone else has it. A
+  // hole becomes a page of zeros.
+  pub(crate) fn make_mut(slot: &mut Option<ArcPage>) -> &mut Page {
+    Arc::make_mut(slot.get_or_insert_with(|| Arc::new(Page::new(None))))
+  }
+}
+
+impl Clone for Page {
+  fn clone(&self) -> Page {
+    let mut page = Page::new(self.usage.clone());
+    page.data = self.data;
+    page
+  }
+}
+
+impl Drop for Page {
+  fn drop(&mut self) {
+    if let Some(ref usage) = self.usage {
+      usage.free_block();
+    }
+  }
+}
 
-const PAGE_SIZE: usize = 4096;
-const LIST_SIZE: usize = 256;
-const FILE_SIZE: usize = 8388608;
+impl Deref for Page {
+  type Target = [u8; PAGE_SIZE];
 
-type Page = Box<([u8; PAGE_SIZE])>;
-type Entry = Page;
-type EntryList = TList<Entry>; // TODO: Option<TList> for lazy loading
-type DoubleEntryList = TList<EntryList>;
-pub type TList<T> = Box<([Option<T>; LIST_SIZE])>;
+  fn deref(&self) -> &[u8; PAGE_SIZE] {
+    &self.data
+  }
+}
 
-#[inline(always)]
-fn ceil_div(x: usize, y: usize) -> usize {
-  return (x + y - 1) / y;
+impl DerefMut for Page {
+  fn deref_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
+    &mut self.data
+  }
 }
 
-#[inline(always)]
-pub fn create_tlist<T>() -> TList<T> {
-  let mut list: TList<T> = Box::new(unsafe { mem::uninitialized() });
-  for x in list.iter_mut() { unsafe { ptr::write(x, None); } };
-  list
+// Permission bits for inodes made without an explicit mode.
+const DEFAULT_FILE_PERM: u32 = 0o644;
+const DEFAULT_DIR_PERM: u32 = 0o755;
+
+// Everything `chmod` may set: the permission, sticky, setgid and setuid bits.
+const PERM_MASK: u32 = 0o7777;
+
+// Under relatime, atime is still bumped at least this often.
+const RELATIME_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When reads update an inode's access time. These mirror the `strictatime`,
+/// `relatime` and `noatime` mount options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtimePolicy {
+  /// Every read updates atime.
+  Strict,
+  /// A read updates atime only if it is older than mtime or ctime, or more
+  /// than a day old.
+  Relatime,
+  /// Reads never update atime.
+  Noatime
+}
+
+/// A timestamp to set with `utimens`, mirroring `utimensat(2)`'s special
+/// `UTIME_NOW` and `UTIME_OMIT` values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UtimeSpec {
+  /// Set the timestamp to the current time.
+  Now,
+  /// Leave the timestamp as it is.
+  Omit,
+  /// Set the timestamp to the given time.
+  Set(SystemTime)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+  RegularFile,
+  Directory,
+  Fifo,
+  CharDevice
+}
+
+/// A snapshot of an inode's metadata, as returned by `stat` and friends.
+/// `mode` holds both the `S_IFMT` file type bits and the permission bits;
+/// `blocks` counts 512-byte units actually allocated, so holes don't count.
+/// `ctime` is the last status change (contents, mode, owner, links, xattrs),
+/// while `btime` is when the inode was created, as in `statx(2)`. `rdev` is
+/// the device number of a device node, and zero for anything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stat {
+  pub ino: u64,
+  pub file_type: FileType,
+  pub mode: u32,
+  pub nlink: u64,
+  pub uid: u32,
+  pub gid: u32,
+  pub rdev: u64,
+  pub size: u64,
+  pub blocks: u64,
+  pub blksize: u64,
+  pub atime: SystemTime,
+  pub mtime: SystemTime,
+  pub ctime: SystemTime,
+  pub btime: SystemTime,
+}
+
+impl FileType {
+  fn mode_bits(self) -> u32 {
+    match self {
+      FileType::RegularFile => libc::S_IFREG,
+      FileType::Directory => libc::S_IFDIR,
+      FileType::Fifo => libc::S_IFIFO,
+      FileType::CharDevice => libc::S_IFCHR
+    }
+  }
+
+  fn default_perm(self) -> u32 {
+    match self {
+      FileType::RegularFile | FileType::Fifo | FileType::CharDevice => DEFAULT_FILE_PERM,
+      FileType::Directory => DEFAULT_DIR_PERM
+    }
+  }
 }
 
 pub struct Inode {
-    store: Vec<u8>,
+    ino: u64,
+    file_type: FileType,
+    mode: u32, // Permission bits only; the type lives in file_type
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    rdev: u64, // Which device a device node stands for
+
+    pages: Vec<Option<ArcPage>>, // None is a hole, which reads back as zeros
     size: usize,
 
-    mod_time: Timespec,
-    access_time: Timespec,
-    create_time: Timespec,
+    mod_time: SystemTime,
+    change_time: SystemTime,
+    access_time: SystemTime,
+    create_time: SystemTime,
+
+    xattrs: Xattrs,
+    charge: Option<InodeCharge>, // Set once the inode belongs to a filesystem
+    notifier: Option<ArcNotifier>, // Likewise
+}
+
+impl Default for Inode {
+  fn default() -> Inode {
+    Inode::new()
+  }
+}
+
+// A copy shares its pages with the original until either is written to, and
+// counts as an inode of its own.
+impl Clone for Inode {
+  fn clone(&self) -> Inode {
+    Inode {
+      ino: self.ino,
+      file_type: self.file_type,
+      mode: self.mode,
+      uid: self.uid,
+      gid: self.gid,
+      nlink: self.nlink,
+      rdev: self.rdev,
+
+      pages: self.pages.clone(),
+      size: self.size,
+
+      mod_time: self.mod_time,
+      change_time: self.change_time,
+      access_time: self.access_time,
+      create_time: self.create_time,
+
+      xattrs: self.xattrs.clone(),
+      charge: self.charge.as_ref().map(|charge| InodeCharge::new(charge.usage())),
+      notifier: self.notifier.clone()
+    }
+  }
 }
 
 impl Inode {
   pub fn new() -> Inode {
-    let time_now = time::get_time();
-    let mut store = Vec::with_capacity(PAGE_SIZE);
+    Inode::with_type(FileType::RegularFile)
+  }
+
+  pub fn with_type(file_type: FileType) -> Inode {
+    Inode::with_mode(file_type, file_type.default_perm())
+  }
+
+  pub fn with_mode(file_type: FileType, mode: u32) -> Inode {
+    let time_now = SystemTime::now();
 
     Inode {
-      store: store,
+      ino: 0, // Numbered once it's entered into an InodeTable
+      file_type,
+      mode: mode & PERM_MASK,
+      uid: 0,
+      gid: 0,
+      nlink: 0,
+      rdev: 0,
+
+      pages: Vec::new(),
       size: 0,
 
       mod_time: time_now,
+      change_time: time_now,
       access_time: time_now,
-      create_time: time_now
+      create_time: time_now,
+
+      xattrs: Xattrs::default(),
+      charge: None,
+      notifier: None
+    }
+  }
+
+  /// Counts this inode, and the pages it allocates from now on, towards
+  /// `usage`.
+  pub fn charge_to(&mut self, usage: &ArcUsage) {
+    self.charge = Some(InodeCharge::new(usage));
+  }
+
+  /// Raises this inode's events with `notifier` from now on.
+  pub fn set_notifier(&mut self, notifier: &ArcNotifier) {
+    self.notifier = Some(notifier.clone());
+  }
+
+  /// Tells whoever watches this inode, or a directory it's in, that `mask`
+  /// happened to it.
+  pub fn notify(&self, mask: WatchMask) {
+    if let Some(ref notifier) = self.notifier {
+      notifier.inode_event(self.ino, mask | self.isdir_flag());
+    }
+  }
+
+  /// Tells whoever watches this directory that `mask` happened to its
+  /// entry `name`, which is `child`. Renames tie their halves by `cookie`.
+  pub fn notify_entry(&self, mask: WatchMask, cookie: u32, name: &str, child: &Inode) {
+    if let Some(ref notifier) = self.notifier {
+      notifier.entry_event(self.ino, mask | child.isdir_flag(), cookie, name, child.ino);
+    }
+  }
+
+  fn isdir_flag(&self) -> WatchMask {
+    if self.file_type == FileType::Directory { WatchMask::IN_ISDIR } else { WatchMask::empty() }
+  }
+
+  // Returns a writable page, allocating it or un-sharing it as needed.
+  fn get_or_alloc_page(&mut self, num: usize) -> &mut Page {
+    if num >= self.pages.len() {
+      self.pages.resize(num + 1, None);
+    }
+
+    let usage = self.charge.as_ref().map(|charge| charge.usage().clone());
+    let page = self.pages[num].get_or_insert_with(|| Arc::new(Page::new(usage)));
+    Arc::make_mut(page)
+  }
+
+  // Modifying the contents is also a change to the inode.
+  pub fn touch_modified(&mut self) {
+    let time_now = SystemTime::now();
+    self.mod_time = time_now;
+    self.change_time = time_now;
+  }
+
+  fn touch_changed(&mut self) {
+    self.change_time = SystemTime::now();
+  }
+
+  // A change to the metadata someone asked for, as opposed to the link
+  // count going down, is also something to tell watchers about.
+  fn touch_attrib(&mut self) {
+    self.touch_changed();
+    self.notify(WatchMask::IN_ATTRIB);
+  }
+
+  /// Whether a read now should update the access time, under `policy`.
+  pub fn access_due(&self, policy: AtimePolicy) -> bool {
+    let atime = self.access_time;
+    match policy {
+      AtimePolicy::Strict => true,
+      AtimePolicy::Relatime => atime <= self.mod_time || atime <= self.change_time
+        || SystemTime::now().duration_since(atime).is_ok_and(|age| age >= RELATIME_MAX_AGE),
+      AtimePolicy::Noatime => false
+    }
+  }
+
+  /// Records a read of this inode.
+  pub fn touch_accessed(&mut self) {
+    self.access_time = SystemTime::now();
+  }
+
+  fn write_at(&mut self, offset: usize, data: &[u8]) {
+    let mut pos = offset;
+    let mut data = data;
+    while !data.is_empty() {
+      let page_offset = pos % PAGE_SIZE;
+      let len = cmp::min(data.len(), PAGE_SIZE - page_offset);
+      let page = self.get_or_alloc_page(pos / PAGE_SIZE);
+      page[page_offset..page_offset + len].copy_from_slice(&data[..len]);
+
+      pos += len;
+      data = &data[len..];
+    }
+  }
+
+  fn read_at(&self, offset: usize, data: &mut [u8]) {
+    let mut pos = offset;
+    let mut done = 0;
+    while done < data.len() {
+      let page_offset = pos % PAGE_SIZE;
+      let len = cmp::min(data.len() - done, PAGE_SIZE - page_offset);
+      let dst = &mut data[done..done + len];
+      match self.pages.get(pos / PAGE_SIZE) {
+        Some(Some(page)) => dst.copy_from_slice(&page[page_offset..page_offset + len]),
+        _ => dst.iter_mut().for_each(|b| *b = 0),
+      }
+
+      pos += len;
+      done += len;
     }
   }
 
@@ -111,48 +412,386 @@ impl Inode {
     //println!("store.len: {}", self.store.len());
     //println!("data.len: {}, offset+data.len: {}", data.len(), offset+data.len());
 
-    let end = offset + data.len();
+    self.write_vectored(offset, &[IoSlice::new(data)])
+  }
+
+  // All slices are copied in under the same borrow of the inode, so readers
+  // never see a vectored write half-applied.
+  // Writing nothing changes nothing, not even mtime, and a write that would
+  // end past FILE_SIZE fails with EFBIG.
+  pub fn write_vectored(&mut self, offset: usize, bufs: &[IoSlice]) -> Result<usize> {
+    let len = bufs.iter().try_fold(0usize, |len, buf| len.checked_add(buf.len()));
+    match len {
+      Some(0) => return Ok(0),
+      Some(len) => check_size(offset, len)?,
+      None => return Err(Error::from_raw_os_error(libc::EFBIG))
+    }
+
     //if self.store.capacity() < end {
     //    return Err(Error::new(ErrorKind::Other, "OOM"))
     //}
 
-    if offset + data.len() >= self.store.len() {
-        //self.store.reserve(offset + data.len());
-        self.store.resize(offset + data.len(), b'\0');
-    }
-
     //TODO: bench this
     //self.store.extend_from_slice(data);
-    let slice = &mut self.store[offset..offset+data.len()];
-    unsafe {
-        let src = data.as_ptr();
-        copy_nonoverlapping(src, slice.as_mut_ptr(), data.len());
+    let mut pos = offset;
+    for buf in bufs {
+        self.write_at(pos, buf);
+        pos += buf.len();
     }
 
-    let time_now = time::get_time();
-    self.mod_time = time_now;
-    self.access_time = time_now;
-
-    Ok(data.len())
+    self.size = cmp::max(self.size, pos);
+    self.touch_modified();
+    self.notify(WatchMask::IN_MODIFY);
+    Ok(pos - offset)
   }
 
   pub fn read(&self, offset: usize, data: &mut [u8]) -> usize {
-    let slice = &self.store[offset..data.len()];
-    unsafe {
-        let dst = data.as_mut_ptr();
-        copy_nonoverlapping(slice.as_ptr(), dst, data.len());
+    self.read_vectored(offset, &mut [IoSliceMut::new(data)])
+  }
+
+  // Reads stop at the end of the file; bytes past EOF are left untouched.
+  pub fn read_vectored(&self, offset: usize, bufs: &mut [IoSliceMut]) -> usize {
+    let mut pos = offset;
+    for buf in bufs.iter_mut() {
+        if pos >= self.size {
+            break;
+        }
+
+        let len = cmp::min(buf.len(), self.size - pos);
+        self.read_at(pos, &mut buf[..len]);
+        pos += len;
     }
 
-    //self.access_time = time::get_time();
-    data.len()
+    pos - offset
+  }
+
+  /// Replaces this inode's contents with `src`'s. No data is copied: both
+  /// inodes point at the same pages until one of them writes.
+  pub fn clone_pages_from(&mut self, src: &Inode) {
+    self.pages = src.pages.clone();
+    self.size = src.size;
+    self.touch_modified();
+    self.notify(WatchMask::IN_MODIFY);
+  }
+
+  /// Whether this inode holds the same bytes as `other`. Pages they share
+  /// aren't looked at.
+  pub fn same_contents(&self, other: &Inode) -> bool {
+    let zeros = [0u8; PAGE_SIZE];
+    self.size == other.size && (0..self.size.div_ceil(PAGE_SIZE)).all(|i| {
+      let len = cmp::min(PAGE_SIZE, self.size - i * PAGE_SIZE);
+      let (a, b) = (self.pages.get(i).and_then(Option::as_ref), other.pages.get(i).and_then(Option::as_ref));
+      match (a, b) {
+        (Some(a), Some(b)) if Arc::ptr_eq(a, b) => true,
+        _ => a.map_or(&zeros, |p| &p.data)[..len] == b.map_or(&zeros, |p| &p.data)[..len]
+      }
+    })
+  }
+
+  /// Whether this inode's type, permission bits, owner, device number and
+  /// extended attributes are the same as `other`'s.
+  pub fn same_attributes(&self, other: &Inode) -> bool {
+    (self.file_type, self.mode, self.uid, self.gid, self.rdev) ==
+      (other.file_type, other.mode, other.uid, other.gid, other.rdev) && self.xattrs == other.xattrs
+  }
+
+  /// Copies up to `len` bytes from `src` at `src_offset` into this inode at
+  /// `offset`, stopping at `src`'s EOF. Whole pages that line up on both sides
+  /// are shared rather than copied. Returns the number of bytes copied, or
+  /// EFBIG if they would end past FILE_SIZE.
+  pub fn copy_range(&mut self, offset: usize, src: &Inode, src_offset: usize, len: usize) -> Result<usize> {
+    if src_offset >= src.size {
+      return Ok(0);
+    }
+
+    let len = cmp::min(len, src.size - src_offset);
+    check_size(offset, len)?;
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut done = 0;
+    while done < len {
+      let (src_pos, dst_pos) = (src_offset + done, offset + done);
+      let chunk = cmp::min(len - done, PAGE_SIZE - src_pos % PAGE_SIZE);
+      let aligned = src_pos % PAGE_SIZE == 0 && dst_pos % PAGE_SIZE == 0;
+
+      // A whole source page can be shared as long as it also covers the
+      // whole destination page, or everything the destination holds there.
+      let dst_page = dst_pos / PAGE_SIZE;
+      if aligned && (chunk == PAGE_SIZE || dst_pos + chunk >= self.size) {
+        if dst_page >= self.pages.len() {
+          self.pages.resize(dst_page + 1, None);
+        }
+
+        self.pages[dst_page] = src.pages.get(src_pos / PAGE_SIZE).cloned().flatten();
+        if chunk < PAGE_SIZE {
+          // Past the copied range the shared page may hold stale bytes from
+          // `src`; they must read back as zeros here.
+          if let Some(ref mut page) = self.pages[dst_page] {
+            if page[chunk..].iter().any(|&b| b != 0) {
+              Arc::make_mut(page)[chunk..].iter_mut().for_each(|b| *b = 0);
+            }
+          }
+        }
+      } else {
+        src.read_at(src_pos, &mut buf[..chunk]);
+        self.write_at(dst_pos, &buf[..chunk]);
+      }
+
+      done += chunk;
+    }
+
+    self.size = cmp::max(self.size, offset + len);
+    self.touch_modified();
+    self.notify(WatchMask::IN_MODIFY);
+    Ok(len)
+  }
+
+  /// The pages holding bytes `offset..offset + len`, for a private mapping
+  /// to share until it writes to them. Holes and pages past EOF are None.
+  pub(crate) fn share_pages(&self, offset: usize, len: usize) -> Vec<Option<ArcPage>> {
+    let end = cmp::min(offset.saturating_add(len), self.size).div_ceil(PAGE_SIZE);
+    (offset / PAGE_SIZE..end).map(|num| self.pages.get(num).cloned().flatten()).collect()
+  }
+
+  /// Page `num`, unless it's a hole.
+  pub(crate) fn page(&self, num: usize) -> Option<&ArcPage> {
+    self.pages.get(num).and_then(Option::as_ref)
+  }
+
+  /// Page `num` for a shared mapping to store into, allocated or un-shared
+  /// as needed. `stored` must follow once it's done.
+  pub(crate) fn page_mut(&mut self, num: usize) -> &mut Page {
+    self.get_or_alloc_page(num)
+  }
+
+  /// Finishes stores made through `page_mut` into page `num`. Whatever
+  /// landed past EOF is dropped, so it reads back as zeros should the file
+  /// grow.
+  pub(crate) fn stored(&mut self, num: usize) {
+    let tail = self.size.saturating_sub(num * PAGE_SIZE);
+    if tail < PAGE_SIZE && self.page(num).is_some() {
+      self.get_or_alloc_page(num)[tail..].iter_mut().for_each(|b| *b = 0);
+    }
+    self.touch_modified();
+  }
+
+  /// Stores `data` at `offset` the way a shared mapping does, straight into
+  /// the pages. It never moves EOF; whatever would land past it is dropped.
+  pub(crate) fn store(&mut self, offset: usize, data: &[u8]) {
+    let end = cmp::min(offset.saturating_add(data.len()), self.size);
+    if end > offset {
+      self.write_at(offset, &data[..end - offset]);
+      self.touch_modified();
+    }
+  }
+
+  /// Cuts the file down to `len` bytes, or extends it to `len` with a hole.
+  /// Past FILE_SIZE, it fails with EFBIG.
+  pub fn truncate(&mut self, len: usize) -> Result<()> {
+    check_size(len, 0)?;
+    if len < self.size {
+      self.pages.truncate(len.div_ceil(PAGE_SIZE));
+      // What's left of the last page past `len` must read back as zeros
+      // should the file grow again.
+      let (num, tail) = (len / PAGE_SIZE, len % PAGE_SIZE);
+      if tail != 0 && self.pages.get(num).is_some_and(Option::is_some) {
+        self.get_or_alloc_page(num)[tail..].iter_mut().for_each(|b| *b = 0);
+      }
+    }
+
+    self.size = len;
+    self.touch_modified();
+    self.notify(WatchMask::IN_MODIFY);
+    Ok(())
+  }
+
+  pub fn xattrs(&self) -> &Xattrs {
+    &self.xattrs
+  }
+
+  pub fn set_xattr(&mut self, cred: &Credentials, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+    self.check_xattr_change(cred, name)?;
+    self.xattrs.set(name, value, flags)?;
+    self.touch_attrib();
+    Ok(())
+  }
+
+  pub fn remove_xattr(&mut self, cred: &Credentials, name: &str) -> Result<()> {
+    self.check_xattr_change(cred, name)?;
+    self.xattrs.remove(name)?;
+    self.touch_attrib();
+    Ok(())
   }
 
   pub fn size(&self) -> usize {
-    self.store.len()
+    self.size
+  }
+
+  pub fn ino(&self) -> u64 {
+    self.ino
+  }
+
+  pub fn set_ino(&mut self, ino: u64) {
+    self.ino = ino;
+  }
+
+  pub fn rdev(&self) -> u64 {
+    self.rdev
+  }
+
+  pub fn set_rdev(&mut self, rdev: u64) {
+    self.rdev = rdev;
+  }
+
+  pub fn file_type(&self) -> FileType {
+    self.file_type
+  }
+
+  pub fn mode(&self) -> u32 {
+    self.mode
+  }
+
+  pub fn set_mode(&mut self, mode: u32) {
+    self.mode = mode & PERM_MASK;
+  }
+
+  pub fn owner(&self) -> (u32, u32) {
+    (self.uid, self.gid)
+  }
+
+  pub fn set_owner(&mut self, uid: u32, gid: u32) {
+    self.uid = uid;
+    self.gid = gid;
+  }
+
+  pub fn check_access(&self, cred: &Credentials, want: Access) -> Result<()> {
+    let is_dir = self.file_type == FileType::Directory;
+    perm::check(cred, self.uid, self.gid, self.mode, is_dir, want)
+  }
+
+  // Changing `user.` attributes takes write permission, even for the owner,
+  // and only regular files and directories have them. Changing `trusted.`
+  // or `security.` ones takes root.
+  fn check_xattr_change(&self, cred: &Credentials, name: &str) -> Result<()> {
+    if name.starts_with("user.") {
+      match self.file_type {
+        FileType::RegularFile | FileType::Directory => self.check_access(cred, Access::WRITE),
+        _ => Err(Error::from_raw_os_error(libc::EPERM))
+      }
+    } else if (name.starts_with("trusted.") || name.starts_with("security.")) && !cred.is_root() {
+      Err(Error::from_raw_os_error(libc::EPERM))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Changes the permission bits on behalf of `cred`, who must own the inode.
+  /// Callers outside the inode's group can't hand out its set-group-ID bit.
+  pub fn chmod(&mut self, cred: &Credentials, mode: u32) -> Result<()> {
+    perm::check_owner(cred, self.uid)?;
+    let mut mode = mode & PERM_MASK;
+    if !cred.is_root() && !cred.in_group(self.gid) {
+      mode &= !libc::S_ISGID;
+    }
+
+    self.mode = mode;
+    self.touch_attrib();
+    Ok(())
   }
 
-  pub fn stat(&self) -> (Timespec, Timespec, Timespec) {
-    (self.create_time, self.access_time, self.mod_time)
+  /// Sets the access and modification times on behalf of `cred`. Setting
+  /// both to the current time needs write permission or ownership; setting
+  /// any other time needs ownership.
+  pub fn utimens(&mut self, cred: &Credentials, atime: UtimeSpec, mtime: UtimeSpec) -> Result<()> {
+    match (atime, mtime) {
+      (UtimeSpec::Omit, UtimeSpec::Omit) => return Ok(()),
+      (UtimeSpec::Set(_), _) | (_, UtimeSpec::Set(_)) => perm::check_owner(cred, self.uid)?,
+      _ => if perm::check_owner(cred, self.uid).is_err() {
+        self.check_access(cred, Access::WRITE)?;
+      }
+    }
+
+    let time_now = SystemTime::now();
+    match atime {
+      UtimeSpec::Now => self.access_time = time_now,
+      UtimeSpec::Set(time) => self.access_time = time,
+      UtimeSpec::Omit => {}
+    }
+
+    match mtime {
+      UtimeSpec::Now => self.mod_time = time_now,
+      UtimeSpec::Set(time) => self.mod_time = time,
+      UtimeSpec::Omit => {}
+    }
+
+    self.change_time = time_now;
+    self.notify(WatchMask::IN_ATTRIB);
+    Ok(())
+  }
+
+  /// Changes the owner and/or group on behalf of `cred`. Only root may give
+  /// an inode away; its owner may move it into any group they belong to.
+  /// Changing either clears the set-user-ID bit, and the set-group-ID bit
+  /// when it means something, on anything but a directory.
+  pub fn chown(&mut self, cred: &Credentials, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let is_owner = cred.uid == self.uid;
+    let uid_ok = uid.is_none_or(|uid| cred.is_root() || (is_owner && uid == self.uid));
+    let gid_ok = gid.is_none_or(|gid| cred.is_root() || (is_owner && (gid == self.gid || cred.in_group(gid))));
+    if !uid_ok || !gid_ok {
+      return Err(Error::from_raw_os_error(libc::EPERM));
+    }
+
+    self.uid = uid.unwrap_or(self.uid);
+    self.gid = gid.unwrap_or(self.gid);
+    if self.file_type != FileType::Directory && (uid.is_some() || gid.is_some()) {
+      self.mode &= !libc::S_ISUID;
+      if self.mode & libc::S_IXGRP != 0 {
+        self.mode &= !libc::S_ISGID;
+      }
+    }
+
+    self.touch_attrib();
+    Ok(())
+  }
+
+  // Gaining a link leaves ctime alone: an inode gains links when it's
+  // created, which already set its times, or when it's renamed, where losing
+  // the old link has already updated ctime.
+  pub fn link(&mut self) {
+    self.nlink += 1;
+  }
+
+  pub fn unlink(&mut self) {
+    self.nlink -= 1;
+    self.touch_changed();
+  }
+
+  pub fn stat(&self) -> Stat {
+    let allocated = self.pages.iter().filter(|page| page.is_some()).count();
+
+    Stat {
+      ino: self.ino,
+      file_type: self.file_type,
+      mode: self.file_type.mode_bits() | self.mode,
+      nlink: self.nlink,
+      uid: self.uid,
+      gid: self.gid,
+      rdev: self.rdev,
+      size: self.size as u64,
+      blocks: (allocated * PAGE_SIZE / 512) as u64,
+      blksize: PAGE_SIZE as u64,
+      atime: self.access_time,
+      mtime: self.mod_time,
+      ctime: self.change_time,
+      btime: self.create_time,
+    }
+  }
+}
+
+/// Fails with EFBIG unless `len` bytes at `offset` end within FILE_SIZE.
+pub(crate) fn check_size(offset: usize, len: usize) -> Result<()> {
+  match offset.checked_add(len) {
+    Some(end) if end <= FILE_SIZE => Ok(()),
+    _ => Err(Error::from_raw_os_error(libc::EFBIG))
   }
 }
 
@@ -160,9 +799,11 @@ impl Inode {
 mod tests {
   extern crate rand;
 
-  use super::{Inode};
+  use super::{Inode, PAGE_SIZE, FILE_SIZE};
+  use std::sync::Arc;
   use self::rand::random;
-  use time;
+  use std::io::{Result, IoSlice, IoSliceMut};
+  use std::time::SystemTime;
 
   fn rand_array(size: usize) -> Vec<u8> {
     (0..size).map(|_| random::<u8>()).collect()
@@ -173,12 +814,12 @@ mod tests {
     const SIZE: usize = 4096 * 8 + 3434;
 
     let original_data = rand_array(SIZE);
-    let time_now = time::get_time();
+    let time_now = SystemTime::now();
     let mut inode = Inode::new();
     let mut buf = [0u8; SIZE];
 
     // Write the random data, read it back into buffer
-    inode.write(0, original_data.as_slice());
+    inode.write(0, original_data.as_slice()).unwrap();
     inode.read(0, &mut buf);
 
     // Make sure inode is right size
@@ -189,7 +830,139 @@ mod tests {
       assert_eq!(buf[i], original_data[i]);
     }
 
-    let (create, _, _) = inode.stat();
-    assert_eq!(create.sec, time_now.sec);
+    let stat = inode.stat();
+    assert!(stat.btime >= time_now && stat.btime <= SystemTime::now());
+    assert!(stat.ctime >= stat.btime);
+    assert_eq!(stat.size, SIZE as u64);
+    assert_eq!(stat.blocks, 9 * 4096 / 512);
+  }
+
+  #[test]
+  fn test_vectored_read_stops_at_eof() {
+    let mut inode = Inode::new();
+    let bufs = [IoSlice::new(b"head"), IoSlice::new(b""), IoSlice::new(b"payload")];
+    assert_eq!(inode.write_vectored(2, &bufs).unwrap(), 11);
+    assert_eq!(inode.size(), 13);
+
+    let mut first = [0xffu8; 6];
+    let mut second = [0xffu8; 16];
+    let read = {
+      let mut dst = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+      inode.read_vectored(0, &mut dst)
+    };
+
+    assert_eq!(read, 13);
+    assert_eq!(&first, b"\0\0head");
+    assert_eq!(&second[..7], b"payload");
+    assert_eq!(second[7], 0xff);
+    assert_eq!(inode.read(20, &mut first), 0);
+  }
+
+  #[test]
+  fn test_empty_and_overflowing_writes() {
+    let mut inode = Inode::new();
+    let mtime = inode.stat().mtime;
+    assert_eq!(inode.write_vectored(1000, &[]).unwrap(), 0);
+    assert_eq!(inode.write(1000, b"").unwrap(), 0);
+    assert_eq!((inode.size(), inode.stat().mtime), (0, mtime));
+
+    let err = inode.write(usize::MAX, b"x").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    assert_eq!(inode.size(), 0);
+  }
+
+  #[test]
+  fn test_max_file_size() {
+    fn efbig<T>(result: Result<T>) -> bool {
+      result.err().and_then(|err| err.raw_os_error()) == Some(libc::EFBIG)
+    }
+
+    let mut inode = Inode::new();
+
+    // Nothing gets allocated for a write or truncate that's too big.
+    assert!(efbig(inode.write(1 << 50, b"x")));
+    assert!(efbig(inode.write(FILE_SIZE, b"x")));
+    assert!(efbig(inode.truncate(FILE_SIZE + 1)));
+    assert_eq!((inode.size(), inode.pages.len()), (0, 0));
+
+    // Up to the limit is fine.
+    assert_eq!(inode.write(FILE_SIZE - 1, b"x").unwrap(), 1);
+    inode.truncate(FILE_SIZE).unwrap();
+    assert_eq!(inode.size(), FILE_SIZE);
+
+    let mut dst = Inode::new();
+    assert!(efbig(dst.copy_range(FILE_SIZE, &inode, 0, 1)));
+    assert!(efbig(dst.copy_range(usize::MAX, &inode, 0, 1)));
+    assert_eq!(dst.copy_range(FILE_SIZE - 1, &inode, 0, 1).unwrap(), 1);
+  }
+
+  #[test]
+  fn test_cloned_pages_copy_on_write() {
+    const SIZE: usize = PAGE_SIZE * 3 + 100;
+    let data = rand_array(SIZE);
+    let mut src = Inode::new();
+    src.write(0, &data).unwrap();
+
+    let mut dst = Inode::new();
+    dst.clone_pages_from(&src);
+    assert_eq!(dst.size(), SIZE);
+    for (a, b) in src.pages.iter().zip(dst.pages.iter()) {
+      assert!(Arc::ptr_eq(a.as_ref().unwrap(), b.as_ref().unwrap()));
+    }
+
+    // Writing one byte only un-shares the page it lands on.
+    dst.write(PAGE_SIZE + 1, &[!data[PAGE_SIZE + 1]]).unwrap();
+    assert!(Arc::ptr_eq(src.pages[0].as_ref().unwrap(), dst.pages[0].as_ref().unwrap()));
+    assert!(!Arc::ptr_eq(src.pages[1].as_ref().unwrap(), dst.pages[1].as_ref().unwrap()));
+
+    let mut buf = vec![0u8; SIZE];
+    src.read(0, &mut buf);
+    assert_eq!(buf, data);
+    dst.read(0, &mut buf);
+    assert_eq!(buf[PAGE_SIZE + 1], !data[PAGE_SIZE + 1]);
+  }
+
+  #[test]
+  fn test_copy_range_unaligned_tail() {
+    let data = rand_array(PAGE_SIZE * 2);
+    let mut src = Inode::new();
+    src.write(0, &data).unwrap();
+
+    // The first page is shared, the partial second one must not leak the
+    // rest of src's page into dst.
+    let mut dst = Inode::new();
+    assert_eq!(dst.copy_range(0, &src, 0, PAGE_SIZE + 10).unwrap(), PAGE_SIZE + 10);
+    assert!(Arc::ptr_eq(src.pages[0].as_ref().unwrap(), dst.pages[0].as_ref().unwrap()));
+
+    dst.write(PAGE_SIZE + 20, b"x").unwrap();
+    let mut buf = [0xffu8; 21];
+    dst.read(PAGE_SIZE, &mut buf);
+    assert_eq!(&buf[..10], &data[PAGE_SIZE..PAGE_SIZE + 10]);
+    assert!(buf[10..20].iter().all(|&b| b == 0));
+    assert_eq!(buf[20], b'x');
+
+    // Unaligned offsets fall back to copying, and copies stop at EOF.
+    assert_eq!(dst.copy_range(3, &src, PAGE_SIZE * 2 - 5, 100).unwrap(), 5);
+    dst.read(3, &mut buf[..5]);
+    assert_eq!(&buf[..5], &data[PAGE_SIZE * 2 - 5..]);
+  }
+
+  #[test]
+  fn test_truncate() {
+    let data = rand_array(PAGE_SIZE * 3);
+    let mut inode = Inode::new();
+    inode.write(0, &data).unwrap();
+
+    inode.truncate(PAGE_SIZE + 10).unwrap();
+    assert_eq!(inode.size(), PAGE_SIZE + 10);
+    assert_eq!(inode.pages.len(), 2);
+
+    // Growing it again shows zeros, not what was cut off.
+    inode.truncate(PAGE_SIZE * 4).unwrap();
+    let mut buf = vec![0xffu8; PAGE_SIZE * 4];
+    assert_eq!(inode.read(0, &mut buf), PAGE_SIZE * 4);
+    assert_eq!(&buf[..PAGE_SIZE + 10], &data[..PAGE_SIZE + 10]);
+    assert!(buf[PAGE_SIZE + 10..].iter().all(|&b| b == 0));
+    assert_eq!(inode.pages.len(), 2);
   }
 }
@@ -1,24 +1,71 @@
-extern crate time;
+extern crate libc;
 #[macro_use]
 extern crate bitflags;
 
+mod aio;
+mod device;
+mod diff;
+#[cfg(test)]
+mod differential;
 mod directory;
+mod fd;
 mod file;
 mod inode;
-
-use file::{RcInode, File, FileHandle};
-use file::File::{EmptyFile, DataFile, Directory};
-use time::Timespec;
-use std::rc::Rc;
-use std::cell::{RefCell};
+mod lock;
+mod mmap;
+mod notify;
+mod path;
+mod perm;
+mod pipe;
+mod shared;
+mod snapshot;
+mod table;
+mod transaction;
+mod usage;
+mod xattr;
+
+use file::{ArcInode, ArcDevice, File, FileHandle};
+use file::File::{EmptyFile, DataFile, Directory, Fifo, CharDevice};
+use std::cmp;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::Waker;
 use std::collections::HashMap;
-use std::io::{Result, Error, ErrorKind};
+use std::io::{Result, Error, IoSlice, IoSliceMut};
 use directory::DirectoryHandle;
+use fd::FdTable;
+use lock::{LockTable, Range, Wait};
+use table::InodeTable;
+use perm::Access;
+use shared::Shared;
+use libc::{EACCES, EBADF, EBUSY, EEXIST, EINVAL, EISDIR, EMFILE, ENOENT, ENOTDIR, ENODEV, ENOTEMPTY, ENXIO, EPERM, ESTALE};
+pub use aio::AsyncVfs;
+pub use device::{Device, Null, Zero, Full, Urandom, DEV_NULL, DEV_ZERO, DEV_FULL, DEV_URANDOM};
+pub use diff::TreeDiff;
+pub use directory::DirEntry;
 pub use file::Whence;
-pub use inode::Inode;
+pub use inode::{Inode, Stat, FileType, AtimePolicy, UtimeSpec};
+pub use lock::{FlockOp, LockCmd, LockType, FileLock};
+pub use mmap::{Mapping, MappedPage, MappedPageMut, MapProt, MapFlags};
+pub use notify::{WatchMask, WatchEvent, WatchDescriptor, NotifyInstance, MAX_QUEUED_EVENTS};
+pub use perm::Credentials;
+pub use pipe::PIPE_BUF;
+pub use transaction::Transaction;
+pub use usage::StatFs;
+pub use xattr::{XattrFlags, XATTR_NAME_MAX, XATTR_SIZE_MAX, XATTR_LIST_MAX};
 
 pub type FileDescriptor = isize;
 
+// The mode `open` creates files with, as `fopen` and `std::fs` do.
+const DEFAULT_CREATE_MODE: u32 = 0o666;
+
+// The umask a new Vfs starts with, as most shells set it.
+const DEFAULT_UMASK: u32 = 0o022;
+
+// How big a filesystem says it is until told otherwise: 4GiB of blocks.
+const DEFAULT_CAPACITY_BLOCKS: u64 = 1 << 20;
+const DEFAULT_CAPACITY_INODES: u64 = 1 << 20;
+
 bitflags!{
     pub struct FileFlags: u32 {
         const O_RDONLY =   0b00000001;
@@ -30,106 +77,1072 @@ bitflags!{
     }
 }
 
-//TODO: paths ("/dir/datafile")
+impl FileFlags {
+  // The access an open asks for. As with POSIX's O_RDONLY, asking for
+  // neither reading nor writing means reading.
+  fn access(self) -> Access {
+    if self.contains(FileFlags::O_RDWR) {
+      Access::READ | Access::WRITE
+    } else if self.contains(FileFlags::O_WRONLY) {
+      Access::WRITE
+    } else {
+      Access::READ
+    }
+  }
+}
+
+/// A filesystem. Every operation takes `&self`, so one `Vfs` can be shared
+/// between threads, e.g. behind an `Arc`; each operation is atomic with
+/// respect to the others.
+///
+/// Every inode and directory has a lock of its own, so I/O on different
+/// files runs in parallel, as do reads of the same file. Lookups only take
+/// the directories they pass through for reading.
 pub struct Vfs<'r> {
   cwd: File<'r>,
-  fd_table: HashMap<FileDescriptor, FileHandle<'r>>,
-  fds: Vec<FileDescriptor>
+  fds: RwLock<FdTable<'r>>,
+  inodes: Mutex<InodeTable<'r>>,
+  devices: Mutex<HashMap<u64, ArcDevice>>,
+  locks: LockTable,
+  // Held while names are added to or removed from the tree, so that checking
+  // for a name and then acting on it can't race with another thread. Lookups
+  // and I/O never take it.
+  namespace: Mutex<()>,
+  // Taken for reading by every operation on names or file contents, and for
+  // writing by a transaction, so that nothing sees one half done.
+  tx_gate: RwLock<()>,
+  capacity: Mutex<(u64, u64)>, // Blocks and inodes
+  atime_policy: RwLock<AtimePolicy>,
+  cred: RwLock<Credentials>,
+  umask: AtomicU32,
+  read_only: bool // Set on snapshots
+}
+
+impl<'r> Default for Vfs<'r> {
+  fn default() -> Vfs<'r> {
+    Vfs::new()
+  }
 }
 
 impl<'r> Vfs<'r> {
+  /// Creates an empty filesystem, operated on by root.
   pub fn new() -> Vfs<'r> {
+    Vfs::with_credentials(Credentials::root())
+  }
+
+  /// Creates an empty filesystem operated on by `cred`, who also owns its
+  /// root directory.
+  pub fn with_credentials(cred: Credentials) -> Vfs<'r> {
+    let root = File::new_dir(None, 0o755);
+    root.with_inode_mut(|inode| inode.set_owner(cred.uid, cred.gid));
+    let mut inodes = InodeTable::new();
+    inodes.insert(&root);
+    Vfs::from_tree(root, inodes, cred)
+  }
+
+  fn from_tree(root: File<'r>, inodes: InodeTable<'r>, cred: Credentials) -> Vfs<'r> {
     Vfs {
-      cwd: File::new_dir(None),
-      fd_table: HashMap::new(),
-      fds: (0..(256 - 2)).map(|i| 256 - i).collect(),
+      cwd: root,
+      fds: RwLock::new(FdTable::new()),
+      inodes: Mutex::new(inodes),
+      devices: Mutex::new(HashMap::new()),
+      locks: LockTable::new(),
+      namespace: Mutex::new(()),
+      tx_gate: RwLock::new(()),
+      capacity: Mutex::new((DEFAULT_CAPACITY_BLOCKS, DEFAULT_CAPACITY_INODES)),
+      atime_policy: RwLock::new(AtimePolicy::Relatime),
+      cred: RwLock::new(cred),
+      umask: AtomicU32::new(DEFAULT_UMASK),
+      read_only: false
     }
   }
 
-  #[inline(always)]
-  fn extract_fd(fd_opt: &Option<FileDescriptor>) -> FileDescriptor {
-    match fd_opt {
-      &Some(fd) => fd,
-      &None => panic!("Error in FD allocation.")
-    }
+  /// Switches who subsequent operations are performed as, like `setresuid`
+  /// and `setgroups` would for a process.
+  pub fn set_credentials(&self, cred: Credentials) {
+    *self.cred.write().unwrap() = cred;
   }
 
-  pub fn open(&mut self, path: &'r str, flags: FileFlags) -> Result<FileDescriptor> {
-    let lookup = self.cwd.get(path);
-    let file = match lookup {
-      Some(f) => f,
-      None => {
+  pub fn credentials(&self) -> Credentials {
+    self.cred().clone()
+  }
+
+  pub(crate) fn cred(&self) -> RwLockReadGuard<'_, Credentials> {
+    self.cred.read().unwrap()
+  }
+
+  /// Sets the permission bits taken away from the mode of every file and
+  /// directory created from now on, returning the old mask like `umask(2)`.
+  /// Only the `0o777` bits count.
+  pub fn set_umask(&self, mask: u32) -> u32 {
+    self.umask.swap(mask & 0o777, Ordering::Relaxed)
+  }
+
+  pub fn umask(&self) -> u32 {
+    self.umask.load(Ordering::Relaxed)
+  }
+
+  // The mode a file asked to be created with `mode` actually gets.
+  fn creation_mode(&self, mode: u32) -> u32 {
+    mode & !self.umask()
+  }
+
+  fn new_data_file(mode: u32) -> File<'r> {
+    let arcinode = Arc::new(RwLock::new(Shared::new(Inode::with_mode(FileType::RegularFile, mode))));
+    File::new_data_file(arcinode)
+  }
+
+  // Numbers a newly made file and links it into `dir`, owned by the caller.
+  fn add_new_file(&self, dir: &mut File<'r>, name: &'r str, file: &File<'r>) {
+    self.inodes.lock().unwrap().insert(file);
+    self.set_new_owner(dir, file);
+    dir.insert(name, file.clone());
+  }
+
+  // New files belong to the caller. In a set-group-ID directory they take
+  // the directory's group instead, and new subdirectories inherit the bit.
+  fn set_new_owner(&self, dir: &File<'r>, file: &File<'r>) {
+    let (dir_mode, (_, dir_gid)) = dir.with_inode(|inode| (inode.mode(), inode.owner()));
+    let inherit = dir_mode & libc::S_ISGID != 0;
+    let cred = self.cred();
+    file.with_inode_mut(|inode| {
+      let gid = if inherit { dir_gid } else { cred.gid };
+      inode.set_owner(cred.uid, gid);
+      if inherit && inode.file_type() == FileType::Directory {
+        let mode = inode.mode() | libc::S_ISGID;
+        inode.set_mode(mode);
+      }
+    });
+  }
+
+  // Serializes changes to the tree's names.
+  fn lock_namespace(&self) -> MutexGuard<'_, ()> {
+    self.namespace.lock().unwrap()
+  }
+
+  /// Opens the file at `path`. With `O_CREAT`, a missing file is created
+  /// with mode `0o666`, less the umask; use `open_with_mode` to pick the
+  /// mode.
+  pub fn open(&self, path: &'r str, flags: FileFlags) -> Result<FileDescriptor> {
+    self.open_with_mode(path, flags, DEFAULT_CREATE_MODE)
+  }
+
+  /// Opens the file at `path`, creating it with permission bits `mode`, less
+  /// the umask, if it is missing and `flags` has `O_CREAT`. Opening an
+  /// existing file checks read and/or write permission according to
+  /// `flags`; creating one needs write and search permission on its
+  /// directory instead.
+  ///
+  /// Opening a FIFO never waits for the other end, except that a
+  /// non-blocking open for writing alone fails with ENXIO while it has no
+  /// readers, as POSIX has it. Opening a device node fails with ENXIO if no
+  /// driver is registered for its device number.
+  pub fn open_with_mode(&self, path: &'r str, flags: FileFlags, mode: u32) -> Result<FileDescriptor> {
+    let _tx = self.enter();
+    let file = match self.resolve(path) {
+      Ok(f) => {
+        if flags.access().contains(Access::WRITE) {
+          self.check_writable()?;
+        }
+        f.check_access(&self.cred(), flags.access())?;
+        f
+      }
+      Err(ref e) if e.raw_os_error() == Some(ENOENT) => {
         if (flags & FileFlags::O_CREAT) == FileFlags::O_CREAT {
-          // FIXME: Fetch from allocator
-          let rcinode = Rc::new(RefCell::new(Box::new(Inode::new())));
-          let file = File::new_data_file(rcinode);
-          self.cwd.insert(path, file.clone());
-          file
+          self.create(path, flags, mode)?
         } else {
           EmptyFile
         }
       }
+      Err(e) => return Err(e)
+    };
+
+    if let Fifo(ref arc) = file {
+      let write_only = flags.access() == Access::WRITE;
+      if write_only && flags.contains(FileFlags::O_NONBLOCK) && arc.lock().unwrap().pipe.readers() == 0 {
+        return Err(Error::from_raw_os_error(ENXIO));
+      }
+    }
+
+    let device = match file {
+      CharDevice(ref arc) => {
+        let rdev = arc.read().unwrap().rdev();
+        match self.devices.lock().unwrap().get(&rdev) {
+          Some(device) => Some(device.clone()),
+          None => return Err(Error::from_raw_os_error(ENXIO))
+        }
+      }
+      _ => None
     };
 
     match file {
-      DataFile(_) => {
-        let fd = Vfs::extract_fd(&self.fds.pop());
-        let handle = FileHandle::new(file);
-        self.fd_table.insert(fd, handle);
-        Ok(fd)
+      DataFile(_) | Fifo(_) | CharDevice(_) => {
+        let handle = FileHandle::new(file, flags, device);
+        self.fds.write().unwrap().insert(handle)
       }
-      Directory(_) => Err(Error::new(ErrorKind::Other, "Directory")),
-      EmptyFile => Err(Error::new(ErrorKind::Other, "EmptyFile")),
+      Directory(_) => Err(Error::other("Directory")),
+      EmptyFile => Err(Error::other("EmptyFile")),
     }
   }
 
-  pub fn get_stats(&mut self, fd: FileDescriptor) -> (Timespec, Timespec, Timespec) {
-    let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-    let inode = handle.file.get_inode_rc();
-    inode.borrow().stat()
+  // The O_CREAT half of `open`. Another thread may have created the file
+  // since `open` looked, in which case that one is opened instead.
+  fn create(&self, path: &'r str, flags: FileFlags, mode: u32) -> Result<File<'r>> {
+    self.check_writable()?;
+    let _ns = self.lock_namespace();
+    let (mut dir, name) = self.resolve_parent(path)?;
+    if let Some(existing) = dir.get(name) {
+      existing.check_access(&self.cred(), flags.access())?;
+      return Ok(existing);
+    }
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    let file = Vfs::new_data_file(self.creation_mode(mode));
+    self.add_new_file(&mut dir, name, &file);
+    Ok(file)
+  }
+
+  /// Creates a directory at `path` with permission bits `mode`, less the
+  /// umask.
+  pub fn mkdir(&self, path: &'r str, mode: u32) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    let (mut dir, name) = self.resolve_parent(path)?;
+    if dir.get(name).is_some() {
+      return Err(Error::from_raw_os_error(EEXIST));
+    }
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    let new_dir = File::new_dir(Some(dir.clone()), self.creation_mode(mode));
+    self.add_new_file(&mut dir, name, &new_dir);
+    Ok(())
+  }
+
+  /// Creates a FIFO at `path` with permission bits `mode`, less the umask.
+  /// Its contents only last while it is open: bytes written to it wait in a
+  /// buffer until read.
+  pub fn mkfifo(&self, path: &'r str, mode: u32) -> Result<()> {
+    self.mknod(path, libc::S_IFIFO | mode, 0)
+  }
+
+  /// Creates a file at `path` of the type given by the `S_IFMT` bits of
+  /// `mode`: a regular file, a FIFO, or a character device numbered `rdev`.
+  /// Its permission bits are the rest of `mode`, less the umask. Only root
+  /// may make device nodes.
+  pub fn mknod(&self, path: &'r str, mode: u32, rdev: u64) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    let (mut dir, name) = self.resolve_parent(path)?;
+    if dir.get(name).is_some() {
+      return Err(Error::from_raw_os_error(EEXIST));
+    }
+
+    let perm = self.creation_mode(mode & !libc::S_IFMT);
+    let file = match mode & libc::S_IFMT {
+      0 | libc::S_IFREG => Vfs::new_data_file(perm),
+      libc::S_IFIFO => File::new_fifo(perm),
+      libc::S_IFCHR if self.cred().is_root() => File::new_char_device(perm, rdev),
+      libc::S_IFCHR | libc::S_IFDIR => return Err(Error::from_raw_os_error(EPERM)),
+      _ => return Err(Error::from_raw_os_error(EINVAL))
+    };
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    self.add_new_file(&mut dir, name, &file);
+    Ok(())
+  }
+
+  /// Makes `device` the driver for device nodes numbered `rdev`. Fails with
+  /// EBUSY if that number already has one.
+  pub fn register_device(&self, rdev: u64, device: Box<dyn Device>) -> Result<()> {
+    let mut devices = self.devices.lock().unwrap();
+    if devices.contains_key(&rdev) {
+      return Err(Error::from_raw_os_error(EBUSY));
+    }
+
+    devices.insert(rdev, Arc::new(Mutex::new(device)));
+    Ok(())
+  }
+
+  /// Registers the built-in devices and creates `/dev/null`, `/dev/zero`,
+  /// `/dev/full` and `/dev/urandom` for them, making `/dev` if needed.
+  /// `urandom` is seeded with `seed`, so its bytes are the same every run.
+  pub fn populate_dev(&self, seed: u64) -> Result<()> {
+    match self.mkdir("/dev", 0o755) {
+      Err(ref e) if e.raw_os_error() == Some(EEXIST) => {}
+      result => result?
+    }
+
+    let devices: Vec<(&'r str, u64, Box<dyn Device>)> = vec![
+      ("/dev/null", DEV_NULL, Box::new(Null)),
+      ("/dev/zero", DEV_ZERO, Box::new(Zero)),
+      ("/dev/full", DEV_FULL, Box::new(Full)),
+      ("/dev/urandom", DEV_URANDOM, Box::new(Urandom::new(seed)))
+    ];
+    for (path, rdev, device) in devices {
+      self.register_device(rdev, device)?;
+      // Everyone may use these, whatever the umask.
+      self.mknod(path, libc::S_IFCHR | 0o666, rdev)?;
+      self.chmod(path, 0o666)?;
+    }
+
+    Ok(())
+  }
+
+  /// Creates an anonymous pipe, returning its read end and its write end.
+  pub fn pipe(&self) -> Result<(FileDescriptor, FileDescriptor)> {
+    self.pipe2(FileFlags::empty())
+  }
+
+  /// Like `pipe`, but with `O_NONBLOCK` in `flags` both ends are
+  /// non-blocking. No other flags are allowed.
+  pub fn pipe2(&self, flags: FileFlags) -> Result<(FileDescriptor, FileDescriptor)> {
+    if !(flags - FileFlags::O_NONBLOCK).is_empty() {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    let mut fds = self.fds.write().unwrap();
+    if fds.free_count() < 2 {
+      return Err(Error::from_raw_os_error(EMFILE));
+    }
+
+    // The pipe has no name, but it has an inode like any FIFO.
+    let fifo = File::new_fifo(0o600);
+    self.inodes.lock().unwrap().insert(&fifo);
+    let (uid, gid) = { let cred = self.cred(); (cred.uid, cred.gid) };
+    fifo.with_inode_mut(|inode| inode.set_owner(uid, gid));
+
+    let read_fd = fds.insert(FileHandle::new(fifo.clone(), flags | FileFlags::O_RDONLY, None))?;
+    let write_fd = fds.insert(FileHandle::new(fifo, flags | FileFlags::O_WRONLY, None))?;
+    Ok((read_fd, write_fd))
   }
 
-  pub fn rename(&mut self, old_path: &'r str, new_path: &'r str) -> Result<()> {
-    let lookup = self.cwd.get(old_path);
-    match lookup {
-      Some(f) => {
-        self.unlink(old_path);
-        self.cwd.insert(new_path, f);
-        Ok(())
+  /// Removes the empty directory at `path`.
+  pub fn rmdir(&self, path: &'r str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    let (mut dir, name) = self.resolve_parent(path)?;
+    let target = match dir.get(name) {
+      Some(f) => f,
+      None => return Err(Error::from_raw_os_error(ENOENT))
+    };
+
+    if !target.is_dir() {
+      return Err(Error::from_raw_os_error(ENOTDIR));
     }
-      None => Err(Error::new(ErrorKind::NotFound, "fd not found")),
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    if !target.is_empty() {
+      return Err(Error::from_raw_os_error(ENOTEMPTY));
     }
+
+    dir.remove(name);
+    target.unlink(); // Its "."
+    dir.unlink(); // Its ".."
+    Ok(())
+  }
+
+  /// Sets the permission bits of the file or directory at `path`. Only its
+  /// owner or root may do so.
+  pub fn chmod(&self, path: &'r str, mode: u32) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.resolve(path)?.with_inode_mut(|inode| inode.chmod(&cred, mode))
+  }
+
+  pub fn fchmod(&self, fd: FileDescriptor, mode: u32) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.get_handle(fd)?.file.with_inode_mut(|inode| inode.chmod(&cred, mode))
+  }
+
+  /// Sets the access and modification times of the file or directory at
+  /// `path`, each either to a given time, to now, or not at all.
+  pub fn utimens(&self, path: &'r str, atime: UtimeSpec, mtime: UtimeSpec) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.resolve(path)?.with_inode_mut(|inode| inode.utimens(&cred, atime, mtime))
+  }
+
+  pub fn futimens(&self, fd: FileDescriptor, atime: UtimeSpec, mtime: UtimeSpec) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.get_handle(fd)?.file.with_inode_mut(|inode| inode.utimens(&cred, atime, mtime))
   }
 
-  pub fn chdir(&mut self, new_path: &'r str) -> Result<()> {
+  /// Changes the owner and/or group of the file or directory at `path`;
+  /// `None` leaves that ID as it is.
+  pub fn chown(&self, path: &'r str, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.resolve(path)?.with_inode_mut(|inode| inode.chown(&cred, uid, gid))
+  }
+
+  /// Like `chown`. Without symbolic links there is nothing to not follow.
+  pub fn lchown(&self, path: &'r str, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    self.chown(path, uid, gid)
+  }
+
+  pub fn fchown(&self, fd: FileDescriptor, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.get_handle(fd)?.file.with_inode_mut(|inode| inode.chown(&cred, uid, gid))
+  }
+
+  /// Returns the metadata of the file or directory at `path`.
+  pub fn stat(&self, path: &'r str) -> Result<Stat> {
+    let _tx = self.enter();
+    Ok(self.resolve(path)?.stat())
+  }
+
+  /// Like `stat`. There are no symbolic links to not follow (yet), so the two
+  /// always agree.
+  pub fn lstat(&self, path: &'r str) -> Result<Stat> {
+    self.stat(path)
+  }
+
+  pub fn fstat(&self, fd: FileDescriptor) -> Result<Stat> {
+    let _tx = self.enter();
+    Ok(self.get_handle(fd)?.file.stat())
+  }
+
+  /// Returns the metadata of the file or directory numbered `ino`, as long
+  /// as it still exists somewhere: linked into the tree or held open.
+  pub fn stat_ino(&self, ino: u64) -> Result<Stat> {
+    let _tx = self.enter();
+    let mut file = self.inodes.lock().unwrap().get(ino);
+    if file.is_none() && self.read_only {
+      // A snapshot only has files once they're looked for by name.
+      self.fill_all();
+      file = self.inodes.lock().unwrap().get(ino);
+    }
+    match file {
+      Some(file) => Ok(file.stat()),
+      None => Err(Error::from_raw_os_error(ESTALE))
+    }
+  }
+
+  /// Reports the capacity and usage of the filesystem `path` is on.
+  pub fn statfs(&self, path: &'r str) -> Result<StatFs> {
+    let _tx = self.enter();
+    self.resolve(path)?;
+    Ok(self.statfs_now())
+  }
+
+  pub fn fstatfs(&self, fd: FileDescriptor) -> Result<StatFs> {
+    let _tx = self.enter();
+    self.get_handle(fd)?;
+    Ok(self.statfs_now())
+  }
+
+  /// Sets how many blocks and inodes `statfs` says the filesystem holds.
+  /// They're only reported, not enforced, so usage can go past them.
+  pub fn set_capacity(&self, blocks: u64, inodes: u64) {
+    *self.capacity.lock().unwrap() = (blocks, inodes);
+  }
+
+  fn statfs_now(&self) -> StatFs {
+    let usage = self.inodes.lock().unwrap().usage().clone();
+    let (blocks, files) = *self.capacity.lock().unwrap();
+    let bfree = blocks.saturating_sub(usage.blocks());
+    let flags = match self.atime_policy() {
+      AtimePolicy::Strict => 0,
+      AtimePolicy::Relatime => libc::ST_RELATIME,
+      AtimePolicy::Noatime => libc::ST_NOATIME
+    } | if self.read_only { libc::ST_RDONLY } else { 0 };
+
+    StatFs {
+      bsize: inode::PAGE_SIZE as u64,
+      blocks,
+      bfree,
+      bavail: bfree, // Nothing is reserved for root
+      files,
+      ffree: files.saturating_sub(usage.inodes()),
+      namemax: libc::NAME_MAX as u64,
+      flags
+    }
+  }
+
+  /// Makes `dst` a copy of the data file at `src`, creating `dst` if needed.
+  /// The copy is cheap: the two files share pages until either one is
+  /// written to, at which point only the written pages are duplicated.
+  pub fn clone_file(&self, src: &'r str, dst: &'r str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let src_inode = self.lookup_inode(src)?;
+    src_inode.read().unwrap().check_access(&self.cred(), Access::READ)?;
+
+    let dst_inode = {
+      let _ns = self.lock_namespace();
+      let (mut dir, name) = self.resolve_parent(dst)?;
+      match dir.get(name) {
+        Some(DataFile(arc)) => {
+          arc.read().unwrap().check_access(&self.cred(), Access::WRITE)?;
+          arc
+        }
+        Some(_) => return Err(Error::other("Directory")),
+        None => {
+          dir.check_access(&self.cred(), Access::WRITE)?;
+          let file = Vfs::new_data_file(self.creation_mode(DEFAULT_CREATE_MODE));
+          self.add_new_file(&mut dir, name, &file);
+          file.get_inode_arc().clone()
+        }
+      }
+    };
+
+    if !Arc::ptr_eq(&src_inode, &dst_inode) {
+      let (src, mut dst) = Vfs::lock_pair(&src_inode, &dst_inode);
+      dst.clone_pages_from(&src);
+    }
+
+    Ok(())
+  }
+
+  /// Copies up to `len` bytes from `fd_in` to `fd_out`, sharing whole pages
+  /// between the two files where the offsets allow it. `None` offsets use
+  /// (and advance) the descriptor's own offset, while explicit offsets leave
+  /// it alone. Returns the number of bytes copied, which is short at EOF,
+  /// or EFBIG if they would end past the largest file size.
+  pub fn copy_file_range(&self, fd_in: FileDescriptor, off_in: Option<usize>,
+                         fd_out: FileDescriptor, off_out: Option<usize>,
+                         len: usize) -> Result<usize> {
+    let _tx = self.enter();
+    let handle_in = self.get_handle(fd_in)?;
+    let handle_out = self.get_handle(fd_out)?;
+    // As on Linux, appending descriptors can't be copied to.
+    if !handle_in.access().contains(Access::READ) || !handle_out.access().contains(Access::WRITE)
+        || handle_out.flags().contains(FileFlags::O_APPEND) {
+      return Err(Error::from_raw_os_error(EBADF));
+    }
+
+    let src_inode = Vfs::data_inode(&handle_in)?;
+    let dst_inode = Vfs::data_inode(&handle_out)?;
+    let src_offset = off_in.unwrap_or_else(|| handle_in.tell());
+    let dst_offset = off_out.unwrap_or_else(|| handle_out.tell());
+
+    let copied = if Arc::ptr_eq(&src_inode, &dst_inode) {
+      // Same file on both ends. Like Linux, overlapping ranges are EINVAL,
+      // once the source range is cut short at EOF.
+      let mut inode = src_inode.write().unwrap();
+      let len = cmp::min(len, inode.size().saturating_sub(src_offset));
+      if len > 0 && dst_offset < src_offset.saturating_add(len) && src_offset < dst_offset.saturating_add(len) {
+        return Err(Error::from_raw_os_error(EINVAL));
+      }
+      inode::check_size(dst_offset, len)?;
+
+      let mut buf = [0u8; inode::PAGE_SIZE];
+      let mut done = 0;
+      while done < len {
+        let chunk = cmp::min(len - done, buf.len());
+        inode.read(src_offset + done, &mut buf[..chunk]);
+        done += inode.write(dst_offset + done, &buf[..chunk])?;
+      }
+      done
+    } else {
+      let (src, mut dst) = Vfs::lock_pair(&src_inode, &dst_inode);
+      inode::check_size(dst_offset, cmp::min(len, src.size().saturating_sub(src_offset)))?;
+      dst.copy_range(dst_offset, &src, src_offset, len)?
+    };
+    if len > 0 {
+      handle_in.file.accessed(self.atime_policy());
+    }
+
+    if off_in.is_none() {
+      handle_in.seek((src_offset + copied) as isize, Whence::SeekSet)?;
+    }
+    if off_out.is_none() {
+      handle_out.seek((dst_offset + copied) as isize, Whence::SeekSet)?;
+    }
+
+    Ok(copied)
+  }
+
+  // Locks two different inodes, `src` for reading and `dst` for writing,
+  // always in the same order, so that two threads locking the same pair the
+  // other way round can't deadlock.
+  fn lock_pair<'a>(src: &'a ArcInode, dst: &'a ArcInode)
+      -> (RwLockReadGuard<'a, Shared<Inode>>, RwLockWriteGuard<'a, Shared<Inode>>) {
+    if Arc::as_ptr(src) < Arc::as_ptr(dst) {
+      let src = src.read().unwrap();
+      (src, dst.write().unwrap())
+    } else {
+      let dst = dst.write().unwrap();
+      (src.read().unwrap(), dst)
+    }
+  }
+
+  /// Sets the extended attribute `name` on the file at `path`. Names must
+  /// live in the `user.`, `trusted.` or `security.` namespace. Changing a
+  /// `user.` attribute takes write permission on the file, which must be a
+  /// regular file or directory, and changing the others takes root.
+  pub fn setxattr(&self, path: &'r str, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.resolve(path)?.with_inode_mut(|inode| inode.set_xattr(&cred, name, value, flags))
+  }
+
+  /// Copies the value of `name` into `dst`, returning its length. An empty
+  /// `dst` only queries the length; a too-small one fails with ERANGE.
+  pub fn getxattr(&self, path: &'r str, name: &str, dst: &mut [u8]) -> Result<usize> {
+    let _tx = self.enter();
+    self.resolve(path)?.with_inode(|inode| inode.xattrs().get(name, dst))
+  }
+
+  /// Copies the NUL-terminated names of every attribute into `dst`, with the
+  /// same sizing rules as `getxattr`.
+  pub fn listxattr(&self, path: &'r str, dst: &mut [u8]) -> Result<usize> {
+    let _tx = self.enter();
+    self.resolve(path)?.with_inode(|inode| inode.xattrs().list(dst))
+  }
+
+  pub fn removexattr(&self, path: &'r str, name: &str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.resolve(path)?.with_inode_mut(|inode| inode.remove_xattr(&cred, name))
+  }
+
+  pub fn fsetxattr(&self, fd: FileDescriptor, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.get_handle(fd)?.file.with_inode_mut(|inode| inode.set_xattr(&cred, name, value, flags))
+  }
+
+  pub fn fgetxattr(&self, fd: FileDescriptor, name: &str, dst: &mut [u8]) -> Result<usize> {
+    let _tx = self.enter();
+    self.get_handle(fd)?.file.with_inode(|inode| inode.xattrs().get(name, dst))
+  }
+
+  pub fn flistxattr(&self, fd: FileDescriptor, dst: &mut [u8]) -> Result<usize> {
+    let _tx = self.enter();
+    self.get_handle(fd)?.file.with_inode(|inode| inode.xattrs().list(dst))
+  }
+
+  pub fn fremovexattr(&self, fd: FileDescriptor, name: &str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let cred = self.cred();
+    self.get_handle(fd)?.file.with_inode_mut(|inode| inode.remove_xattr(&cred, name))
+  }
+
+  /// Takes a whole-file advisory lock on the file open at `fd`: shared with
+  /// `LOCK_SH` or exclusive with `LOCK_EX`, replacing any it has, or drops
+  /// it with `LOCK_UN`. Waits while another open file holds a conflicting
+  /// lock, or fails with EAGAIN given `LOCK_NB`. The lock belongs to the
+  /// open file, so closing it drops the lock.
+  pub fn flock(&self, fd: FileDescriptor, op: FlockOp) -> Result<()> {
+    self.flock_parked(fd, op, None)
+  }
+
+  // Like `flock`, but with `park` the task it wakes is parked rather than
+  // the thread blocked, and the call fails with a bare WouldBlock.
+  pub(crate) fn flock_parked(&self, fd: FileDescriptor, op: FlockOp, park: Option<&Waker>) -> Result<()> {
+    let modes = op - FlockOp::LOCK_NB;
+    if modes.bits().count_ones() != 1 {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    let handle = self.get_handle(fd)?;
+    self.locks.flock(handle.ino(), handle.id(), op, park.map_or(Wait::Thread, Wait::Task))
+  }
+
+  /// Sets, clears or queries a POSIX byte-range lock on the file open at
+  /// `fd`, on behalf of process `lock.pid`. Read locks need `fd` open for
+  /// reading and write locks for writing, or fail with EBADF.
+  ///
+  /// `SetLk` fails with EAGAIN if another process holds a conflicting lock,
+  /// while `SetLkW` waits for it to go, failing with EDEADLK instead if the
+  /// holder is itself waiting, directly or not, for a lock of `lock.pid`'s.
+  /// `GetLk` overwrites `lock` with a lock that's in the way, or sets its
+  /// kind to `Unlock` if nothing is.
+  ///
+  /// A process's locks on a file are all dropped when any handle it locked
+  /// that file through is closed, as POSIX has it.
+  pub fn fcntl_lock(&self, fd: FileDescriptor, cmd: LockCmd, lock: &mut FileLock) -> Result<()> {
+    self.fcntl_lock_parked(fd, cmd, lock, None)
+  }
+
+  // Like `fcntl_lock`, parking `SetLkW`'s task as `flock_parked` does.
+  pub(crate) fn fcntl_lock_parked(&self, fd: FileDescriptor, cmd: LockCmd, lock: &mut FileLock,
+                                  park: Option<&Waker>) -> Result<()> {
+    let handle = self.get_handle(fd)?;
+    let range = Vfs::lock_range(&handle, lock)?;
+    if cmd == LockCmd::GetLk {
+      if lock.kind == LockType::Unlock {
+        return Err(Error::from_raw_os_error(EINVAL));
+      }
+
+      match self.locks.get(handle.ino(), lock.pid, lock.kind, range) {
+        Some((pid, (start, end), write)) => *lock = FileLock {
+          kind: if write { LockType::Write } else { LockType::Read },
+          whence: Whence::SeekSet,
+          start: start as isize,
+          len: if end == usize::MAX { 0 } else { (end - start) as isize },
+          pid
+        },
+        None => lock.kind = LockType::Unlock
+      }
+      return Ok(());
+    }
+
+    let needs = match lock.kind {
+      LockType::Read => Access::READ,
+      LockType::Write => Access::WRITE,
+      LockType::Unlock => Access::empty()
+    };
+    if !handle.access().contains(needs) {
+      return Err(Error::from_raw_os_error(EBADF));
+    }
+
+    let wait = match cmd {
+      LockCmd::SetLkW => park.map_or(Wait::Thread, Wait::Task),
+      _ => Wait::Never
+    };
+    self.locks.set(handle.ino(), handle.id(), lock.pid, lock.kind, range, wait)
+  }
+
+  // The absolute range a lock covers.
+  fn lock_range(handle: &FileHandle<'r>, lock: &FileLock) -> Result<Range> {
+    let base = match lock.whence {
+      Whence::SeekSet => 0,
+      Whence::SeekCur => handle.tell(),
+      Whence::SeekEnd => handle.file.stat().size as usize
+    };
+
+    // A negative length counts back from the start.
+    let start = (base as isize).checked_add(lock.start);
+    let start = if lock.len < 0 { start.and_then(|start| start.checked_add(lock.len)) } else { start };
+    let start = match start {
+      Some(start) if start >= 0 => start as usize,
+      _ => return Err(Error::from_raw_os_error(EINVAL))
+    };
+
+    let len = lock.len.unsigned_abs();
+    let end = if len == 0 { usize::MAX } else { start.saturating_add(len) };
+    Ok((start, end))
+  }
+
+  fn lookup_inode(&self, path: &'r str) -> Result<ArcInode> {
+    match self.resolve(path)? {
+      DataFile(arc) => Ok(arc),
+      Fifo(_) | CharDevice(_) => Err(Error::from_raw_os_error(EINVAL)),
+      _ => Err(Error::other("Directory")),
+    }
+  }
+
+  // Ranges can only be copied between data files.
+  fn data_inode(handle: &FileHandle<'r>) -> Result<ArcInode> {
+    match handle.file {
+      DataFile(ref arc) => Ok(arc.clone()),
+      _ => Err(Error::from_raw_os_error(EINVAL))
+    }
+  }
+
+  /// Moves the file or directory at `old_path` to `new_path`, replacing
+  /// whatever is there as long as the two are compatible: a file can only
+  /// replace a file, and a directory only an empty directory.
+  pub fn rename(&self, old_path: &'r str, new_path: &'r str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    // Both parents are found before either name is looked up, as Linux does.
+    let (mut old_dir, old_name, old_chain) = self.resolve_parent_chain(old_path)?;
+    let (mut new_dir, new_name, new_chain) = self.resolve_parent_chain(new_path)?;
+    let file = match old_dir.get(old_name) {
+      Some(f) => f,
+      None => return Err(Error::from_raw_os_error(ENOENT))
+    };
+
+    old_dir.check_access(&self.cred(), Access::WRITE)?;
+    new_dir.check_access(&self.cred(), Access::WRITE)?;
+
+    // A directory can't be moved somewhere beneath itself.
+    if file.is_dir() && new_chain.iter().any(|d| d.is_same(&file)) {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    let replaced = new_dir.get(new_name);
+    if let Some(ref existing) = replaced {
+      if existing.is_same(&file) {
+        return Ok(());
+      } else if old_chain.iter().any(|d| d.is_same(existing)) {
+        // Nothing can replace a directory it's inside of.
+        return Err(Error::from_raw_os_error(ENOTEMPTY));
+      } else if file.is_dir() && !existing.is_dir() {
+        return Err(Error::from_raw_os_error(ENOTDIR));
+      } else if !file.is_dir() && existing.is_dir() {
+        return Err(Error::from_raw_os_error(EISDIR));
+      } else if existing.is_dir() && !existing.is_empty() {
+        return Err(Error::from_raw_os_error(ENOTEMPTY));
+      }
+    }
+
+    // Whatever `new_path` named stays visible until the file replaces it.
+    let cookie = self.inodes.lock().unwrap().notifier().next_cookie();
+    old_dir.remove_moved(old_name, cookie);
+    new_dir.insert_moved(new_name, file.clone(), cookie);
+    if let Some(existing) = replaced {
+      if existing.is_dir() {
+        existing.unlink(); // Its "."
+        new_dir.unlink(); // Its ".."
+      }
+    }
+
+    // A moved directory's ".." now links to its new parent.
+    if file.is_dir() && !old_dir.is_same(&new_dir) {
+      old_dir.unlink();
+      new_dir.link();
+    }
+
+    Ok(())
+  }
+
+  pub fn chdir(&self, _new_path: &'r str) -> Result<()> {
     unimplemented!();
   }
 
+  /// Sets when reads update access times, for the whole filesystem. The
+  /// default is `AtimePolicy::Relatime`, as on Linux.
+  pub fn set_atime_policy(&self, policy: AtimePolicy) {
+    *self.atime_policy.write().unwrap() = policy;
+  }
+
+  pub fn atime_policy(&self) -> AtimePolicy {
+    *self.atime_policy.read().unwrap()
+  }
+
+  // Zero-length reads don't count as accesses. Nor do reads of a snapshot,
+  // which can't be changed.
+  fn accessed(&self, handle: &FileHandle<'r>, requested: usize) {
+    if requested > 0 && !self.read_only {
+      handle.file.accessed(self.atime_policy());
+    }
+  }
+
   pub fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> Result<usize> {
-    let handle = match self.fd_table.get(&fd) {
-        Some(h) => h,
-        None => return Err(Error::new(ErrorKind::NotFound, "fd not found")),
-    };
-    Ok(handle.read(dst))
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
+    let read = handle.read(dst)?;
+    self.accessed(&handle, dst.len());
+    Ok(read)
   }
 
-  pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> usize {
-    let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
+  /// Writes `src` at the fd's offset. On a FIFO, fails with EPIPE once
+  /// nobody has it open for reading.
+  pub fn write(&self, fd: FileDescriptor, src: &[u8]) -> Result<usize> {
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
     handle.write(src)
   }
 
-  pub fn seek(&mut self, fd: FileDescriptor, o: isize, whence: Whence) -> usize {
-    let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-    handle.seek(o, whence)
+  /// Reads into each buffer in `dsts` in turn, starting at the fd's current
+  /// offset, and advances the offset by the total number of bytes read.
+  pub fn readv(&self, fd: FileDescriptor, dsts: &mut [IoSliceMut]) -> Result<usize> {
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
+    let read = handle.read_vectored(dsts)?;
+    self.accessed(&handle, dsts.iter().map(|d| d.len()).sum());
+    Ok(read)
+  }
+
+  /// Writes every buffer in `srcs` back-to-back at the fd's current offset.
+  /// The whole gather list lands in the inode in one step, so no other handle
+  /// can observe or interleave with a partially applied `writev`.
+  pub fn writev(&self, fd: FileDescriptor, srcs: &[IoSlice]) -> Result<usize> {
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
+    handle.write_vectored(srcs)
+  }
+
+  /// Like `readv`, but reads from `offset` and leaves the fd's offset alone.
+  pub fn preadv(&self, fd: FileDescriptor, dsts: &mut [IoSliceMut], offset: usize) -> Result<usize> {
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
+    let read = handle.read_vectored_at(dsts, offset)?;
+    self.accessed(&handle, dsts.iter().map(|d| d.len()).sum());
+    Ok(read)
+  }
+
+  /// Like `writev`, but writes at `offset` and leaves the fd's offset alone.
+  pub fn pwritev(&self, fd: FileDescriptor, srcs: &[IoSlice], offset: usize) -> Result<usize> {
+    let handle = self.get_handle(fd)?;
+    let _tx = self.enter_io(&handle);
+    handle.write_vectored_at(srcs, offset)
+  }
+
+  // The table is only held long enough to take a reference to the handle.
+  fn get_handle(&self, fd: FileDescriptor) -> Result<Arc<FileHandle<'r>>> {
+    self.fds.read().unwrap().get(fd)
+  }
+
+  /// Flushes the file open at `fd` to storage. Everything lives in memory,
+  /// so there is never anything to flush, but `fd` must be open.
+  pub fn fsync(&self, fd: FileDescriptor) -> Result<()> {
+    self.get_handle(fd).map(|_| ())
+  }
+
+  /// Cuts the file open at `fd` down, or extends it with a hole, to `len`
+  /// bytes. The fd must be open for writing, and `len` can't be past the
+  /// largest file size.
+  pub fn ftruncate(&self, fd: FileDescriptor, len: usize) -> Result<()> {
+    let _tx = self.enter();
+    let handle = self.get_handle(fd)?;
+    let inode = Vfs::data_inode(&handle)?;
+    if !handle.access().contains(Access::WRITE) {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    inode.write().unwrap().truncate(len)?;
+    Ok(())
+  }
+
+  /// Maps `len` bytes of the file open at `fd`, from `offset`, which must
+  /// be a multiple of the page size. `flags` must hold exactly one of
+  /// `MAP_SHARED` and `MAP_PRIVATE`. Like any mapping, it needs `fd` open
+  /// for reading, and a shared writable one needs it open for writing too.
+  /// The mapping may run past EOF, though those pages fault until the file
+  /// grows into them.
+  pub fn mmap(&self, fd: FileDescriptor, offset: usize, len: usize, prot: MapProt, flags: MapFlags)
+      -> Result<Mapping<'_, 'r>> {
+    let _tx = self.enter();
+    let handle = self.get_handle(fd)?;
+    let inode = match handle.file {
+      DataFile(ref arc) => arc.clone(),
+      _ => return Err(Error::from_raw_os_error(ENODEV))
+    };
+    if len == 0 || !offset.is_multiple_of(inode::PAGE_SIZE) || offset.checked_add(len).is_none()
+        || flags.bits().count_ones() != 1 {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    let access = handle.access();
+    let writes_back = flags.contains(MapFlags::MAP_SHARED) && prot.contains(MapProt::PROT_WRITE);
+    if !access.contains(Access::READ) || (writes_back && !access.contains(Access::WRITE)) {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    Ok(Mapping::new(self, inode, offset, len, prot, flags))
+  }
+
+  /// Flushes a mapping to its file. Mappings work on the file's own pages,
+  /// so there's never anything left to write.
+  pub fn msync(&self, _map: &Mapping) -> Result<()> {
+    Ok(())
+  }
+
+  /// Unmaps a mapping. A private one's copied pages go with it.
+  pub fn munmap(&self, map: Mapping) -> Result<()> {
+    drop(map);
+    Ok(())
+  }
+
+  /// Lists the directory at `path`, sorted by name. Like `std::fs::read_dir`,
+  /// the listing leaves out "." and "..". Needs read permission on it.
+  pub fn read_dir(&self, path: &'r str) -> Result<Vec<DirEntry<'r>>> {
+    let _tx = self.enter();
+    let dir = self.resolve(path)?;
+    if !dir.is_dir() {
+      return Err(Error::from_raw_os_error(ENOTDIR));
+    }
+
+    dir.check_access(&self.cred(), Access::READ)?;
+    let mut entries: Vec<_> = dir.list().into_iter().map(|(name, file)| {
+      let (ino, file_type) = file.with_inode(|inode| (inode.ino(), inode.file_type()));
+      DirEntry { name, ino, file_type }
+    }).collect();
+    entries.sort_by_key(|entry| entry.name);
+    Ok(entries)
+  }
+
+  /// Moves the fd's offset, returning the new one. FIFOs have no offset to
+  /// move and fail with ESPIPE.
+  pub fn seek(&self, fd: FileDescriptor, o: isize, whence: Whence) -> Result<usize> {
+    let _tx = self.enter();
+    self.get_handle(fd)?.seek(o, whence)
+  }
+
+  /// Closes `fd`, dropping its `flock` lock and the byte-range locks of
+  /// every process that locked the file through it.
+  pub fn close(&self, fd: FileDescriptor) {
+    // Dropped once the table is unlocked, as closing a FIFO end locks it.
+    let handle = self.fds.write().unwrap().remove(fd);
+    if let Some(handle) = handle {
+      self.locks.release(handle.ino(), handle.id());
+      if handle.access().contains(Access::WRITE) {
+        handle.file.with_inode(|inode| inode.notify(WatchMask::IN_CLOSE_WRITE));
+      }
+    }
+  }
+
+  /// Starts a new inotify instance, which has its own watches and queue of
+  /// events. Its queue holds up to `MAX_QUEUED_EVENTS`; past that, events
+  /// are dropped and an `IN_Q_OVERFLOW` one queued in their place.
+  pub fn inotify_init(&self) -> NotifyInstance {
+    self.inodes.lock().unwrap().notifier().add_instance()
+  }
+
+  /// Ends an inotify instance, dropping its watches and unread events.
+  pub fn inotify_close(&self, instance: NotifyInstance) -> Result<()> {
+    self.inodes.lock().unwrap().notifier().remove_instance(instance)
+  }
+
+  /// Watches the file or directory at `path` for the events in `mask`,
+  /// returning a descriptor that identifies the watch in `instance`'s
+  /// events. Watching a directory also reports what happens to its entries.
+  /// Watching it again replaces the mask and returns the same descriptor.
+  /// Needs read permission on it, like inotify.
+  pub fn watch(&self, instance: NotifyInstance, path: &'r str, mask: WatchMask) -> Result<WatchDescriptor> {
+    let _tx = self.enter();
+    // Keeps the directory's entries still while they're recorded.
+    let _ns = self.lock_namespace();
+    let file = self.resolve(path)?;
+    file.check_access(&self.cred(), Access::READ)?;
+    let entries = if file.is_dir() { file.list() } else { Vec::new() };
+    let entries = entries.iter().map(|(name, child)| (*name, child.with_inode(|i| i.ino()))).collect();
+    let ino = file.with_inode(|inode| inode.ino());
+    self.inodes.lock().unwrap().notifier().add_watch(instance, ino, mask, entries)
+  }
+
+  /// Removes a watch, queueing an `IN_IGNORED` event for it.
+  pub fn unwatch(&self, instance: NotifyInstance, wd: WatchDescriptor) -> Result<()> {
+    self.inodes.lock().unwrap().notifier().remove_watch(instance, wd)
+  }
+
+  /// Takes the events queued for `instance` so far, oldest first.
+  pub fn read_events(&self, instance: NotifyInstance) -> Result<Vec<WatchEvent>> {
+    self.inodes.lock().unwrap().notifier().read_events(instance)
   }
 
-  pub fn close(&mut self, fd: FileDescriptor) {
-    self.fd_table.remove(&fd);
-    self.fds.push(fd);
+  /// Makes `new_path` another name for the file at `old_path`. As on Linux,
+  /// directories can't be linked.
+  pub fn link(&self, old_path: &'r str, new_path: &'r str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    let file = self.resolve(old_path)?;
+    if file.is_dir() {
+      return Err(Error::from_raw_os_error(EPERM));
+    }
+
+    let (mut dir, name) = self.resolve_parent(new_path)?;
+    if dir.get(name).is_some() {
+      return Err(Error::from_raw_os_error(EEXIST));
+    }
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    dir.insert(name, file);
+    Ok(())
   }
 
-  pub fn unlink(&mut self, path: &'r str) {
-    self.cwd.remove(path);
+  /// Removes the name `path`. The file itself lives on until the last open
+  /// handle to it is closed.
+  pub fn unlink(&self, path: &'r str) -> Result<()> {
+    let _tx = self.enter_write()?;
+    let _ns = self.lock_namespace();
+    let (mut dir, name) = self.resolve_parent(path)?;
+    match dir.get(name) {
+      Some(ref f) if f.is_dir() => return Err(Error::from_raw_os_error(EISDIR)),
+      Some(_) => {}
+      None => return Err(Error::from_raw_os_error(ENOENT))
+    }
+
+    dir.check_access(&self.cred(), Access::WRITE)?;
+    dir.remove(name);
+    Ok(())
   }
 }
 
@@ -138,22 +1151,27 @@ mod proc_tests {
   // extern crate test;
   extern crate rand;
 
-  use super::{Vfs, FileFlags};
-  use file::Whence::SeekSet;
-  use inode::Inode;
+  use super::{Vfs, AtimePolicy, Credentials, Device, FileFlags, FileType, UtimeSpec, XattrFlags};
+  use super::{FileLock, FlockOp, LockCmd, LockType, WatchEvent, WatchMask};
+  use super::{Null, DEV_NULL, PIPE_BUF};
+  use crate::file::Whence::SeekSet;
+  use crate::inode::{Inode, FILE_SIZE};
   use self::rand::random;
+  use std::cell::Cell;
+  use std::io::{ErrorKind, IoSlice, IoSliceMut};
+  use std::thread::sleep;
+  use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-  static mut test_inode_drop: bool = false;
+  // Each test runs on its own thread, so keeping the flag thread-local stops
+  // inodes dropped by other tests from tripping (or clearing) it.
+  thread_local!(static TEST_INODE_DROP: Cell<bool> = const { Cell::new(false) });
 
   impl Drop for Inode {
     fn drop(&mut self) {
-      unsafe {
-        if test_inode_drop {
-          test_inode_drop = false;
-          panic!("Dropping.");
-        } else {
-          println!("Dropping, but no flag.");
-        }
+      if TEST_INODE_DROP.with(|flag| flag.replace(false)) {
+        panic!("Dropping.");
+      } else {
+        println!("Dropping, but no flag.");
       }
     }
   }
@@ -173,16 +1191,15 @@ mod proc_tests {
   #[test]
   fn test_rename_simple() {
     const SIZE: usize = 4096 * 8 + 3434;
-    let mut p = Proc::new();
+    let p = Vfs::new();
     let data = rand_array(SIZE);
-    let mut buf = [0u8; SIZE];
     let filename = "first_file";
     let newname = "new_file";
 
     let fd = p.open(filename, FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
 
-    p.write(fd, &data);
-    p.seek(fd, 0, SeekSet);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
     assert!(p.rename(filename, newname).is_ok());
 
     p.close(fd);
@@ -192,7 +1209,7 @@ mod proc_tests {
 
   #[test]
   fn test_rename_old_nonexistent() {
-    let mut p = Proc::new();
+    let p = Vfs::new();
     let filename = "first_file";
     let newname = "new_file";
 
@@ -203,43 +1220,724 @@ mod proc_tests {
   #[test]
   fn test_inode_stat_time() {
     const SIZE: usize = 4096 * 8 + 3434;
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
 
-    let (ctime, atime, mtime) = p.get_stats(fd);
+    let stat = p.fstat(fd).unwrap();
+    let (ctime, atime, mtime) = (stat.ctime, stat.atime, stat.mtime);
     // All three timestamps should be equal after creation.
     assert_eq!((ctime, atime), (atime, mtime));
 
-    p.write(fd, &data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
 
-    let (ctime, atime, mtime) = p.get_stats(fd);
+    let stat = p.fstat(fd).unwrap();
+    let (ctime, atime, mtime) = (stat.ctime, stat.atime, stat.mtime);
     assert_ne!((ctime, atime), (atime, mtime));
   }
 
+  #[test]
+  fn test_stat_files_and_dirs() {
+    let p = Vfs::new();
+    let data = rand_array(4096 + 1);
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    p.write(fd, &data).unwrap();
+
+    let stat = p.stat("file").unwrap();
+    assert_eq!(stat, p.fstat(fd).unwrap());
+    assert_eq!(stat, p.lstat("file").unwrap());
+    assert_eq!(stat.file_type, FileType::RegularFile);
+    assert_eq!(stat.mode & libc::S_IFMT, libc::S_IFREG);
+    assert_eq!((stat.nlink, stat.size, stat.blocks, stat.blksize), (1, 4097, 16, 4096));
+
+    let root = p.stat("/").unwrap();
+    assert_eq!(root.file_type, FileType::Directory);
+    assert_eq!(root.mode & libc::S_IFMT, libc::S_IFDIR);
+    assert_eq!(root.nlink, 2);
+    assert_eq!(root.ino, p.stat(".").unwrap().ino);
+    assert_ne!(root.ino, stat.ino);
+
+    // An open but unlinked file has no links left.
+    p.unlink("file").unwrap();
+    assert_eq!(p.fstat(fd).unwrap().nlink, 0);
+    assert!(p.stat("file").is_err());
+    assert!(p.fstat(fd + 1).is_err());
+  }
+
+  #[test]
+  fn test_atime_policies() {
+    let p = Vfs::new();
+    let mut buf = [0u8; 4];
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let created = p.fstat(fd).unwrap().atime;
+
+    // Writes leave atime alone.
+    sleep(Duration::from_millis(2));
+    p.write(fd, b"data").unwrap();
+    let stat = p.fstat(fd).unwrap();
+    assert_eq!(stat.atime, created);
+    assert!(stat.mtime > created);
+
+    // Relatime: the first read after a write updates atime, the next doesn't.
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    let first = p.fstat(fd).unwrap().atime;
+    assert!(first > stat.mtime);
+    sleep(Duration::from_millis(2));
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    assert_eq!(p.fstat(fd).unwrap().atime, first);
+
+    p.set_atime_policy(AtimePolicy::Strict);
+    p.preadv(fd, &mut [IoSliceMut::new(&mut buf)], 0).unwrap();
+    let strict = p.fstat(fd).unwrap().atime;
+    assert!(strict > first);
+
+    p.set_atime_policy(AtimePolicy::Noatime);
+    p.pwritev(fd, &[IoSlice::new(b"more")], 4).unwrap();
+    sleep(Duration::from_millis(2));
+    p.readv(fd, &mut [IoSliceMut::new(&mut buf)]).unwrap();
+    assert_eq!(p.fstat(fd).unwrap().atime, strict);
+  }
+
+  fn assert_errno<T: std::fmt::Debug>(result: std::io::Result<T>, errno: i32) {
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(errno));
+  }
+
+  fn user_vfs<'r>() -> Vfs<'r> {
+    Vfs::with_credentials(Credentials::new(1000, 1000, vec![]))
+  }
+
+  #[test]
+  fn test_open_permissions() {
+    let p = user_vfs();
+    let fd = p.open_with_mode("ro", FileFlags::O_RDWR | FileFlags::O_CREAT, 0o444).expect("open failed!");
+    assert_eq!(p.fstat(fd).unwrap().mode, libc::S_IFREG | 0o444);
+
+    assert_errno(p.open("ro", FileFlags::O_RDWR), libc::EACCES);
+    assert_errno(p.open("ro", FileFlags::O_WRONLY), libc::EACCES);
+    assert!(p.open("ro", FileFlags::O_RDONLY).is_ok());
+
+    p.chmod("ro", 0o200).unwrap();
+    assert_errno(p.open("ro", FileFlags::O_RDONLY), libc::EACCES);
+    assert!(p.open("ro", FileFlags::O_WRONLY).is_ok());
+
+    p.fchmod(fd, 0o10644).unwrap();
+    assert_eq!(p.stat("ro").unwrap().mode, libc::S_IFREG | 0o644);
+  }
+
+  #[test]
+  fn test_directory_permissions() {
+    let p = user_vfs();
+    p.mkdir("dir", 0o755).unwrap();
+    p.mkdir("/dir/sub/", 0o700).unwrap();
+    p.open("dir/sub/file", FileFlags::O_CREAT).expect("open failed!");
+    assert_eq!(p.stat("dir/./sub/../sub").unwrap().mode, libc::S_IFDIR | 0o700);
+    assert_eq!((p.stat("/").unwrap().nlink, p.stat("dir").unwrap().nlink), (3, 3));
+
+    // Without search permission nothing beneath the directory is reachable.
+    p.chmod("dir", 0o644).unwrap();
+    assert_errno(p.stat("dir/sub"), libc::EACCES);
+    assert_errno(p.open("dir/sub/file", FileFlags::O_RDONLY), libc::EACCES);
+    assert!(p.stat("dir").is_ok());
+
+    // Without write permission its entries can't change.
+    p.chmod("dir", 0o555).unwrap();
+    assert!(p.stat("dir/sub/file").is_ok());
+    assert_errno(p.open("dir/new", FileFlags::O_CREAT), libc::EACCES);
+    assert_errno(p.mkdir("dir/new", 0o755), libc::EACCES);
+    assert_errno(p.mkdir("dir/sub", 0o755), libc::EEXIST);
+    assert_errno(p.rename("dir/sub", "moved"), libc::EACCES);
+    assert_errno(p.rmdir("dir/sub"), libc::EACCES);
+    assert!(p.unlink("dir/sub/file").is_ok());
+
+    p.chmod("dir", 0o755).unwrap();
+    assert_errno(p.rename("dir", "dir/sub/inside"), libc::EINVAL);
+    p.rename("dir/sub", "moved").unwrap();
+    assert_eq!((p.stat("/").unwrap().nlink, p.stat("dir").unwrap().nlink), (4, 2));
+    assert_errno(p.rmdir("dir/sub"), libc::ENOENT);
+    assert_errno(p.unlink("moved"), libc::EISDIR);
+    p.rmdir("moved").unwrap();
+    assert_errno(p.rmdir("/"), libc::EINVAL);
+  }
+
+  #[test]
+  fn test_ownership_and_root_bypass() {
+    let p = Vfs::new();
+    p.set_umask(0);
+    p.mkdir("shared", 0o777).unwrap();
+    p.open_with_mode("shared/secret", FileFlags::O_CREAT, 0o600).expect("open failed!");
+    let stat = p.stat("shared/secret").unwrap();
+    assert_eq!((stat.uid, stat.gid), (0, 0));
+
+    // Another user only gets the "other" bits.
+    p.set_credentials(Credentials::new(1000, 100, vec![]));
+    assert_errno(p.open("shared/secret", FileFlags::O_RDONLY), libc::EACCES);
+    assert_errno(p.chmod("shared/secret", 0o666), libc::EPERM);
+    assert_errno(p.chown("shared/secret", Some(1000), None), libc::EPERM);
+    let fd = p.open_with_mode("shared/mine", FileFlags::O_CREAT, 0o640).expect("open failed!");
+    let stat = p.fstat(fd).unwrap();
+    assert_eq!((stat.uid, stat.gid), (1000, 100));
+
+    // Owners may move files between their own groups, but not give them away.
+    assert_errno(p.fchown(fd, None, Some(200)), libc::EPERM);
+    p.set_credentials(Credentials::new(1000, 100, vec![200]));
+    p.fchown(fd, None, Some(200)).unwrap();
+    assert_errno(p.lchown("shared/mine", Some(0), None), libc::EPERM);
+
+    // A group member gets the group bits.
+    p.set_credentials(Credentials::new(1001, 300, vec![200]));
+    assert!(p.open("shared/mine", FileFlags::O_RDONLY).is_ok());
+    assert_errno(p.open("shared/mine", FileFlags::O_WRONLY), libc::EACCES);
+
+    // Root reads and writes anything, but only executes what someone can.
+    p.set_credentials(Credentials::root());
+    p.chmod("shared", 0).unwrap();
+    assert!(p.open("shared/secret", FileFlags::O_RDWR).is_ok());
+    p.chown("shared/mine", Some(1001), Some(300)).unwrap();
+    assert_eq!(p.stat("shared/mine").unwrap().uid, 1001);
+  }
+
+  #[test]
+  fn test_setgid_directory_inheritance() {
+    let p = Vfs::new();
+    p.mkdir("project", 0o777).unwrap();
+    p.chown("project", None, Some(500)).unwrap();
+    p.chmod("project", 0o2777).unwrap();
+
+    p.set_credentials(Credentials::new(1000, 100, vec![]));
+    p.open("project/file", FileFlags::O_CREAT).expect("open failed!");
+    p.mkdir("project/sub", 0o755).unwrap();
+    assert_errno(p.mkdir("elsewhere", 0o755), libc::EACCES);
+
+    let file = p.stat("project/file").unwrap();
+    assert_eq!((file.gid, file.mode & libc::S_ISGID), (500, 0));
+    let sub = p.stat("project/sub").unwrap();
+    assert_eq!((sub.gid, sub.mode & 0o7777), (500, 0o2755));
+
+    // Outside the group, chmod can't keep the set-group-ID bit.
+    p.chmod("project/sub", 0o2700).unwrap();
+    assert_eq!(p.stat("project/sub").unwrap().mode & 0o7777, 0o700);
+  }
+
+  #[test]
+  fn test_utimens() {
+    let p = Vfs::new();
+    let fd = p.open("archived", FileFlags::O_CREAT).expect("open failed!");
+    let before = p.fstat(fd).unwrap();
+
+    // Restoring an archived mtime keeps every nanosecond.
+    let mtime = UNIX_EPOCH + Duration::new(1_234_567_890, 123_456_789);
+    p.utimens("archived", UtimeSpec::Omit, UtimeSpec::Set(mtime)).unwrap();
+    let stat = p.stat("archived").unwrap();
+    assert_eq!((stat.atime, stat.mtime), (before.atime, mtime));
+
+    sleep(Duration::from_millis(2));
+    p.futimens(fd, UtimeSpec::Now, UtimeSpec::Omit).unwrap();
+    let stat = p.fstat(fd).unwrap();
+    assert!(stat.atime > before.atime && stat.atime <= SystemTime::now());
+    assert_eq!(stat.mtime, mtime);
+
+    // Others may only touch the file to now, and only with write permission.
+    p.chmod("archived", 0o646).unwrap();
+    p.set_credentials(Credentials::new(1000, 1000, vec![]));
+    p.utimens("archived", UtimeSpec::Now, UtimeSpec::Now).unwrap();
+    assert_errno(p.utimens("archived", UtimeSpec::Set(mtime), UtimeSpec::Omit), libc::EPERM);
+    p.set_credentials(Credentials::new(1000, 0, vec![]));
+    assert_errno(p.futimens(fd, UtimeSpec::Now, UtimeSpec::Now), libc::EACCES);
+    assert!(p.futimens(fd, UtimeSpec::Omit, UtimeSpec::Omit).is_ok());
+  }
+
+  #[test]
+  fn test_umask() {
+    let p = Vfs::new();
+    assert_eq!(p.umask(), 0o022);
+    p.open("default", FileFlags::O_CREAT).expect("open failed!");
+    assert_eq!(p.stat("default").unwrap().mode, libc::S_IFREG | 0o644);
+
+    assert_eq!(p.set_umask(0o10027), 0o022);
+    assert_eq!(p.umask(), 0o027);
+    p.open_with_mode("file", FileFlags::O_CREAT, 0o666).expect("open failed!");
+    p.mkdir("dir", 0o1777).unwrap();
+    p.mkfifo("fifo", 0o666).unwrap();
+    p.mknod("node", libc::S_IFREG | 0o4777, 0).unwrap();
+    let perm = |p: &Vfs, path| p.stat(path).unwrap().mode & 0o7777;
+    assert_eq!((perm(&p, "file"), perm(&p, "dir")), (0o640, 0o1750));
+    assert_eq!((perm(&p, "fifo"), perm(&p, "node")), (0o640, 0o4750));
+
+    // Existing files keep their mode, and chmod ignores the umask.
+    p.open_with_mode("file", FileFlags::O_CREAT, 0o600).expect("open failed!");
+    p.chmod("dir", 0o777).unwrap();
+    assert_eq!((perm(&p, "file"), perm(&p, "dir")), (0o640, 0o777));
+  }
+
+  #[test]
+  fn test_threads() {
+    fn shareable<T: Send + Sync>() {}
+    shareable::<Vfs>();
+
+    const THREADS: usize = 8;
+    let names: Vec<String> = (0..THREADS).map(|i| format!("dir/{}", i)).collect();
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    let shared = p.open("shared", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+
+    std::thread::scope(|s| {
+      for (i, name) in names.iter().enumerate() {
+        let p = &p;
+        s.spawn(move || {
+          let fd = p.open(name, FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+          let data = vec![i as u8; 1000 + i];
+          assert_eq!(p.write(fd, &data).unwrap(), data.len());
+          p.close(fd);
+
+          // Racing creators of one name all end up with the same file.
+          p.close(p.open("dir/same", FileFlags::O_CREAT).expect("open failed!"));
+          p.pwritev(shared, &[IoSlice::new(&[i as u8 + 1])], i).unwrap();
+        });
+      }
+    });
+
+    for (i, name) in names.iter().enumerate() {
+      assert_eq!(p.stat(name).unwrap().size, 1000 + i as u64);
+    }
+    assert_eq!(p.stat("dir/same").unwrap().nlink, 1);
+    let mut buf = [0u8; THREADS];
+    p.preadv(shared, &mut [IoSliceMut::new(&mut buf)], 0).unwrap();
+    assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
+  }
+
+  #[test]
+  fn test_watch() {
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    p.open("dir/old", FileFlags::O_CREAT).expect("open failed!");
+    let inotify = p.inotify_init();
+    let dir = p.watch(inotify, "dir", WatchMask::IN_ALL_EVENTS).unwrap();
+    let event = |mask, cookie, name: &str| WatchEvent { wd: dir, mask, cookie, name: Some(name.to_string()) };
+
+    let fd = p.open("dir/new", FileFlags::O_WRONLY | FileFlags::O_CREAT).expect("open failed!");
+    let file = p.watch(inotify, "dir/new", WatchMask::IN_MODIFY).unwrap();
+    p.write(fd, b"a").unwrap();
+    p.close(fd);
+    p.chmod("dir/old", 0o600).unwrap();
+    p.mkdir("dir/sub", 0o755).unwrap();
+    p.rename("dir/old", "dir/renamed").unwrap();
+    p.unlink("dir/new").unwrap();
+
+    let events = p.read_events(inotify).unwrap();
+    let cookie = events[6].cookie;
+    assert_ne!(cookie, 0);
+    assert_eq!(events, [
+      event(WatchMask::IN_CREATE, 0, "new"),
+      WatchEvent { wd: file, mask: WatchMask::IN_MODIFY, cookie: 0, name: None },
+      event(WatchMask::IN_MODIFY, 0, "new"),
+      event(WatchMask::IN_CLOSE_WRITE, 0, "new"),
+      event(WatchMask::IN_ATTRIB, 0, "old"),
+      event(WatchMask::IN_CREATE | WatchMask::IN_ISDIR, 0, "sub"),
+      event(WatchMask::IN_MOVED_FROM, cookie, "old"),
+      event(WatchMask::IN_MOVED_TO, cookie, "renamed"),
+      event(WatchMask::IN_DELETE, 0, "new"),
+    ]);
+
+    // Renamed entries are reported under their new name. Identical events
+    // in a row are merged.
+    p.chmod("dir/renamed", 0o644).unwrap();
+    p.chmod("dir/renamed", 0o640).unwrap();
+    p.unwatch(inotify, dir).unwrap();
+    p.chmod("dir/renamed", 0o600).unwrap();
+    assert_eq!(p.read_events(inotify).unwrap(), [
+      event(WatchMask::IN_ATTRIB, 0, "renamed"),
+      WatchEvent { wd: dir, mask: WatchMask::IN_IGNORED, cookie: 0, name: None },
+    ]);
+    assert_eq!(p.unwatch(inotify, dir).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+    // Each instance has its own watches and queue.
+    let other = p.inotify_init();
+    let mine = p.watch(inotify, "dir", WatchMask::IN_CREATE).unwrap();
+    let theirs = p.watch(other, "dir", WatchMask::IN_DELETE).unwrap();
+    p.open("dir/another", FileFlags::O_CREAT).unwrap();
+    p.unlink("dir/another").unwrap();
+    let only = |wd, mask| vec![WatchEvent { wd, mask, cookie: 0, name: Some("another".to_string()) }];
+    assert_eq!(p.read_events(inotify).unwrap(), only(mine, WatchMask::IN_CREATE));
+    assert_eq!(p.read_events(other).unwrap(), only(theirs, WatchMask::IN_DELETE));
+    p.inotify_close(other).unwrap();
+    assert_eq!(p.read_events(other).unwrap_err().raw_os_error(), Some(libc::EBADF));
+  }
+
+  #[test]
+  fn test_flock() {
+    let p = Vfs::new();
+    let a = p.open("lock", FileFlags::O_RDONLY | FileFlags::O_CREAT).expect("open failed!");
+    let b = p.open("lock", FileFlags::O_RDONLY).expect("open failed!");
+
+    // Shared locks coexist; an exclusive one needs the file to itself.
+    p.flock(a, FlockOp::LOCK_SH).unwrap();
+    p.flock(b, FlockOp::LOCK_SH | FlockOp::LOCK_NB).unwrap();
+    let err = p.flock(b, FlockOp::LOCK_EX | FlockOp::LOCK_NB).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+    assert_eq!(p.flock(a, FlockOp::LOCK_SH | FlockOp::LOCK_EX).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+    // Closing the holder lets a waiter in.
+    std::thread::scope(|s| {
+      let waiter = s.spawn(|| p.flock(b, FlockOp::LOCK_EX));
+      p.close(a);
+      waiter.join().unwrap().unwrap();
+    });
+    let c = p.open("lock", FileFlags::O_RDONLY).expect("open failed!");
+    assert!(p.flock(c, FlockOp::LOCK_SH | FlockOp::LOCK_NB).is_err());
+    p.flock(b, FlockOp::LOCK_UN).unwrap();
+    p.flock(c, FlockOp::LOCK_SH | FlockOp::LOCK_NB).unwrap();
+  }
+
+  #[test]
+  fn test_fcntl_locks() {
+    let p = Vfs::new();
+    let fd = p.open("data", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let other = p.open("data", FileFlags::O_RDWR).expect("open failed!");
+    let lock = |kind, start, len, pid| FileLock { kind, whence: SeekSet, start, len, pid };
+
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Write, 0, 0, 1)).unwrap();
+    // Unlocking the middle splits the lock in two.
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Unlock, 10, 10, 1)).unwrap();
+    p.fcntl_lock(other, LockCmd::SetLk, &mut lock(LockType::Read, 10, 10, 2)).unwrap();
+    let err = p.fcntl_lock(other, LockCmd::SetLk, &mut lock(LockType::Read, 15, 10, 2)).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+    let mut query = lock(LockType::Read, 5, 100, 2);
+    p.fcntl_lock(other, LockCmd::GetLk, &mut query).unwrap();
+    assert_eq!(query, lock(LockType::Write, 0, 10, 1));
+    let mut query = lock(LockType::Read, 10, 10, 3);
+    p.fcntl_lock(other, LockCmd::GetLk, &mut query).unwrap();
+    assert_eq!(query.kind, LockType::Unlock);
+
+    // Locks need the matching access.
+    let ro = p.open("data", FileFlags::O_RDONLY).expect("open failed!");
+    let err = p.fcntl_lock(ro, LockCmd::SetLk, &mut lock(LockType::Write, 100, 1, 3)).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+
+    // A negative length covers the bytes before the start, which can't go
+    // below zero.
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Unlock, 0, 0, 1)).unwrap();
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Write, 50, -10, 1)).unwrap();
+    let mut query = lock(LockType::Read, 0, 0, 2);
+    p.fcntl_lock(other, LockCmd::GetLk, &mut query).unwrap();
+    assert_eq!(query, lock(LockType::Write, 40, 10, 1));
+    let err = p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Write, 5, -10, 1)).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+    // Closing a handle drops all its process's locks on the file.
+    p.close(fd);
+    p.fcntl_lock(other, LockCmd::SetLk, &mut lock(LockType::Write, 0, 10, 2)).unwrap();
+  }
+
+  #[test]
+  fn test_fcntl_deadlock() {
+    let p = Vfs::new();
+    let fd = p.open("data", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let lock = |kind, start, pid| FileLock { kind, whence: SeekSet, start, len: 1, pid };
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Write, 0, 1)).unwrap();
+    p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Write, 1, 2)).unwrap();
+
+    // Each process waits for the other's byte. Whichever waits second
+    // would close the cycle, so it's refused and gives up its own byte.
+    let results: Vec<_> = std::thread::scope(|s| {
+      let waiters: Vec<_> = [(1, 1), (0, 2)].iter().map(|&(start, pid)| {
+        let p = &p;
+        s.spawn(move || {
+          let result = p.fcntl_lock(fd, LockCmd::SetLkW, &mut lock(LockType::Write, start, pid));
+          if result.is_err() {
+            p.fcntl_lock(fd, LockCmd::SetLk, &mut lock(LockType::Unlock, 1 - start, pid)).unwrap();
+          }
+          result.map_err(|e| e.raw_os_error())
+        })
+      }).collect();
+      waiters.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(results.contains(&Err(Some(libc::EDEADLK))));
+  }
+
+  #[test]
+  fn test_ctime_vs_btime() {
+    let p = Vfs::new();
+    let tick = || sleep(Duration::from_millis(2));
+    p.mkdir("dir", 0o755).unwrap();
+    tick();
+    let fd = p.open("dir/file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let born = p.fstat(fd).unwrap();
+    assert_eq!((born.ctime, born.mtime), (born.btime, born.btime));
+
+    // Creating an entry modifies its directory.
+    let dir = p.stat("dir").unwrap();
+    assert!(dir.mtime > dir.btime && dir.ctime == dir.mtime);
+
+    tick();
+    p.chmod("dir/file", 0o600).unwrap();
+    let chmodded = p.fstat(fd).unwrap();
+    assert!(chmodded.ctime > born.ctime);
+    assert_eq!((chmodded.mtime, chmodded.btime), (born.mtime, born.btime));
+
+    tick();
+    p.fsetxattr(fd, "user.k", b"v", XattrFlags::empty()).unwrap();
+    let tagged = p.fstat(fd).unwrap();
+    assert!(tagged.ctime > chmodded.ctime);
+
+    tick();
+    p.rename("dir/file", "file").unwrap();
+    let renamed = p.fstat(fd).unwrap();
+    assert!(renamed.ctime > tagged.ctime);
+    assert_eq!(renamed.mtime, born.mtime);
+
+    tick();
+    p.write(fd, b"data").unwrap();
+    let written = p.fstat(fd).unwrap();
+    assert!(written.mtime > renamed.ctime && written.ctime == written.mtime);
+    assert_eq!(written.btime, born.btime);
+
+    tick();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut [0u8; 4]).unwrap();
+    assert_eq!(p.fstat(fd).unwrap().ctime, written.ctime);
+  }
+
+  #[test]
+  fn test_inode_numbers() {
+    let p = Vfs::new();
+    let names = ["a", "b", "c", "d"];
+    for name in names.iter() {
+      let fd = p.open(name, FileFlags::O_CREAT).expect("open failed!");
+      p.close(fd);
+    }
+    p.mkdir("dir", 0o755).unwrap();
+
+    let mut inos: Vec<u64> = names.iter().map(|n| p.stat(n).unwrap().ino).collect();
+    inos.push(p.stat("dir").unwrap().ino);
+    inos.push(p.stat("/").unwrap().ino);
+    let mut unique = inos.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), inos.len());
+
+    // Renames keep the number; lookups by number find the same file.
+    let ino = p.stat("a").unwrap().ino;
+    p.rename("a", "dir/a").unwrap();
+    assert_eq!(p.stat("dir/a").unwrap().ino, ino);
+    assert_eq!(p.stat_ino(ino).unwrap(), p.stat("dir/a").unwrap());
+
+    // An unlinked file keeps its number while it's open, then it's gone.
+    let fd = p.open("b", FileFlags::O_RDONLY).expect("open failed!");
+    let ino = p.fstat(fd).unwrap().ino;
+    p.unlink("b").unwrap();
+    assert_eq!(p.stat_ino(ino).unwrap().nlink, 0);
+    p.close(fd);
+    assert_errno(p.stat_ino(ino), libc::ESTALE);
+
+    // Numbers of dead inodes aren't handed out again, even after the table
+    // has been swept.
+    for _ in 0..200 {
+      let fd = p.open("tmp", FileFlags::O_CREAT).expect("open failed!");
+      p.close(fd);
+      assert!(!inos.contains(&p.stat("tmp").unwrap().ino));
+      p.unlink("tmp").unwrap();
+    }
+  }
+
+  #[test]
+  fn test_fifo() {
+    let p = Vfs::new();
+    p.mkfifo("fifo", 0o640).unwrap();
+    assert_errno(p.mkfifo("fifo", 0o640), libc::EEXIST);
+    let stat = p.stat("fifo").unwrap();
+    assert_eq!((stat.file_type, stat.mode), (FileType::Fifo, libc::S_IFIFO | 0o640));
+
+    // Nobody is reading yet, so a non-blocking writer can't open it.
+    let nonblock = FileFlags::O_NONBLOCK;
+    assert_errno(p.open("fifo", FileFlags::O_WRONLY | nonblock), libc::ENXIO);
+    let reader = p.open("fifo", FileFlags::O_RDONLY | nonblock).expect("open failed!");
+    let writer = p.open("fifo", FileFlags::O_WRONLY).expect("open failed!");
+
+    let mut buf = [0u8; 8];
+    assert_errno(p.read(reader, &mut buf), libc::EAGAIN);
+    assert_eq!(p.write(writer, b"hello").unwrap(), 5);
+    assert_eq!(p.write(writer, b" there").unwrap(), 6);
+    assert_eq!(p.read(reader, &mut buf).unwrap(), 8);
+    assert_eq!(&buf, b"hello th");
+    assert_errno(p.seek(reader, 0, SeekSet), libc::ESPIPE);
+
+    // A blocking reader is told it would block rather than getting EAGAIN.
+    let blocking = p.open("fifo", FileFlags::O_RDONLY).expect("open failed!");
+    assert_eq!(p.read(blocking, &mut buf).unwrap(), 3);
+    let err = p.read(blocking, &mut buf).unwrap_err();
+    assert_eq!((err.kind(), err.raw_os_error()), (ErrorKind::WouldBlock, None));
+
+    // With the writer gone, readers see EOF; with the readers gone, writes fail.
+    p.close(writer);
+    assert_eq!(p.read(reader, &mut buf).unwrap(), 0);
+    let writer = p.open("fifo", FileFlags::O_WRONLY).expect("open failed!");
+    p.close(reader);
+    p.close(blocking);
+    assert_errno(p.write(writer, b"lost"), libc::EPIPE);
+  }
+
+  #[test]
+  fn test_pipe() {
+    let p = Vfs::new();
+    let (rd, wr) = p.pipe().unwrap();
+    assert_eq!(p.fstat(rd).unwrap(), p.fstat(wr).unwrap());
+    assert_eq!(p.fstat(rd).unwrap().file_type, FileType::Fifo);
+
+    // Stages hand data along in order, in whatever chunks they like.
+    let data = rand_array(PIPE_BUF * 3);
+    assert_eq!(p.write(wr, &data[..PIPE_BUF]).unwrap(), PIPE_BUF);
+    assert_eq!(p.writev(wr, &[IoSlice::new(&data[PIPE_BUF..])]).unwrap(), PIPE_BUF * 2);
+    let mut buf = vec![0u8; data.len() + 1];
+    assert_eq!(p.read(rd, &mut buf[..100]).unwrap(), 100);
+    assert_eq!(p.read(rd, &mut buf[100..]).unwrap(), data.len() - 100);
+    assert_eq_buf(&data, &buf[..data.len()]);
+
+    // EOF once every write end is gone.
+    p.close(wr);
+    assert_eq!(p.read(rd, &mut buf).unwrap(), 0);
+    p.close(rd);
+
+    let (rd, wr) = p.pipe2(FileFlags::O_NONBLOCK).unwrap();
+    assert_errno(p.read(rd, &mut buf), libc::EAGAIN);
+    p.close(rd);
+    assert_errno(p.write(wr, b"x"), libc::EPIPE);
+    assert_errno(p.pipe2(FileFlags::O_CREAT), libc::EINVAL);
+  }
+
+  #[test]
+  fn test_dev() {
+    let p = Vfs::new();
+    assert!(p.open("/dev/null", FileFlags::O_RDWR).is_err());
+    p.populate_dev(42).unwrap();
+    let mut buf = [7u8; 64];
+
+    let null = p.open("/dev/null", FileFlags::O_RDWR).expect("open failed!");
+    assert_eq!(p.write(null, b"discarded").unwrap(), 9);
+    assert_eq!(p.read(null, &mut buf).unwrap(), 0);
+    let stat = p.fstat(null).unwrap();
+    assert_eq!((stat.file_type, stat.mode, stat.rdev), (FileType::CharDevice, libc::S_IFCHR | 0o666, DEV_NULL));
+
+    let zero = p.open("/dev/zero", FileFlags::O_RDONLY).expect("open failed!");
+    assert_eq!(p.read(zero, &mut buf).unwrap(), 64);
+    assert_eq!(buf, [0u8; 64]);
+
+    let full = p.open("/dev/full", FileFlags::O_RDWR).expect("open failed!");
+    assert_errno(p.write(full, b"x"), libc::ENOSPC);
+
+    // urandom is reproducible from its seed.
+    let other = Vfs::new();
+    other.populate_dev(42).unwrap();
+    let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+    let fd = p.open("/dev/urandom", FileFlags::O_RDONLY).expect("open failed!");
+    p.read(fd, &mut a).unwrap();
+    let fd = other.open("/dev/urandom", FileFlags::O_RDONLY).expect("open failed!");
+    other.read(fd, &mut b).unwrap();
+    assert_eq!(a, b);
+    assert_ne!(a, [0u8; 32]);
+  }
+
+  #[test]
+  fn test_custom_device() {
+    // Echoes back the last byte written to it.
+    struct Echo(u8);
+    impl Device for Echo {
+      fn read(&mut self, _offset: usize, dst: &mut [u8]) -> std::io::Result<usize> {
+        dst.iter_mut().for_each(|b| *b = self.0);
+        Ok(dst.len())
+      }
+
+      fn write(&mut self, _offset: usize, src: &[u8]) -> std::io::Result<usize> {
+        self.0 = *src.last().unwrap_or(&self.0);
+        Ok(src.len())
+      }
+    }
+
+    let p = Vfs::new();
+    let rdev = libc::makedev(240, 0);
+    p.mknod("echo", libc::S_IFCHR | 0o600, rdev).unwrap();
+    assert_errno(p.open("echo", FileFlags::O_RDWR), libc::ENXIO);
+    p.register_device(rdev, Box::new(Echo(0))).unwrap();
+    assert_errno(p.register_device(rdev, Box::new(Null)), libc::EBUSY);
+
+    let fd = p.open("echo", FileFlags::O_RDWR).expect("open failed!");
+    p.write(fd, b"abc").unwrap();
+    let mut buf = [0u8; 2];
+    p.read(fd, &mut buf).unwrap();
+    assert_eq!(&buf, b"cc");
+
+    // Anyone may make FIFOs and regular files, but only root devices.
+    p.chmod("/", 0o777).unwrap();
+    p.set_credentials(Credentials::new(1000, 1000, vec![]));
+    assert_errno(p.mknod("mine", libc::S_IFCHR | 0o600, rdev), libc::EPERM);
+    assert_errno(p.mknod("mine", libc::S_IFDIR | 0o700, 0), libc::EPERM);
+    p.mknod("mine", libc::S_IFREG | 0o600, 0).unwrap();
+    assert_eq!(p.stat("mine").unwrap().file_type, FileType::RegularFile);
+  }
+
+  #[test]
+  fn test_statfs() {
+    let long_name = "x".repeat(256);
+    let p = Vfs::new();
+    p.set_capacity(100, 10);
+    let empty = p.statfs("/").unwrap();
+    assert_eq!((empty.bsize, empty.blocks, empty.files, empty.namemax), (4096, 100, 10, 255));
+    assert_eq!((empty.bfree, empty.bavail, empty.ffree), (100, 100, 9));
+    assert_eq!(empty.flags, libc::ST_RELATIME);
+
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    p.write(fd, &[1u8; 4096 * 3 + 1]).unwrap();
+    let used = p.fstatfs(fd).unwrap();
+    assert_eq!((used.bfree, used.ffree), (96, 8));
+
+    // Clones share their pages until they're written to.
+    p.clone_file("file", "copy").unwrap();
+    assert_eq!((p.statfs("copy").unwrap().bfree, p.statfs("/").unwrap().ffree), (96, 7));
+    let copy = p.open("copy", FileFlags::O_RDWR).expect("open failed!");
+    p.write(copy, b"!").unwrap();
+    assert_eq!(p.statfs("/").unwrap().bfree, 95);
+
+    // An unlinked file's space only comes back once it's closed, and then
+    // only the pages nothing else shares.
+    p.unlink("file").unwrap();
+    assert_eq!(p.statfs("/").unwrap().bfree, 95);
+    p.close(fd);
+    let freed = p.statfs("/").unwrap();
+    assert_eq!((freed.bfree, freed.ffree), (96, 8));
+
+    p.set_atime_policy(AtimePolicy::Noatime);
+    assert_eq!(p.statfs("/").unwrap().flags, libc::ST_NOATIME);
+    assert_errno(p.mkdir(&long_name, 0o755), libc::ENAMETOOLONG);
+    assert_errno(p.statfs("missing"), libc::ENOENT);
+  }
+
   #[test]
   fn simple_test() {
     const SIZE: usize = 4096 * 8 + 3434;
-    let mut p = Vfs::new();
+    let p = Vfs::new();
     let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
-    p.write(fd, &data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
 
     assert_eq_buf(&data, &buf);
 
     let fd2 = p.open(filename, FileFlags::O_RDWR).expect("open failed!");
     let mut buf2 = [0u8; SIZE];
-    p.read(fd2, &mut buf2);
+    p.read(fd2, &mut buf2).unwrap();
 
     assert_eq_buf(&data, &buf2);
 
@@ -248,30 +1946,214 @@ mod proc_tests {
 
     let fd3 = p.open(filename, FileFlags::O_RDWR).expect("open failed!");
     let mut buf3 = [0u8; SIZE];
-    p.read(fd3, &mut buf3);
+    p.read(fd3, &mut buf3).unwrap();
 
     assert_eq_buf(&data, &buf3);
     p.close(fd3);
 
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
 
     let fd4 = p.open(filename, FileFlags::O_RDWR);
     assert!(fd4.is_err());
   }
 
+  #[test]
+  fn test_writev_readv() {
+    let p = Vfs::new();
+    let header = [7u8; 16];
+    let payload = rand_array(4096 * 2 + 17);
+
+    let fd = p.open("framed", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let written = p.writev(fd, &[IoSlice::new(&header), IoSlice::new(&payload)]).unwrap();
+    assert_eq!(written, header.len() + payload.len());
+
+    // A second handle sees the whole frame, split however the reader likes.
+    let fd2 = p.open("framed", FileFlags::O_RDWR).expect("open failed!");
+    let mut head_buf = [0u8; 16];
+    let mut body_buf = vec![0u8; payload.len() + 100];
+    let read = p.readv(fd2, &mut [IoSliceMut::new(&mut head_buf), IoSliceMut::new(&mut body_buf)]).unwrap();
+
+    assert_eq!(read, written);
+    assert_eq_buf(&header, &head_buf);
+    assert_eq_buf(&payload, &body_buf[..payload.len()]);
+
+    // The offset advanced past the frame, so the next read hits EOF.
+    assert_eq!(p.read(fd2, &mut head_buf).unwrap(), 0);
+  }
+
+  #[test]
+  fn test_preadv_pwritev_keep_offset() {
+    let p = Vfs::new();
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    p.write(fd, b"0123456789").unwrap();
+
+    let written = p.pwritev(fd, &[IoSlice::new(b"ab"), IoSlice::new(b"cd")], 3).unwrap();
+    assert_eq!(written, 4);
+
+    let mut first = [0u8; 3];
+    let mut second = [0u8; 3];
+    let read = p.preadv(fd, &mut [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)], 2).unwrap();
+    assert_eq!(read, 6);
+    assert_eq!(&first, b"2ab");
+    assert_eq!(&second, b"cd7");
+
+    // Neither call moved the offset, which still sits after the first write.
+    let mut rest = [0u8; 4];
+    assert_eq!(p.read(fd, &mut rest).unwrap(), 0);
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut rest).unwrap();
+    assert_eq!(&rest, b"012a");
+
+    assert!(p.preadv(fd + 1, &mut [IoSliceMut::new(&mut rest)], 0).is_err());
+  }
+
+  #[test]
+  fn test_clone_file_is_independent() {
+    const SIZE: usize = 4096 * 4 + 77;
+    let p = Vfs::new();
+    let data = rand_array(SIZE);
+
+    let fd = p.open("fixture", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    p.write(fd, &data).unwrap();
+    p.close(fd);
+
+    p.clone_file("fixture", "copy").unwrap();
+    let fd = p.open("copy", FileFlags::O_RDWR).expect("open failed!");
+    let mut buf = vec![0u8; SIZE];
+    assert_eq!(p.read(fd, &mut buf).unwrap(), SIZE);
+    assert_eq_buf(&data, &buf);
+
+    // Writing to the clone leaves the original untouched.
+    p.seek(fd, 4096, SeekSet).unwrap();
+    p.write(fd, b"clobbered").unwrap();
+    p.close(fd);
+
+    let fd = p.open("fixture", FileFlags::O_RDWR).expect("open failed!");
+    p.read(fd, &mut buf).unwrap();
+    assert_eq_buf(&data, &buf);
+
+    assert!(p.clone_file("missing", "copy2").is_err());
+  }
+
+  #[test]
+  fn test_copy_file_range() {
+    let p = Vfs::new();
+    let data = rand_array(4096 * 2 + 5);
+    let src = p.open("src", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    let dst = p.open("dst", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+    p.write(src, &data).unwrap();
+
+    // An explicit source offset leaves the fd's own offset alone.
+    assert_eq!(p.copy_file_range(src, Some(0), dst, None, 4096).unwrap(), 4096);
+    // Implicit ones advance; the copy is short at EOF.
+    p.seek(src, 4096, SeekSet).unwrap();
+    assert_eq!(p.copy_file_range(src, None, dst, None, 1 << 20).unwrap(), 4096 + 5);
+    assert_eq!(p.copy_file_range(src, None, dst, None, 1 << 20).unwrap(), 0);
+
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(p.preadv(dst, &mut [IoSliceMut::new(&mut buf)], 0).unwrap(), data.len());
+    assert_eq_buf(&data, &buf);
+
+    // Copies within one file may not overlap; ones that don't are fine,
+    // however much is asked for.
+    assert_errno(p.copy_file_range(dst, Some(0), dst, Some(2), 6), libc::EINVAL);
+    let size = data.len();
+    assert_eq!(p.copy_file_range(dst, Some(size - 5), dst, Some(size), usize::MAX).unwrap(), 5);
+    let mut tail = [0u8; 5];
+    p.preadv(dst, &mut [IoSliceMut::new(&mut tail)], size).unwrap();
+    assert_eq!(&tail, &data[size - 5..]);
+
+    // Nor may a copy end past the largest file size, wherever it's to.
+    assert_errno(p.copy_file_range(src, Some(0), dst, Some(usize::MAX - 1), 4096), libc::EFBIG);
+    assert_errno(p.copy_file_range(src, Some(0), dst, Some(FILE_SIZE), 1), libc::EFBIG);
+    assert_errno(p.copy_file_range(dst, Some(0), dst, Some(usize::MAX - 1), 4096), libc::EFBIG);
+    assert_eq!(p.fstat(dst).unwrap().size, (size + 5) as u64);
+    assert_eq!(p.copy_file_range(src, Some(size - 1), dst, Some(FILE_SIZE - 1), usize::MAX).unwrap(), 1);
+
+    // The source must be readable, and the destination writable and not
+    // appending.
+    let read_only = p.open("src", FileFlags::O_RDONLY).unwrap();
+    let write_only = p.open("dst", FileFlags::O_WRONLY).unwrap();
+    let append = p.open("dst", FileFlags::O_WRONLY | FileFlags::O_APPEND).unwrap();
+    assert_errno(p.copy_file_range(write_only, Some(0), dst, Some(0), 1), libc::EBADF);
+    assert_errno(p.copy_file_range(src, Some(0), read_only, Some(0), 1), libc::EBADF);
+    assert_errno(p.copy_file_range(src, Some(0), append, Some(0), 1), libc::EBADF);
+    assert_eq!(p.copy_file_range(read_only, Some(0), write_only, Some(0), 1).unwrap(), 1);
+  }
+
+  #[test]
+  fn test_xattrs() {
+    let p = Vfs::new();
+    let fd = p.open("tagged", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
+
+    p.setxattr("tagged", "user.sha256", b"abc123", XattrFlags::XATTR_CREATE).unwrap();
+    p.fsetxattr(fd, "trusted.origin", b"ci", XattrFlags::empty()).unwrap();
+
+    // CREATE refuses to clobber, REPLACE refuses to create.
+    let err = p.setxattr("tagged", "user.sha256", b"x", XattrFlags::XATTR_CREATE).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    let err = p.fsetxattr(fd, "user.missing", b"x", XattrFlags::XATTR_REPLACE).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+
+    // Empty buffers query the size; short ones are ERANGE.
+    let mut buf = [0u8; 64];
+    assert_eq!(p.getxattr("tagged", "user.sha256", &mut []).unwrap(), 6);
+    let err = p.fgetxattr(fd, "user.sha256", &mut buf[..3]).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ERANGE));
+    assert_eq!(p.fgetxattr(fd, "user.sha256", &mut buf).unwrap(), 6);
+    assert_eq!(&buf[..6], b"abc123");
+
+    let len = p.listxattr("tagged", &mut buf).unwrap();
+    assert_eq!(&buf[..len], &b"trusted.origin\0user.sha256\0"[..]);
+
+    p.removexattr("tagged", "trusted.origin").unwrap();
+    let err = p.fremovexattr(fd, "trusted.origin").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENODATA));
+    assert_eq!(p.flistxattr(fd, &mut []).unwrap(), "user.sha256\0".len());
+
+    assert!(p.getxattr("missing", "user.sha256", &mut buf).is_err());
+    let both = XattrFlags::XATTR_CREATE | XattrFlags::XATTR_REPLACE;
+    assert_errno(p.setxattr("tagged", "user.sha256", b"x", both), libc::EINVAL);
+
+    // Changing user. attributes takes write permission, even on your own
+    // files, and nobody but root may change trusted. or security. ones.
+    p.mkdir("home", 0o755).unwrap();
+    p.chown("home", Some(1000), Some(1000)).unwrap();
+    p.set_credentials(Credentials::new(1000, 1000, vec![]));
+    assert_errno(p.setxattr("tagged", "user.sha256", b"x", XattrFlags::empty()), libc::EACCES);
+    assert_errno(p.removexattr("tagged", "user.sha256"), libc::EACCES);
+    p.open("home/mine", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    p.chmod("home/mine", 0o444).unwrap();
+    assert_errno(p.setxattr("home/mine", "user.tag", b"x", XattrFlags::empty()), libc::EACCES);
+    assert_errno(p.setxattr("home/mine", "trusted.tag", b"x", XattrFlags::empty()), libc::EPERM);
+    assert_errno(p.setxattr("home/mine", "security.tag", b"x", XattrFlags::empty()), libc::EPERM);
+    p.set_credentials(Credentials::root());
+    p.setxattr("home/mine", "trusted.tag", b"x", XattrFlags::empty()).unwrap();
+    p.set_credentials(Credentials::new(1000, 1000, vec![]));
+    assert_errno(p.removexattr("home/mine", "trusted.tag"), libc::EPERM);
+    p.chmod("home/mine", 0o644).unwrap();
+    p.setxattr("home/mine", "user.tag", b"x", XattrFlags::empty()).unwrap();
+    p.removexattr("home/mine", "user.tag").unwrap();
+
+    // Only regular files and directories take user. attributes.
+    p.mkfifo("home/fifo", 0o666).unwrap();
+    assert_errno(p.setxattr("home/fifo", "user.tag", b"x", XattrFlags::empty()), libc::EPERM);
+    p.setxattr("home", "user.tag", b"x", XattrFlags::empty()).unwrap();
+  }
+
   #[test]
   #[should_panic]
   fn test_proc_drop_inode_dealloc() {
     // Variable is used to make sure that the Drop implemented is only valid for
     // tests that set that test_inode_drop global variable to true.
-    unsafe { test_inode_drop = true; }
+    TEST_INODE_DROP.with(|flag| flag.set(true));
 
     const SIZE: usize = 4096 * 3 + 3498;
-    let mut p = Vfs::new();
-    let mut data = rand_array(SIZE);
+    let p = Vfs::new();
+    let data = rand_array(SIZE);
 
     let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
-    p.write(fd, &mut data);
+    p.write(fd, &data).unwrap();
   }
 
   /**
@@ -288,25 +2170,25 @@ mod proc_tests {
   #[should_panic]
   fn test_inode_dealloc() {
     // Make sure flag is set to detect drop.
-    unsafe { test_inode_drop = true; }
+    TEST_INODE_DROP.with(|flag| flag.set(true));
 
     const SIZE: usize = 4096 * 3 + 3498;
-    let mut p = Vfs::new();
-    let mut data = rand_array(SIZE);
+    let p = Vfs::new();
+    let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, FileFlags::O_RDWR | FileFlags::O_CREAT).expect("open failed!");
-    p.write(fd, &mut data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
 
     assert_eq_buf(&data, &buf);
 
     // close + unlink should remove both references to inode, dropping it,
     // causing a failure
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
 
     // If inode is not being dropped properly, ie, on the unlink call this will
     // cause a double failure: once for panic! call, and once when then the Inode
@@ -320,7 +2202,7 @@ mod proc_tests {
   //#[test]
   //fn test_max_singly_file_size() {
   //  const SIZE: usize = 4096 * 256;
-  //  let mut p = Vfs::new();
+  //  let p = Vfs::new();
   //  let mut data = rand_array(SIZE);
   //  let mut buf = [0u8; SIZE];
   //  let filename = "first_file";
@@ -342,7 +2224,7 @@ mod proc_tests {
   //#[test]
   //fn test_max_file_size() {
   //  const SIZE: usize = 2 * 4096 * 256;
-  //  let mut p = Vfs::new();
+  //  let p = Vfs::new();
   //  let mut data1 = rand_array(SIZE);
   //  let mut data2 = rand_array(SIZE);
   //  let mut buf = vec![0; SIZE];
@@ -366,7 +2248,7 @@ mod proc_tests {
   //#[should_panic]
   //fn test_morethan_max_file_size() {
   //  const SIZE: usize = 2 * 4096 * 256;
-  //  let mut p = Vfs::new();
+  //  let p = Vfs::new();
   //  let mut data = rand_array(SIZE);
   //  let filename = "first_file";
 
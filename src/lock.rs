@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Result, Error};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::task::Waker;
+use libc::{EAGAIN, EDEADLK};
+use crate::file::Whence;
+use crate::pipe::blocked;
+
+bitflags!{
+    pub struct FlockOp: u32 {
+        const LOCK_SH = 0b00000001;
+        const LOCK_EX = 0b00000010;
+        const LOCK_NB = 0b00000100;
+        const LOCK_UN = 0b00001000;
+    }
+}
+
+/// The kind of a byte-range lock, as in `struct flock`'s `l_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockType {
+  Read,  // F_RDLCK
+  Write, // F_WRLCK
+  Unlock // F_UNLCK
+}
+
+/// What `Vfs::fcntl_lock` does with a `FileLock`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockCmd {
+  SetLk,  // F_SETLK: fail with EAGAIN if the lock is taken
+  SetLkW, // F_SETLKW: wait for it instead
+  GetLk   // F_GETLK: report a lock that would be in the way
+}
+
+// What a lock request does when the lock is taken: fail with EAGAIN, wait
+// on the calling thread, or park a task to be woken once any lock is dropped.
+#[derive(Clone, Copy)]
+pub enum Wait<'a> {
+  Never,
+  Thread,
+  Task(&'a Waker)
+}
+
+// A byte range [start, end); an `end` of usize::MAX runs to the end of the
+// file, however far that moves.
+pub type Range = (usize, usize);
+
+// Byte-range locks belong to a process; `flock` locks belong to the open
+// file they were taken through.
+#[derive(Clone, Copy)]
+struct RangeLock {
+  pid: u32,
+  range: Range,
+  write: bool
+}
+
+impl RangeLock {
+  fn conflicts(&self, pid: u32, range: Range, write: bool) -> bool {
+    self.pid != pid && (self.write || write) && self.range.0 < range.1 && range.0 < self.range.1
+  }
+}
+
+#[derive(Default)]
+struct FileLocks {
+  flocks: Vec<(usize, bool)>, // Handle, and whether it's exclusive
+  ranges: Vec<RangeLock>,
+  users: Vec<(usize, u32)> // Which handles each process has locked through
+}
+
+impl FileLocks {
+  fn is_empty(&self) -> bool {
+    self.flocks.is_empty() && self.ranges.is_empty() && self.users.is_empty()
+  }
+
+  fn range_conflict(&self, pid: u32, range: Range, write: bool) -> Option<RangeLock> {
+    self.ranges.iter().find(|lock| lock.conflicts(pid, range, write)).cloned()
+  }
+
+  // Takes `range` out of every lock `pid` holds, splitting those that stick
+  // out on both sides.
+  fn unlock_range(&mut self, pid: u32, range: Range) {
+    let mut kept = Vec::with_capacity(self.ranges.len());
+    for lock in self.ranges.drain(..) {
+      if lock.pid != pid || lock.range.1 <= range.0 || range.1 <= lock.range.0 {
+        kept.push(lock);
+        continue;
+      }
+
+      if lock.range.0 < range.0 {
+        kept.push(RangeLock { range: (lock.range.0, range.0), ..lock });
+      }
+      if range.1 < lock.range.1 {
+        kept.push(RangeLock { range: (range.1, lock.range.1), ..lock });
+      }
+    }
+    self.ranges = kept;
+  }
+}
+
+#[derive(Default)]
+struct State {
+  files: HashMap<u64, FileLocks>, // By inode number
+  waiting: HashMap<u32, (u64, Range, bool)>, // What each waiting process wants
+  parked: Vec<Waker>
+}
+
+impl State {
+  // Whether `pid` waiting for `range` of `ino` would close a cycle of
+  // processes waiting on each other, which would then wait forever.
+  fn would_deadlock(&self, pid: u32, ino: u64, range: Range, write: bool) -> bool {
+    let mut seen = HashSet::new();
+    let mut wants = vec![(pid, ino, range, write)];
+    while let Some((waiter, ino, range, write)) = wants.pop() {
+      let locks = match self.files.get(&ino) {
+        Some(locks) => locks,
+        None => continue
+      };
+
+      for holder in locks.ranges.iter().filter(|l| l.conflicts(waiter, range, write)) {
+        if holder.pid == pid {
+          return true;
+        } else if !seen.insert(holder.pid) {
+          continue;
+        }
+
+        if let Some(&(ino, range, write)) = self.waiting.get(&holder.pid) {
+          wants.push((holder.pid, ino, range, write));
+        }
+      }
+    }
+
+    false
+  }
+}
+
+/// Advisory locks for a whole filesystem: `flock` locks on whole files and
+/// POSIX byte-range locks. They're kept here rather than on the inodes so
+/// that deadlocks spanning several files can be seen.
+#[derive(Default)]
+pub struct LockTable {
+  state: Mutex<State>,
+  changed: Condvar // Signalled, and parked tasks woken, whenever a lock is dropped
+}
+
+impl LockTable {
+  pub fn new() -> LockTable {
+    LockTable::default()
+  }
+
+  /// Takes a shared or exclusive lock on `ino` for `handle`, replacing the
+  /// one it has, or drops its lock with `LOCK_UN`. Waits for the lock as
+  /// `wait` says, though `LOCK_NB` means never waiting.
+  pub fn flock(&self, ino: u64, handle: usize, op: FlockOp, wait: Wait) -> Result<()> {
+    let exclusive = op.contains(FlockOp::LOCK_EX);
+    let wait = if op.contains(FlockOp::LOCK_NB) { Wait::Never } else { wait };
+    let mut state = self.state.lock().unwrap();
+    // As on Linux, converting a lock drops the old one first.
+    let locks = state.files.entry(ino).or_default();
+    let held = locks.flocks.len();
+    locks.flocks.retain(|&(h, _)| h != handle);
+    if locks.flocks.len() != held {
+      self.wake(&mut state);
+    }
+
+    if op.contains(FlockOp::LOCK_UN) {
+      LockTable::prune(&mut state, ino);
+      return Ok(());
+    }
+
+    loop {
+      let locks = state.files.entry(ino).or_default();
+      if !locks.flocks.iter().any(|&(h, ex)| h != handle && (ex || exclusive)) {
+        locks.flocks.push((handle, exclusive));
+        return Ok(());
+      }
+
+      match wait {
+        Wait::Never => {
+          LockTable::prune(&mut state, ino);
+          return Err(Error::from_raw_os_error(EAGAIN));
+        }
+        Wait::Thread => state = self.changed.wait(state).unwrap(),
+        Wait::Task(waker) => {
+          state.parked.push(waker.clone());
+          return Err(blocked(false));
+        }
+      }
+    }
+  }
+
+  /// Sets (or with `LockType::Unlock`, clears) `pid`'s lock on `range` of
+  /// `ino`, taken through `handle`. Waits for conflicting locks to go away
+  /// as `wait` says, unless waiting would deadlock. A parked task stays
+  /// registered as waiting until it gets the lock or calls `cancel`.
+  pub fn set(&self, ino: u64, handle: usize, pid: u32, kind: LockType, range: Range, wait: Wait)
+      -> Result<()> {
+    let write = kind == LockType::Write;
+    let mut state = self.state.lock().unwrap();
+    loop {
+      let locks = state.files.entry(ino).or_default();
+      if kind == LockType::Unlock || locks.range_conflict(pid, range, write).is_none() {
+        // Replacing a lock can shrink or downgrade it, so that may let
+        // waiters in as well as unlocking does.
+        locks.unlock_range(pid, range);
+        if kind != LockType::Unlock {
+          locks.ranges.push(RangeLock { pid, range, write });
+          if !locks.users.contains(&(handle, pid)) {
+            locks.users.push((handle, pid));
+          }
+        } else if !locks.ranges.iter().any(|lock| lock.pid == pid) {
+          // Closing a handle has nothing left to drop for the process.
+          locks.users.retain(|&(_, p)| p != pid);
+        }
+        state.waiting.remove(&pid);
+        self.wake(&mut state);
+        LockTable::prune(&mut state, ino);
+        return Ok(());
+      }
+
+      let error = match wait {
+        Wait::Never => Some(EAGAIN),
+        _ if state.would_deadlock(pid, ino, range, write) => Some(EDEADLK),
+        _ => None
+      };
+      if let Some(errno) = error {
+        state.waiting.remove(&pid);
+        LockTable::prune(&mut state, ino);
+        return Err(Error::from_raw_os_error(errno));
+      }
+
+      state.waiting.insert(pid, (ino, range, write));
+      match wait {
+        Wait::Task(waker) => {
+          state.parked.push(waker.clone());
+          return Err(blocked(false));
+        }
+        _ => state = self.changed.wait(state).unwrap()
+      }
+    }
+  }
+
+  /// Stops counting `pid` as waiting, for a parked task that gave up.
+  pub fn cancel(&self, pid: u32) {
+    self.state.lock().unwrap().waiting.remove(&pid);
+  }
+
+  /// Finds a lock that would stop `pid` from locking `range` of `ino`,
+  /// returning its owner, range and whether it's a write lock.
+  pub fn get(&self, ino: u64, pid: u32, kind: LockType, range: Range) -> Option<(u32, Range, bool)> {
+    let state = self.state.lock().unwrap();
+    let lock = state.files.get(&ino)?.range_conflict(pid, range, kind == LockType::Write)?;
+    Some((lock.pid, lock.range, lock.write))
+  }
+
+  /// Drops what closing `handle` releases: its own `flock` lock, and every
+  /// byte-range lock on `ino` held by a process that locked through it.
+  pub fn release(&self, ino: u64, handle: usize) {
+    let mut state = self.state.lock().unwrap();
+    if let Some(locks) = state.files.get_mut(&ino) {
+      let pids: Vec<u32> = locks.users.iter().filter(|u| u.0 == handle).map(|u| u.1).collect();
+      locks.flocks.retain(|&(h, _)| h != handle);
+      locks.ranges.retain(|lock| !pids.contains(&lock.pid));
+      locks.users.retain(|&(h, pid)| h != handle && !pids.contains(&pid));
+      self.wake(&mut state);
+    }
+    LockTable::prune(&mut state, ino);
+  }
+
+  fn wake(&self, state: &mut MutexGuard<State>) {
+    self.changed.notify_all();
+    state.parked.drain(..).for_each(Waker::wake);
+  }
+
+  fn prune(state: &mut State, ino: u64) {
+    if state.files.get(&ino).is_some_and(FileLocks::is_empty) {
+      state.files.remove(&ino);
+    }
+  }
+}
+
+/// A byte-range lock as `fcntl` takes and reports it, like `struct flock`.
+/// The range starts `start` bytes from the point `whence` names and runs
+/// for `len` bytes, or to the end of the file if `len` is 0. As POSIX
+/// allows, a negative `len` covers the `-len` bytes before `start` instead.
+/// `pid` is the process the lock belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileLock {
+  pub kind: LockType,
+  pub whence: Whence,
+  pub start: isize,
+  pub len: isize,
+  pub pid: u32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{LockTable, LockType, Wait};
+
+  #[test]
+  fn test_unlocked_files_are_pruned() {
+    let table = LockTable::new();
+    table.set(1, 10, 100, LockType::Write, (0, 10), Wait::Never).unwrap();
+    table.set(1, 10, 100, LockType::Read, (20, 30), Wait::Never).unwrap();
+    table.set(1, 10, 100, LockType::Unlock, (0, 10), Wait::Never).unwrap();
+    assert!(!table.state.lock().unwrap().files.is_empty());
+    table.set(1, 10, 100, LockType::Unlock, (0, usize::MAX), Wait::Never).unwrap();
+    assert!(table.state.lock().unwrap().files.is_empty());
+  }
+}
@@ -0,0 +1,440 @@
+use std::cmp;
+use std::io::{Result, Error};
+use std::ops::{Deref, DerefMut};
+use std::sync::RwLockWriteGuard;
+use libc::{EACCES, EFAULT};
+use crate::Vfs;
+use crate::file::ArcInode;
+use crate::inode::{ArcPage, Inode, Page, PAGE_SIZE};
+use crate::shared::Shared;
+use crate::transaction::Entered;
+
+// What a hole in a mapping reads as.
+static ZEROS: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+fn bytes(page: Option<&ArcPage>) -> &[u8; PAGE_SIZE] {
+  match page {
+    Some(page) => page,
+    None => &ZEROS
+  }
+}
+
+bitflags!{
+    pub struct MapProt: u32 {
+        const PROT_READ =  0b00000001;
+        const PROT_WRITE = 0b00000010;
+    }
+}
+
+bitflags!{
+    pub struct MapFlags: u32 {
+        const MAP_SHARED =  0b00000001;
+        const MAP_PRIVATE = 0b00000010;
+    }
+}
+
+/// Part of a file mapped into memory by `Vfs::mmap`. Its bytes are the
+/// file's pages themselves: `page` and `page_mut` borrow one of them as a
+/// slice, while `read` and `write` copy in and out at offsets into the
+/// mapping.
+///
+/// A mapping works on the file's own pages rather than a copy of them. What
+/// is stored through a `MAP_SHARED` one is in the file straight away, and
+/// it sees every write to the file as soon as it's made; stores past EOF,
+/// in the page the file ends in, are dropped. A `MAP_PRIVATE` mapping keeps
+/// the pages the file had when it was made, and copies each the first time
+/// it's written to, so nothing written to it reaches the file.
+///
+/// As with SIGBUS, using a mapping where it runs into pages wholly past the
+/// end of the file, as happens once the file is truncated under it, is a
+/// fault, as is going past the end of the mapping: both fail with EFAULT.
+pub struct Mapping<'v, 'r> {
+  vfs: &'v Vfs<'r>,
+  inode: ArcInode,
+  offset: usize,
+  len: usize,
+  prot: MapProt,
+  private: Option<Vec<Option<ArcPage>>> // A private mapping's pages, holes past the end left out
+}
+
+impl<'v, 'r> Mapping<'v, 'r> {
+  pub(crate) fn new(vfs: &'v Vfs<'r>, inode: ArcInode, offset: usize, len: usize, prot: MapProt,
+      flags: MapFlags) -> Mapping<'v, 'r> {
+    let private = if flags.contains(MapFlags::MAP_PRIVATE) {
+      Some(inode.read().unwrap().share_pages(offset, len))
+    } else {
+      None
+    };
+    Mapping { vfs, inode, offset, len, prot, private }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Borrows page `num` of the mapping, as much of it as the mapping covers.
+  /// The page is pinned: it shows the bytes it held when borrowed, however
+  /// the file changes while it's held.
+  pub fn page(&self, num: usize) -> Result<MappedPage> {
+    if self.prot.is_empty() {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    let _tx = self.vfs.enter();
+    let inode = self.inode.read().unwrap();
+    let len = self.check_page(&inode, num)?;
+    let page = match self.private {
+      None => inode.page(self.offset / PAGE_SIZE + num).cloned(),
+      Some(ref pages) => pages.get(num).cloned().flatten()
+    };
+    Ok(MappedPage { page, len })
+  }
+
+  /// Borrows page `num` of the mapping to write to in place. A shared
+  /// mapping's page is the file's own, locked until the borrow ends, when
+  /// anything stored past EOF is dropped. A private mapping's page is
+  /// copied first if it's still the file's.
+  pub fn page_mut(&mut self, num: usize) -> Result<MappedPageMut<'_>> {
+    if !self.prot.contains(MapProt::PROT_WRITE) {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    let tx = self.vfs.enter();
+    if self.private.is_none() {
+      let inode = self.inode.write().unwrap();
+      let len = self.check_page(&inode, num)?;
+      let target = PageTarget::Shared(inode, self.offset / PAGE_SIZE + num);
+      return Ok(MappedPageMut { target, len, written: false, _tx: tx });
+    }
+
+    let len = self.check_page(&self.inode.read().unwrap(), num)?;
+    let pages = self.private.as_mut().unwrap();
+    if num >= pages.len() {
+      pages.resize(num + 1, None);
+    }
+    let target = PageTarget::Private(Page::make_mut(&mut pages[num]));
+    Ok(MappedPageMut { target, len, written: false, _tx: tx })
+  }
+
+  /// Reads `buf.len()` bytes from `pos` in the mapping.
+  pub fn read(&self, pos: usize, buf: &mut [u8]) -> Result<()> {
+    if self.prot.is_empty() {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    let _tx = self.vfs.enter();
+    let inode = self.inode.read().unwrap();
+    self.check_bounds(&inode, pos, buf.len())?;
+    buf.iter_mut().for_each(|b| *b = 0);
+    match self.private {
+      None => { inode.read(self.offset + pos, buf); }
+      Some(ref pages) => {
+        let mut done = 0;
+        while done < buf.len() {
+          let (num, page_offset) = ((pos + done) / PAGE_SIZE, (pos + done) % PAGE_SIZE);
+          let len = cmp::min(buf.len() - done, PAGE_SIZE - page_offset);
+          if let Some(Some(page)) = pages.get(num) {
+            buf[done..done + len].copy_from_slice(&page[page_offset..page_offset + len]);
+          }
+          done += len;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes `data` at `pos` in the mapping.
+  pub fn write(&mut self, pos: usize, data: &[u8]) -> Result<()> {
+    if !self.prot.contains(MapProt::PROT_WRITE) {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    let _tx = self.vfs.enter();
+    if self.private.is_none() {
+      let mut inode = self.inode.write().unwrap();
+      self.check_bounds(&inode, pos, data.len())?;
+      inode.store(self.offset + pos, data);
+      return Ok(());
+    }
+
+    self.check_bounds(&self.inode.read().unwrap(), pos, data.len())?;
+    let pages = self.private.as_mut().unwrap();
+    let mut done = 0;
+    while done < data.len() {
+      let (num, page_offset) = ((pos + done) / PAGE_SIZE, (pos + done) % PAGE_SIZE);
+      let len = cmp::min(data.len() - done, PAGE_SIZE - page_offset);
+      if num >= pages.len() {
+        pages.resize(num + 1, None);
+      }
+      let page = Page::make_mut(&mut pages[num]);
+      page[page_offset..page_offset + len].copy_from_slice(&data[done..done + len]);
+      done += len;
+    }
+    Ok(())
+  }
+
+  // Page `num` must be in the mapping, and no further than the one the file
+  // ends in. Returns how much of it the mapping covers.
+  fn check_page(&self, inode: &Inode, num: usize) -> Result<usize> {
+    let pos = match num.checked_mul(PAGE_SIZE) {
+      Some(pos) if pos < self.len => pos,
+      _ => return Err(Error::from_raw_os_error(EFAULT))
+    };
+    let len = cmp::min(PAGE_SIZE, self.len - pos);
+    self.check_bounds(inode, pos, len)?;
+    Ok(len)
+  }
+
+  // Bytes `pos..pos + len` must be in the mapping, and pages there up to the
+  // one the file ends in.
+  fn check_bounds(&self, inode: &Inode, pos: usize, len: usize) -> Result<()> {
+    let end = match pos.checked_add(len) {
+      Some(end) if end <= self.len => end,
+      _ => return Err(Error::from_raw_os_error(EFAULT))
+    };
+    if self.offset + end > inode.size().div_ceil(PAGE_SIZE) * PAGE_SIZE {
+      return Err(Error::from_raw_os_error(EFAULT));
+    }
+
+    Ok(())
+  }
+}
+
+/// A page of a mapping borrowed with `Mapping::page`.
+pub struct MappedPage {
+  page: Option<ArcPage>, // None for a hole
+  len: usize
+}
+
+impl Deref for MappedPage {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &bytes(self.page.as_ref())[..self.len]
+  }
+}
+
+/// A page of a mapping borrowed with `Mapping::page_mut`.
+pub struct MappedPageMut<'m> {
+  target: PageTarget<'m>,
+  len: usize,
+  written: bool,
+  _tx: Entered<'m>
+}
+
+enum PageTarget<'m> {
+  Shared(RwLockWriteGuard<'m, Shared<Inode>>, usize), // The file, and the page's number in it
+  Private(&'m mut Page)
+}
+
+impl<'m> Deref for MappedPageMut<'m> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self.target {
+      PageTarget::Shared(ref inode, num) => &bytes(inode.page(num))[..self.len],
+      PageTarget::Private(ref page) => &page[..self.len]
+    }
+  }
+}
+
+impl<'m> DerefMut for MappedPageMut<'m> {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    self.written = true;
+    match self.target {
+      PageTarget::Shared(ref mut inode, num) => &mut inode.page_mut(num)[..self.len],
+      PageTarget::Private(ref mut page) => &mut page[..self.len]
+    }
+  }
+}
+
+impl<'m> Drop for MappedPageMut<'m> {
+  fn drop(&mut self) {
+    if let PageTarget::Shared(ref mut inode, num) = self.target {
+      if self.written {
+        inode.stored(num);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{MapFlags, MapProt};
+  use crate::{Vfs, FileFlags};
+  use crate::inode::PAGE_SIZE;
+  use std::io::{IoSlice, IoSliceMut};
+
+  const RW: MapProt = MapProt::from_bits_truncate(0b11);
+
+  fn assert_errno<T>(result: std::io::Result<T>, errno: i32) {
+    assert_eq!(result.err().and_then(|e| e.raw_os_error()), Some(errno));
+  }
+
+  fn byte(map: &super::Mapping, pos: usize) -> u8 {
+    let mut buf = [0u8];
+    map.read(pos, &mut buf).unwrap();
+    buf[0]
+  }
+
+  #[test]
+  fn test_shared_mapping() {
+    let p = Vfs::new();
+    let fd = p.open("index", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, &[1u8; PAGE_SIZE + 100]).unwrap();
+    let reader = p.open("index", FileFlags::O_RDONLY).unwrap();
+
+    // Stores reach the file at once, without waiting for msync.
+    let mut map = p.mmap(fd, 0, PAGE_SIZE + 100, RW, MapFlags::MAP_SHARED).unwrap();
+    assert_eq!(byte(&map, PAGE_SIZE + 99), 1);
+    map.write(10, &[2]).unwrap();
+    map.write(PAGE_SIZE, &[3]).unwrap();
+    let mut buf = [0u8; PAGE_SIZE + 1];
+    assert_eq!(p.read(reader, &mut buf).unwrap(), PAGE_SIZE + 1);
+    assert_eq!((buf[9], buf[10], buf[PAGE_SIZE]), (1, 2, 3));
+
+    // Writes through other descriptors show up at once too, and storing a
+    // byte the mapping first saw still overwrites them.
+    p.pwritev(fd, &[IoSlice::new(&[5, 9])], 12).unwrap();
+    assert_eq!(byte(&map, 12), 5);
+    map.write(13, &[1]).unwrap();
+    p.preadv(reader, &mut [std::io::IoSliceMut::new(&mut buf[..2])], 12).unwrap();
+    assert_eq!(&buf[..2], &[5, 1]);
+
+    // Read-only descriptors can't make writable shared mappings.
+    assert_errno(p.mmap(reader, 0, 10, RW, MapFlags::MAP_SHARED), libc::EACCES);
+    assert_errno(p.mmap(fd, 1, 10, RW, MapFlags::MAP_SHARED), libc::EINVAL);
+    assert_errno(p.mmap(fd, 0, 10, RW, MapFlags::all()), libc::EINVAL);
+    assert_errno(p.mmap(fd, PAGE_SIZE, usize::MAX, RW, MapFlags::MAP_SHARED), libc::EINVAL);
+
+    let mut read_only = p.mmap(reader, 0, 10, MapProt::PROT_READ, MapFlags::MAP_SHARED).unwrap();
+    assert_errno(read_only.write(0, &[0]), libc::EACCES);
+    assert_errno(read_only.read(5, &mut [0u8; 6]), libc::EFAULT);
+    map.write(0, &[6]).unwrap();
+    p.munmap(map).unwrap();
+    p.msync(&read_only).unwrap();
+    assert_eq!(byte(&read_only, 0), 6);
+  }
+
+  #[test]
+  fn test_mapped_pages() {
+    let p = Vfs::new();
+    let fd = p.open("index", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, &[1u8; PAGE_SIZE * 2 + 100]).unwrap();
+    let mut map = p.mmap(fd, 0, PAGE_SIZE * 3, RW, MapFlags::MAP_SHARED).unwrap();
+
+    // Pages are the file's own, so storing into one allocates nothing and
+    // reaches the file at once.
+    let blocks = p.statfs("/").unwrap().bfree;
+    let page = map.page(1).unwrap();
+    assert_eq!((page.len(), page[0]), (PAGE_SIZE, 1));
+    drop(page);
+    map.page_mut(1).unwrap()[5] = 2;
+    assert_eq!(p.statfs("/").unwrap().bfree, blocks);
+    let mut buf = [0u8; 1];
+    p.preadv(fd, &mut [IoSliceMut::new(&mut buf)], PAGE_SIZE + 5).unwrap();
+    assert_eq!(buf[0], 2);
+
+    // A borrowed page keeps what it held; the next borrow sees the change.
+    let pinned = map.page(0).unwrap();
+    p.pwritev(fd, &[IoSlice::new(&[3])], 0).unwrap();
+    assert_eq!((pinned[0], map.page(0).unwrap()[0]), (1, 3));
+    drop(pinned);
+
+    // Stores past EOF are dropped, and pages past the mapping or wholly past
+    // EOF fault.
+    map.page_mut(2).unwrap()[100] = 4;
+    p.ftruncate(fd, PAGE_SIZE * 3).unwrap();
+    assert_eq!(map.page(2).unwrap()[100], 0);
+    assert_errno(map.page(3), libc::EFAULT);
+    p.ftruncate(fd, PAGE_SIZE).unwrap();
+    assert_errno(map.page_mut(1), libc::EFAULT);
+
+    // A private mapping's page is copied the first time it's written to.
+    let mut private = p.mmap(fd, 0, PAGE_SIZE, RW, MapFlags::MAP_PRIVATE).unwrap();
+    private.page_mut(0).unwrap()[0] = 5;
+    assert_eq!(p.statfs("/").unwrap().bfree, blocks + 1);
+    assert_eq!((private.page(0).unwrap()[0], map.page(0).unwrap()[0]), (5, 3));
+
+    let mut read_only = p.mmap(fd, 0, PAGE_SIZE, MapProt::PROT_READ, MapFlags::MAP_SHARED).unwrap();
+    assert_errno(read_only.page_mut(0), libc::EACCES);
+  }
+
+  #[test]
+  fn test_private_mapping() {
+    let p = Vfs::new();
+    let writer = p.open("file", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    p.write(writer, b"original").unwrap();
+    let fd = p.open("file", FileFlags::O_RDONLY).unwrap();
+
+    // Private mappings are writable through read-only descriptors, as the
+    // writes stay in the mapping.
+    let mut map = p.mmap(fd, 0, 8, RW, MapFlags::MAP_PRIVATE).unwrap();
+    map.write(0, b"copy").unwrap();
+    let mut buf = [0u8; 8];
+    map.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"copyinal");
+    p.read(fd, &mut buf).unwrap();
+    assert_eq!(&buf, b"original");
+
+    // Nor does it see what's written to the file afterwards.
+    p.pwritev(writer, &[IoSlice::new(b"ORIGINAL")], 0).unwrap();
+    map.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"copyinal");
+    p.munmap(map).unwrap();
+  }
+
+  #[test]
+  fn test_mappings_are_made_lazily() {
+    let p = Vfs::new();
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, b"small").unwrap();
+
+    // Mapping far more than there is to map costs nothing up front.
+    let huge = 1 << 40;
+    for &flags in &[MapFlags::MAP_SHARED, MapFlags::MAP_PRIVATE] {
+      let mut map = p.mmap(fd, 0, huge, RW, flags).unwrap();
+      assert_eq!(map.len(), huge);
+      assert_eq!(byte(&map, 4), b'l');
+      assert_errno(map.write(huge - 1, &[0]), libc::EFAULT);
+    }
+
+    // Writing to a private mapping copies only the page written to.
+    let blocks = p.statfs("/").unwrap().bfree;
+    let mut map = p.mmap(fd, 0, huge, RW, MapFlags::MAP_PRIVATE).unwrap();
+    assert_eq!(p.statfs("/").unwrap().bfree, blocks);
+    map.write(0, b"S").unwrap();
+    assert_eq!(p.statfs("/").unwrap().bfree, blocks - 1);
+    p.munmap(map).unwrap();
+    assert_eq!(p.statfs("/").unwrap().bfree, blocks);
+  }
+
+  #[test]
+  fn test_truncated_mapping_faults() {
+    let p = Vfs::new();
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, &[7u8; PAGE_SIZE * 2]).unwrap();
+    let mut map = p.mmap(fd, 0, PAGE_SIZE * 2, RW, MapFlags::MAP_SHARED).unwrap();
+
+    // Cutting the file into the second page leaves that page mapped, but
+    // cutting it to one page doesn't.
+    p.ftruncate(fd, PAGE_SIZE + 1).unwrap();
+    assert!(map.read(PAGE_SIZE * 2 - 1, &mut [0u8]).is_ok());
+    p.ftruncate(fd, PAGE_SIZE).unwrap();
+    assert_errno(map.read(0, &mut [0u8; PAGE_SIZE * 2]), libc::EFAULT);
+    assert_errno(map.write(PAGE_SIZE, &[0]), libc::EFAULT);
+    assert_eq!(byte(&map, PAGE_SIZE - 1), 7);
+
+    // Nothing stored lands past EOF, and growing the file again brings the
+    // page back, as zeros.
+    p.ftruncate(fd, PAGE_SIZE + 1).unwrap();
+    map.write(PAGE_SIZE, &[8, 8]).unwrap();
+    assert_eq!(p.fstat(fd).unwrap().size, PAGE_SIZE as u64 + 1);
+    p.ftruncate(fd, PAGE_SIZE * 2).unwrap();
+    assert_eq!((byte(&map, PAGE_SIZE), byte(&map, PAGE_SIZE + 1)), (8, 0));
+  }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Result, Error};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use libc::{EBADF, EINVAL};
+
+pub type WatchDescriptor = i32;
+
+/// An inotify instance, with its own watches and queue of events.
+pub type NotifyInstance = i32;
+
+/// How many events an instance's queue holds, as inotify's
+/// `max_queued_events` defaults to.
+pub const MAX_QUEUED_EVENTS: usize = 16384;
+
+// The same bits as inotify's.
+bitflags!{
+    pub struct WatchMask: u32 {
+        const IN_MODIFY =      0x0000_0002;
+        const IN_ATTRIB =      0x0000_0004;
+        const IN_CLOSE_WRITE = 0x0000_0008;
+        const IN_MOVED_FROM =  0x0000_0040;
+        const IN_MOVED_TO =    0x0000_0080;
+        const IN_CREATE =      0x0000_0100;
+        const IN_DELETE =      0x0000_0200;
+        const IN_Q_OVERFLOW =  0x0000_4000; // Events were dropped
+        const IN_IGNORED =     0x0000_8000; // The watch was removed
+        const IN_ISDIR =       0x4000_0000; // The subject is a directory
+        const IN_MOVE = Self::IN_MOVED_FROM.bits | Self::IN_MOVED_TO.bits;
+        const IN_ALL_EVENTS = Self::IN_MODIFY.bits | Self::IN_ATTRIB.bits
+          | Self::IN_CLOSE_WRITE.bits | Self::IN_MOVE.bits | Self::IN_CREATE.bits
+          | Self::IN_DELETE.bits;
+    }
+}
+
+/// Something that happened to a watched file, or in a watched directory.
+/// `name` is the entry it happened to for a directory's watch, and None when
+/// it happened to the watched file itself. The two halves of a rename share a
+/// nonzero `cookie`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchEvent {
+  pub wd: WatchDescriptor,
+  pub mask: WatchMask,
+  pub cookie: u32,
+  pub name: Option<String>
+}
+
+pub type ArcNotifier = Arc<Notifier>;
+
+#[derive(Default)]
+struct Instance {
+  watches: HashMap<u64, (WatchDescriptor, WatchMask)>, // By inode number
+  inos: HashMap<WatchDescriptor, u64>,
+  // The names each inode has in watched directories, so that a directory's
+  // watch hears about what happens to its entries.
+  names: HashMap<u64, Vec<(u64, String)>>,
+  queue: VecDeque<WatchEvent>,
+  last_wd: WatchDescriptor
+}
+
+impl Instance {
+  fn deliver(&mut self, ino: u64, mask: WatchMask, cookie: u32, name: Option<&str>) {
+    let wd = match self.watches.get(&ino) {
+      Some(&(wd, wants)) if wants.intersects(mask) => wd,
+      _ => return
+    };
+
+    self.push(WatchEvent { wd, mask, cookie, name: name.map(String::from) });
+  }
+
+  fn push(&mut self, event: WatchEvent) {
+    // Like inotify, an event identical to the last one unread is dropped,
+    // and one that doesn't fit is replaced by a single IN_Q_OVERFLOW.
+    if self.queue.back() == Some(&event) {
+      return;
+    }
+    if self.queue.len() >= MAX_QUEUED_EVENTS {
+      let overflow = WatchEvent { wd: -1, mask: WatchMask::IN_Q_OVERFLOW, cookie: 0, name: None };
+      if self.queue.back() != Some(&overflow) {
+        self.queue.push_back(overflow);
+      }
+      return;
+    }
+    self.queue.push_back(event);
+  }
+}
+
+#[derive(Default)]
+struct State {
+  instances: HashMap<NotifyInstance, Instance>,
+  last_instance: NotifyInstance
+}
+
+impl State {
+  fn instance(&mut self, instance: NotifyInstance) -> Result<&mut Instance> {
+    self.instances.get_mut(&instance).ok_or_else(|| Error::from_raw_os_error(EBADF))
+  }
+}
+
+/// Inotify instances on a filesystem's inodes, each with its watches and the
+/// queue their events wait in until read. Inodes raise events themselves,
+/// directories for their entries and files for their contents and
+/// attributes.
+#[derive(Default)]
+pub struct Notifier {
+  watching: AtomicUsize, // Lets events go unraised while nothing's watched
+  next_cookie: AtomicU32,
+  state: Mutex<State>
+}
+
+impl Notifier {
+  pub fn add_instance(&self) -> NotifyInstance {
+    let mut state = self.state.lock().unwrap();
+    state.last_instance += 1;
+    let instance = state.last_instance;
+    state.instances.insert(instance, Instance::default());
+    instance
+  }
+
+  /// Drops an instance along with its watches and unread events.
+  pub fn remove_instance(&self, instance: NotifyInstance) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let removed = state.instances.remove(&instance).ok_or_else(|| Error::from_raw_os_error(EBADF))?;
+    self.watching.fetch_sub(removed.watches.len(), Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Watches inode `ino` for the events in `mask`, replacing the mask if
+  /// `instance` already watches it. `entries` are the names and inode
+  /// numbers in it, if it's a directory.
+  pub fn add_watch(&self, instance: NotifyInstance, ino: u64, mask: WatchMask, entries: Vec<(&str, u64)>)
+      -> Result<WatchDescriptor> {
+    let mut state = self.state.lock().unwrap();
+    let instance = state.instance(instance)?;
+    if let Some(watch) = instance.watches.get_mut(&ino) {
+      watch.1 = mask;
+      return Ok(watch.0);
+    }
+
+    instance.last_wd += 1;
+    let wd = instance.last_wd;
+    instance.watches.insert(ino, (wd, mask));
+    instance.inos.insert(wd, ino);
+    for (name, child) in entries {
+      instance.names.entry(child).or_default().push((ino, name.to_string()));
+    }
+    self.watching.fetch_add(1, Ordering::Relaxed);
+    Ok(wd)
+  }
+
+  pub fn remove_watch(&self, instance: NotifyInstance, wd: WatchDescriptor) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    let instance = state.instance(instance)?;
+    let ino = instance.inos.remove(&wd).ok_or_else(|| Error::from_raw_os_error(EINVAL))?;
+    instance.names.values_mut().for_each(|names| names.retain(|&(dir, _)| dir != ino));
+    instance.names.retain(|_, names| !names.is_empty());
+    instance.push(WatchEvent { wd, mask: WatchMask::IN_IGNORED, cookie: 0, name: None });
+    instance.watches.remove(&ino);
+    self.watching.fetch_sub(1, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// A cookie to tie the two halves of a rename together.
+  pub fn next_cookie(&self) -> u32 {
+    self.next_cookie.fetch_add(1, Ordering::Relaxed) + 1
+  }
+
+  /// Raised by directory `dir` when its entry `name`, inode `child`, comes
+  /// or goes.
+  pub fn entry_event(&self, dir: u64, mask: WatchMask, cookie: u32, name: &str, child: u64) {
+    if self.watching.load(Ordering::Relaxed) == 0 {
+      return;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    for instance in state.instances.values_mut() {
+      if !instance.watches.contains_key(&dir) {
+        continue;
+      }
+
+      instance.deliver(dir, mask, cookie, Some(name));
+      if mask.intersects(WatchMask::IN_CREATE | WatchMask::IN_MOVED_TO) {
+        instance.names.entry(child).or_default().push((dir, name.to_string()));
+      } else if let Some(names) = instance.names.get_mut(&child) {
+        names.retain(|(d, n)| *d != dir || n != name);
+        if names.is_empty() {
+          instance.names.remove(&child);
+        }
+      }
+    }
+  }
+
+  /// Raised by inode `ino` about itself. Directories it's in hear about it
+  /// too, under its name there.
+  pub fn inode_event(&self, ino: u64, mask: WatchMask) {
+    if self.watching.load(Ordering::Relaxed) == 0 {
+      return;
+    }
+
+    let mut state = self.state.lock().unwrap();
+    for instance in state.instances.values_mut() {
+      instance.deliver(ino, mask, 0, None);
+      for (dir, name) in instance.names.get(&ino).cloned().unwrap_or_default() {
+        instance.deliver(dir, mask, 0, Some(&name));
+      }
+    }
+  }
+
+  /// Takes every event queued for `instance` so far, oldest first.
+  pub fn read_events(&self, instance: NotifyInstance) -> Result<Vec<WatchEvent>> {
+    Ok(self.state.lock().unwrap().instance(instance)?.queue.drain(..).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Notifier, WatchEvent, WatchMask, MAX_QUEUED_EVENTS};
+
+  #[test]
+  fn test_queue_overflow() {
+    let notifier = Notifier::default();
+    let instance = notifier.add_instance();
+    let quiet = notifier.add_instance();
+    let wd = notifier.add_watch(instance, 1, WatchMask::IN_ALL_EVENTS, Vec::new()).unwrap();
+    for i in 0..MAX_QUEUED_EVENTS + 10 {
+      notifier.entry_event(1, WatchMask::IN_CREATE, 0, &i.to_string(), 2);
+    }
+
+    let events = notifier.read_events(instance).unwrap();
+    assert_eq!(events.len(), MAX_QUEUED_EVENTS + 1);
+    assert_eq!(events[0], WatchEvent { wd, mask: WatchMask::IN_CREATE, cookie: 0, name: Some("0".into()) });
+    assert_eq!(events[MAX_QUEUED_EVENTS].mask, WatchMask::IN_Q_OVERFLOW);
+    assert!(notifier.read_events(quiet).unwrap().is_empty());
+
+    // Reading makes room again.
+    notifier.entry_event(1, WatchMask::IN_DELETE, 0, "0", 2);
+    assert_eq!(notifier.read_events(instance).unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,83 @@
+use std::io::{Result, Error};
+use libc::{EINVAL, ENAMETOOLONG, ENOENT, ENOTDIR, NAME_MAX};
+use crate::Vfs;
+use crate::file::File;
+use crate::directory::DirectoryHandle;
+use crate::perm::Access;
+
+// Path resolution. Paths are '/'-separated and always walked from the root;
+// empty components and "." are skipped, and ".." steps back up the walk.
+impl<'r> Vfs<'r> {
+  /// Walks `path`, checking search permission on every directory looked
+  /// inside. Returns the directories passed through, root first, with the
+  /// file `path` names at the end.
+  pub(crate) fn walk(&self, path: &'r str) -> Result<Vec<File<'r>>> {
+    let mut chain = vec![self.cwd.clone()];
+    for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+      let dir = chain.last().unwrap().clone();
+      if !dir.is_dir() {
+        return Err(Error::from_raw_os_error(ENOTDIR));
+      }
+
+      dir.check_access(&self.cred(), Access::EXEC)?;
+      if name == ".." {
+        if chain.len() > 1 {
+          chain.pop();
+        }
+        continue;
+      }
+
+      match dir.get(name) {
+        Some(file) => chain.push(file),
+        None => return Err(Error::from_raw_os_error(ENOENT))
+      }
+    }
+
+    Ok(chain)
+  }
+
+  pub(crate) fn resolve(&self, path: &'r str) -> Result<File<'r>> {
+    if path.is_empty() {
+      return Err(Error::from_raw_os_error(ENOENT));
+    }
+
+    Ok(self.walk(path)?.pop().unwrap())
+  }
+
+  /// Splits `path` into the directory it lives in and its final name, for
+  /// operations that add or remove that name. The name must be a real entry
+  /// name, not "." or "..".
+  pub(crate) fn resolve_parent(&self, path: &'r str) -> Result<(File<'r>, &'r str)> {
+    let (dir, name, _) = self.resolve_parent_chain(path)?;
+    Ok((dir, name))
+  }
+
+  /// Like `resolve_parent`, but also returns the chain of directories from
+  /// the root down to the parent, as `walk` does.
+  pub(crate) fn resolve_parent_chain(&self, path: &'r str)
+      -> Result<(File<'r>, &'r str, Vec<File<'r>>)> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir_path, name) = match trimmed.rfind('/') {
+      Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+      None => ("", trimmed)
+    };
+
+    if path.is_empty() {
+      return Err(Error::from_raw_os_error(ENOENT));
+    } else if name.is_empty() || name == "." || name == ".." {
+      return Err(Error::from_raw_os_error(EINVAL));
+    } else if name.len() > NAME_MAX as usize {
+      return Err(Error::from_raw_os_error(ENAMETOOLONG));
+    }
+
+    let chain = self.walk(dir_path)?;
+    let dir = chain.last().unwrap().clone();
+    if !dir.is_dir() {
+      return Err(Error::from_raw_os_error(ENOTDIR));
+    }
+
+    // Callers always look `name` up in the parent, so it must be searchable.
+    dir.check_access(&self.cred(), Access::EXEC)?;
+    Ok((dir, name, chain))
+  }
+}
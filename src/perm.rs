@@ -0,0 +1,83 @@
+use std::io::{Result, Error};
+use libc::{EACCES, EPERM};
+
+bitflags!{
+    /// The kinds of access a permission check asks for, laid out like one
+    /// class (owner, group or other) of `rwx` mode bits.
+    pub struct Access: u32 {
+        const READ =  0b100;
+        const WRITE = 0b010;
+        const EXEC =  0b001;
+    }
+}
+
+/// Who is performing an operation: the caller's user and group IDs and any
+/// supplementary groups. Permission checks are made against these.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+  pub uid: u32,
+  pub gid: u32,
+  pub groups: Vec<u32>
+}
+
+impl Default for Credentials {
+  fn default() -> Credentials {
+    Credentials::root()
+  }
+}
+
+impl Credentials {
+  pub fn new(uid: u32, gid: u32, groups: Vec<u32>) -> Credentials {
+    Credentials { uid, gid, groups }
+  }
+
+  pub fn root() -> Credentials {
+    Credentials::new(0, 0, vec![])
+  }
+
+  pub fn is_root(&self) -> bool {
+    self.uid == 0
+  }
+
+  pub fn in_group(&self, gid: u32) -> bool {
+    self.gid == gid || self.groups.contains(&gid)
+  }
+}
+
+/// Checks `want` against an inode's permission bits `mode`, owner and group.
+///
+/// Exactly one class of bits applies: the owner's if `cred` owns the inode,
+/// else the group's if `cred` is in its group, else everyone else's. Root
+/// skips the check, except that it may only execute a file that someone can.
+pub fn check(cred: &Credentials, uid: u32, gid: u32, mode: u32, is_dir: bool, want: Access) -> Result<()> {
+  if cred.is_root() {
+    if want.contains(Access::EXEC) && !is_dir && mode & 0o111 == 0 {
+      return Err(Error::from_raw_os_error(EACCES));
+    }
+
+    return Ok(());
+  }
+
+  let class = if cred.uid == uid {
+    mode >> 6
+  } else if cred.in_group(gid) {
+    mode >> 3
+  } else {
+    mode
+  };
+
+  if Access::from_bits_truncate(class & 0o7).contains(want) {
+    Ok(())
+  } else {
+    Err(Error::from_raw_os_error(EACCES))
+  }
+}
+
+/// Only an inode's owner, or root, may change its mode or group.
+pub fn check_owner(cred: &Credentials, uid: u32) -> Result<()> {
+  if cred.is_root() || cred.uid == uid {
+    Ok(())
+  } else {
+    Err(Error::from_raw_os_error(EPERM))
+  }
+}
@@ -0,0 +1,154 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{Result, Error, ErrorKind, IoSlice, IoSliceMut};
+use std::task::Waker;
+use libc::{EAGAIN, EPIPE};
+
+/// Writes of at most this many bytes to a pipe are atomic: they go in whole
+/// or not at all, so they never interleave with other writers' data.
+pub const PIPE_BUF: usize = 4096;
+
+// How many bytes a pipe holds before writers have to wait, as on Linux.
+const PIPE_CAPACITY: usize = 65536;
+
+/// The ring buffer behind FIFOs and pipes. It also counts the handles open on each
+/// end: readers only see EOF once every writer is gone, and writes fail with
+/// EPIPE once every reader is.
+#[derive(Default)]
+pub struct Pipe {
+  buf: VecDeque<u8>,
+  readers: usize,
+  writers: usize,
+  parked: Vec<Waker> // Tasks to wake once the pipe changes
+}
+
+// What a read or write that can't make progress returns. Under O_NONBLOCK
+// that's EAGAIN, as usual. Otherwise the caller would block, which a
+// filesystem driven from one thread can't do; the error tells a cooperative
+// scheduler to park the caller and retry once the other end has moved.
+pub fn blocked(nonblock: bool) -> Error {
+  if nonblock {
+    Error::from_raw_os_error(EAGAIN)
+  } else {
+    Error::new(ErrorKind::WouldBlock, "operation would block")
+  }
+}
+
+// Whether `error` says to park the caller, rather than to give up.
+pub fn is_blocked(error: &Error) -> bool {
+  error.kind() == ErrorKind::WouldBlock && error.raw_os_error().is_none()
+}
+
+impl Pipe {
+  pub fn new() -> Pipe {
+    Pipe::default()
+  }
+
+  pub fn open(&mut self, read: bool, write: bool) {
+    self.readers += read as usize;
+    self.writers += write as usize;
+  }
+
+  pub fn close(&mut self, read: bool, write: bool) {
+    self.readers -= read as usize;
+    self.writers -= write as usize;
+    // Whatever nobody read is gone once nobody has the pipe open.
+    if self.readers == 0 && self.writers == 0 {
+      self.buf.clear();
+    }
+    self.wake();
+  }
+
+  /// Has `waker` woken the next time the pipe is read, written or closed.
+  pub fn park(&mut self, waker: &Waker) {
+    if !self.parked.iter().any(|w| w.will_wake(waker)) {
+      self.parked.push(waker.clone());
+    }
+  }
+
+  fn wake(&mut self) {
+    self.parked.drain(..).for_each(Waker::wake);
+  }
+
+  pub fn readers(&self) -> usize {
+    self.readers
+  }
+
+  /// Moves as many buffered bytes as fit into `dsts`. An empty pipe reads as
+  /// EOF if nobody can write to it anymore, and blocks otherwise.
+  pub fn read(&mut self, dsts: &mut [IoSliceMut], nonblock: bool) -> Result<usize> {
+    if dsts.iter().all(|d| d.is_empty()) {
+      return Ok(0);
+    } else if self.buf.is_empty() {
+      return if self.writers == 0 { Ok(0) } else { Err(blocked(nonblock)) };
+    }
+
+    let mut read = 0;
+    for dst in dsts.iter_mut() {
+      let len = cmp::min(dst.len(), self.buf.len());
+      for (d, b) in dst[..len].iter_mut().zip(self.buf.drain(..len)) {
+        *d = b;
+      }
+      read += len;
+    }
+
+    self.wake();
+    Ok(read)
+  }
+
+  /// Appends `srcs` to the pipe. Writes of up to `PIPE_BUF` bytes block
+  /// until they fit whole; bigger ones take whatever room there is.
+  pub fn write(&mut self, srcs: &[IoSlice], nonblock: bool) -> Result<usize> {
+    if self.readers == 0 {
+      return Err(Error::from_raw_os_error(EPIPE));
+    }
+
+    let len: usize = srcs.iter().map(|s| s.len()).sum();
+    let room = PIPE_CAPACITY - self.buf.len();
+    if len == 0 {
+      return Ok(0);
+    } else if room == 0 || (len <= PIPE_BUF && room < len) {
+      return Err(blocked(nonblock));
+    }
+
+    let mut written = 0;
+    for src in srcs {
+      let take = cmp::min(src.len(), room - written);
+      self.buf.extend(&src[..take]);
+      written += take;
+    }
+
+    self.wake();
+    Ok(written)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Pipe, PIPE_BUF, PIPE_CAPACITY};
+  use std::io::{ErrorKind, IoSlice, IoSliceMut};
+
+  #[test]
+  fn test_small_writes_are_atomic() {
+    let mut pipe = Pipe::new();
+    pipe.open(true, true);
+
+    // Fill the pipe to just short of a PIPE_BUF-sized write.
+    let big = vec![1u8; PIPE_CAPACITY - PIPE_BUF + 1];
+    assert_eq!(pipe.write(&[IoSlice::new(&big)], true).unwrap(), big.len());
+    let small = vec![2u8; PIPE_BUF];
+    let err = pipe.write(&[IoSlice::new(&small)], true).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+    // Bigger writes are split, taking what room is left.
+    let bigger = vec![3u8; PIPE_BUF + 1];
+    assert_eq!(pipe.write(&[IoSlice::new(&bigger)], false).unwrap(), PIPE_BUF - 1);
+    let err = pipe.write(&[IoSlice::new(&bigger)], false).unwrap_err();
+    assert_eq!((err.kind(), err.raw_os_error()), (ErrorKind::WouldBlock, None));
+
+    // Bytes come out in order.
+    let mut buf = vec![0u8; PIPE_CAPACITY];
+    assert_eq!(pipe.read(&mut [IoSliceMut::new(&mut buf)], true).unwrap(), PIPE_CAPACITY);
+    assert_eq!((buf[big.len() - 1], buf[big.len()]), (1, 3));
+  }
+}
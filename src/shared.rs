@@ -0,0 +1,128 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A value that snapshots can share with the live tree. Reading it goes
+/// straight through. Once it's on a `Clock`, the first change made to it
+/// after a snapshot is taken leaves the value as it was with the snapshot,
+/// which finds it with `as_of`, and works on a copy.
+pub struct Shared<T> {
+  value: Arc<T>,
+  // What the value was as of each snapshot it has changed since, oldest
+  // first: each entry holds for snapshots numbered up to its own number.
+  // Only the snapshots keep them alive.
+  history: Vec<(u64, Weak<T>)>,
+  saved: u64, // The latest snapshot `history` covers
+  clock: Option<Arc<Clock<T>>>
+}
+
+/// Old values kept alive for one snapshot, for as long as it holds on to
+/// this.
+pub type Keep<T> = Mutex<Vec<Arc<T>>>;
+
+/// The snapshots taken of one filesystem, for the `Shared` values of one
+/// type in it. Snapshots are numbered from 1 up, in the order taken.
+pub struct Clock<T> {
+  last: AtomicU64, // The latest snapshot taken
+  latest_live: AtomicU64, // The latest snapshot that may still be around, or 0
+  snapshots: Mutex<Vec<(u64, Weak<Keep<T>>)>>
+}
+
+impl<T> Shared<T> {
+  pub fn new(value: T) -> Shared<T> {
+    Shared::from_arc(Arc::new(value))
+  }
+
+  pub fn from_arc(value: Arc<T>) -> Shared<T> {
+    Shared { value, history: Vec::new(), saved: 0, clock: None }
+  }
+
+  /// Keeps the value as it is for snapshots on `clock` taken from now on,
+  /// whenever it changes.
+  pub fn set_clock(&mut self, clock: &Arc<Clock<T>>) {
+    self.saved = clock.last.load(Ordering::SeqCst);
+    self.clock = Some(clock.clone());
+  }
+
+  /// The value as it is now. Two `Shared`s holding the very same one, as
+  /// snapshots' copies of an unchanged file do, hold equal values.
+  pub fn current(&self) -> Arc<T> {
+    self.value.clone()
+  }
+
+  /// The value as it was when snapshot `epoch` was taken, which must still
+  /// be around.
+  pub fn as_of(&self, epoch: u64) -> Arc<T> {
+    self.history.iter().find(|&&(saved, _)| saved >= epoch)
+      .and_then(|(_, value)| value.upgrade())
+      .unwrap_or_else(|| self.value.clone())
+  }
+}
+
+impl<T> Clone for Shared<T> {
+  fn clone(&self) -> Shared<T> {
+    Shared::from_arc(self.value.clone())
+  }
+}
+
+impl<T> Deref for Shared<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.value
+  }
+}
+
+impl<T: Clone> DerefMut for Shared<T> {
+  fn deref_mut(&mut self) -> &mut T {
+    if let Some(ref clock) = self.clock {
+      if let Some(latest) = clock.keep(&self.value, self.saved) {
+        self.history.retain(|(_, value)| value.strong_count() > 0);
+        self.history.push((latest, Arc::downgrade(&self.value)));
+        self.saved = latest;
+      }
+    }
+
+    Arc::make_mut(&mut self.value)
+  }
+}
+
+impl<T> Default for Clock<T> {
+  fn default() -> Clock<T> {
+    Clock { last: AtomicU64::new(0), latest_live: AtomicU64::new(0), snapshots: Mutex::new(Vec::new()) }
+  }
+}
+
+impl<T> Clock<T> {
+  /// Starts keeping values for a new snapshot, numbered after every one
+  /// before it, for as long as it holds on to what's returned.
+  pub fn add(&self) -> (u64, Arc<Keep<T>>) {
+    let mut snapshots = self.snapshots.lock().unwrap();
+    let epoch = self.last.load(Ordering::SeqCst) + 1;
+    let keep = Arc::new(Mutex::new(Vec::new()));
+    snapshots.push((epoch, Arc::downgrade(&keep)));
+    self.last.store(epoch, Ordering::SeqCst);
+    self.latest_live.store(epoch, Ordering::SeqCst);
+    (epoch, keep)
+  }
+
+  // Keeps `value` for the snapshots since `saved` that are still around,
+  // returning the latest, if there are any.
+  fn keep(&self, value: &Arc<T>, saved: u64) -> Option<u64> {
+    if self.latest_live.load(Ordering::SeqCst) <= saved {
+      return None;
+    }
+
+    let mut snapshots = self.snapshots.lock().unwrap();
+    snapshots.retain(|(_, keep)| keep.strong_count() > 0);
+    self.latest_live.store(snapshots.last().map_or(0, |&(epoch, _)| epoch), Ordering::SeqCst);
+    let mut latest = None;
+    for (epoch, keep) in snapshots.iter().filter(|&&(epoch, _)| epoch > saved) {
+      if let Some(keep) = keep.upgrade() {
+        keep.lock().unwrap().push(value.clone());
+        latest = Some(*epoch);
+      }
+    }
+    latest
+  }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::io::{Result, Error};
+use std::sync::{Arc, Mutex, RwLock};
+use libc::EROFS;
+use crate::Vfs;
+use crate::directory::DirectoryHandle;
+use crate::file::{DirectoryContent, Entries, FifoContent, File, WeakFile};
+use crate::file::File::{DataFile, Directory, Fifo, CharDevice, EmptyFile};
+use crate::inode::Inode;
+use crate::pipe::Pipe;
+use crate::shared::{Clock, Keep, Shared};
+use crate::transaction::Entered;
+
+impl<'r> Vfs<'r> {
+  /// Takes a read-only copy of the whole tree as it is now, which is itself
+  /// a `Vfs` and is read through the same calls. Anything that would change
+  /// it fails with EROFS, as does opening any of its files for writing, and
+  /// reading it never updates access times.
+  ///
+  /// Taking one copies nothing. The snapshot shares every file and directory
+  /// with the live tree, pages included, until the live one is changed; the
+  /// change then works on a copy, leaving the snapshot what it saw. The
+  /// snapshot makes its own files as it comes across them. Inode numbers
+  /// stay as they were.
+  pub fn snapshot(&self) -> Vfs<'r> {
+    let (root, table) = if self.read_only {
+      // A snapshot of a snapshot is the same tree.
+      let table = self.inodes.lock().unwrap();
+      let frozen = table.frozen().expect("snapshot without a frozen tree").clone();
+      (self.cwd.clone(), table.for_snapshot(frozen))
+    } else {
+      // Nothing may be half done when it's taken, as in a transaction.
+      let _gate = if self.entered() { None } else { Some(self.tx_gate.write().unwrap()) };
+      let table = self.inodes.lock().unwrap();
+      let frozen = Arc::new(Frozen::new(table.clocks()));
+      (frozen.freeze(&self.cwd), table.for_snapshot(frozen))
+    };
+
+    let mut snapshot = Vfs::from_tree(root, table, self.credentials());
+    snapshot.read_only = true;
+    *snapshot.capacity.lock().unwrap() = *self.capacity.lock().unwrap();
+    snapshot.set_atime_policy(self.atime_policy());
+    *snapshot.devices.lock().unwrap() = self.devices.lock().unwrap().clone();
+    snapshot
+  }
+
+  /// Whether this is a snapshot, which can't be changed.
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  // Fails with EROFS on a snapshot.
+  pub(crate) fn check_writable(&self) -> Result<()> {
+    if self.read_only {
+      return Err(Error::from_raw_os_error(EROFS));
+    }
+
+    Ok(())
+  }
+
+  /// Like `enter`, for operations that change the tree.
+  pub(crate) fn enter_write(&self) -> Result<Entered<'_>> {
+    self.check_writable()?;
+    Ok(self.enter())
+  }
+
+  // Makes every file in a snapshot, so that all of them can be found by
+  // number.
+  pub(crate) fn fill_all(&self) {
+    let mut dirs = vec![self.cwd.clone()];
+    while let Some(dir) = dirs.pop() {
+      dirs.extend(dir.list().into_iter().map(|(_, file)| file).filter(File::is_dir));
+    }
+  }
+}
+
+/// The clocks that keep what a filesystem's inodes and directories held as
+/// of each of its snapshots.
+#[derive(Clone, Default)]
+pub struct Clocks<'r> {
+  inodes: Arc<Clock<Inode>>,
+  entries: Arc<Clock<Entries<'r>>>
+}
+
+impl<'r> Clocks<'r> {
+  /// Puts a new file's inode, and entries if it's a directory, on the
+  /// clocks.
+  pub fn set(&self, file: &File<'r>) {
+    match *file {
+      DataFile(ref arc) | CharDevice(ref arc) => arc.write().unwrap().set_clock(&self.inodes),
+      Directory(ref arc) => {
+        let mut content = arc.write().unwrap();
+        content.inode.set_clock(&self.inodes);
+        content.entries.set_clock(&self.entries);
+      }
+      Fifo(ref arc) => arc.lock().unwrap().inode.set_clock(&self.inodes),
+      EmptyFile => panic!("no such file")
+    }
+  }
+}
+
+/// One snapshot's view of the live tree: it makes the snapshot's files from
+/// the live ones as they were when it was taken, as they're needed. What the
+/// live tree has changed since is kept until it goes.
+pub struct Frozen<'r> {
+  epoch: u64,
+  _kept: (Arc<Keep<Inode>>, Arc<Keep<Entries<'r>>>),
+  files: Mutex<HashMap<u64, WeakFile<'r>>> // Those made so far, by inode number
+}
+
+impl<'r> Frozen<'r> {
+  fn new(clocks: &Clocks<'r>) -> Frozen<'r> {
+    // The clocks are only ever added to together, so they number snapshots
+    // alike.
+    let (epoch, inodes) = clocks.inodes.add();
+    let (_, entries) = clocks.entries.add();
+    Frozen { epoch, _kept: (inodes, entries), files: Mutex::new(HashMap::new()) }
+  }
+
+  pub fn get(&self, ino: u64) -> Option<File<'r>> {
+    self.files.lock().unwrap().get(&ino).and_then(WeakFile::upgrade)
+  }
+
+  // The snapshot's copy of the live `file`, made the first time it's needed.
+  // A directory's entries are left to be filled in when it's first read.
+  fn freeze(self: &Arc<Self>, file: &File<'r>) -> File<'r> {
+    let mut files = self.files.lock().unwrap();
+    let ino = file.with_inode(Inode::ino);
+    if let Some(frozen) = files.get(&ino).and_then(WeakFile::upgrade) {
+      return frozen;
+    }
+
+    let frozen = match *file {
+      DataFile(ref arc) => DataFile(Arc::new(RwLock::new(Shared::from_arc(arc.read().unwrap().as_of(self.epoch))))),
+      CharDevice(ref arc) =>
+        CharDevice(Arc::new(RwLock::new(Shared::from_arc(arc.read().unwrap().as_of(self.epoch))))),
+      Fifo(ref arc) => {
+        // A snapshot's FIFO starts out empty, as pipe contents aren't kept.
+        let inode = Shared::from_arc(arc.lock().unwrap().inode.as_of(self.epoch));
+        Fifo(Arc::new(Mutex::new(Box::new(FifoContent { pipe: Pipe::new(), inode }))))
+      }
+      Directory(ref arc) => {
+        let content = arc.read().unwrap();
+        let inode = Shared::from_arc(content.inode.as_of(self.epoch));
+        let source = Source { entries: content.entries.as_of(self.epoch), frozen: self.clone(), filled: false };
+        let content = DirectoryContent { entries: Shared::new(HashMap::new()), inode, source: Some(source) };
+        Directory(Arc::new(RwLock::new(Box::new(content))))
+      }
+      EmptyFile => panic!("no such file")
+    };
+    files.insert(ino, frozen.downgrade());
+    frozen
+  }
+}
+
+/// Where a snapshot's directory comes from: the live one's entries as they
+/// were when the snapshot was taken, from which its own are made the first
+/// time it's read.
+pub struct Source<'r> {
+  entries: Arc<Entries<'r>>,
+  frozen: Arc<Frozen<'r>>,
+  filled: bool
+}
+
+impl<'r> DirectoryContent<'r> {
+  /// Whether this is a snapshot's directory whose entries are still to be
+  /// made.
+  pub(crate) fn unfilled(&self) -> bool {
+    self.source.as_ref().is_some_and(|source| !source.filled)
+  }
+
+  // Fills in a snapshot's directory from the live one, if it hasn't been.
+  pub(crate) fn fill(&mut self) {
+    if let Some(ref mut source) = self.source {
+      if !source.filled {
+        let entries = source.entries.iter().map(|(&name, child)| (name, source.frozen.freeze(child))).collect();
+        self.entries = Shared::new(entries);
+        source.filled = true;
+      }
+    }
+  }
+}
+
+impl<'r> File<'r> {
+  // Whether `self` and `other` were made by snapshots from the same live
+  // file as it was, everything under it included. Nothing under a
+  // directory needs making to tell.
+  pub(crate) fn same_version(&self, other: &File<'r>) -> bool {
+    match (self.version(), other.version()) {
+      ((a, None), (b, None)) => Arc::ptr_eq(&a, &b),
+      ((a, Some((entries, epoch_a))), (b, Some((entries_b, epoch_b)))) =>
+        Arc::ptr_eq(&a, &b) && Arc::ptr_eq(&entries, &entries_b)
+          && entries.values().all(|child| child.unchanged(epoch_a, epoch_b)),
+      _ => false
+    }
+  }
+
+  // The inode this file holds now, and for a snapshot's directory, the live
+  // entries it comes from and the snapshot's number.
+  fn version(&self) -> (Arc<Inode>, Option<(Arc<Entries<'r>>, u64)>) {
+    match *self {
+      DataFile(ref arc) | CharDevice(ref arc) => (arc.read().unwrap().current(), None),
+      Fifo(ref arc) => (arc.lock().unwrap().inode.current(), None),
+      Directory(ref arc) => {
+        let content = arc.read().unwrap();
+        let source = content.source.as_ref().map(|source| (source.entries.clone(), source.frozen.epoch));
+        (content.inode.current(), source)
+      }
+      EmptyFile => panic!("no such file")
+    }
+  }
+
+  // Whether the live file, and everything under it, were the same when
+  // snapshots `a` and `b` were taken.
+  fn unchanged(&self, a: u64, b: u64) -> bool {
+    match *self {
+      DataFile(ref arc) | CharDevice(ref arc) => {
+        let inode = arc.read().unwrap();
+        Arc::ptr_eq(&inode.as_of(a), &inode.as_of(b))
+      }
+      Fifo(ref arc) => {
+        let content = arc.lock().unwrap();
+        Arc::ptr_eq(&content.inode.as_of(a), &content.inode.as_of(b))
+      }
+      Directory(ref arc) => {
+        let (same_inode, entries_a, entries_b) = {
+          let content = arc.read().unwrap();
+          (Arc::ptr_eq(&content.inode.as_of(a), &content.inode.as_of(b)), content.entries.as_of(a), content.entries.as_of(b))
+        };
+        same_inode && Arc::ptr_eq(&entries_a, &entries_b) && entries_a.values().all(|child| child.unchanged(a, b))
+      }
+      EmptyFile => panic!("no such file")
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Vfs, AtimePolicy, FileFlags, UtimeSpec, XattrFlags};
+  use crate::inode::PAGE_SIZE;
+  use std::io::IoSlice;
+  use std::time::{Duration, UNIX_EPOCH};
+
+  fn read_all(p: &Vfs, path: &'static str) -> Vec<u8> {
+    let fd = p.open(path, FileFlags::O_RDONLY).unwrap();
+    let mut buf = vec![0u8; p.fstat(fd).unwrap().size as usize];
+    p.read(fd, &mut buf).unwrap();
+    p.close(fd);
+    buf
+  }
+
+  #[test]
+  fn test_snapshot_keeps_old_state() {
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    let fd = p.open("dir/file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, b"before").unwrap();
+    let ino = p.stat("dir/file").unwrap().ino;
+
+    let snap = p.snapshot();
+    p.pwritev(fd, &[std::io::IoSlice::new(b"after!")], 0).unwrap();
+    p.chmod("dir/file", 0o600).unwrap();
+    p.setxattr("dir/file", "user.tag", b"new", XattrFlags::empty()).unwrap();
+    p.rename("dir/file", "moved").unwrap();
+    p.mkdir("dir/sub", 0o755).unwrap();
+
+    assert_eq!(read_all(&snap, "dir/file"), b"before");
+    assert_eq!(read_all(&p, "moved"), b"after!");
+    let stat = snap.stat("dir/file").unwrap();
+    assert_eq!((stat.ino, stat.mode & 0o777), (ino, 0o644));
+    assert_eq!(snap.stat_ino(ino).unwrap().ino, ino);
+    assert!(snap.getxattr("dir/file", "user.tag", &mut []).is_err());
+    let names: Vec<_> = snap.read_dir("dir").unwrap().iter().map(|e| e.name).collect();
+    assert_eq!(names, ["file"]);
+    assert!(snap.stat("moved").is_err());
+  }
+
+  #[test]
+  fn test_snapshot_is_read_only() {
+    let p = Vfs::new();
+    p.open("file", FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+    p.mkfifo("fifo", 0o666).unwrap();
+    let snap = p.snapshot();
+    let erofs = |result: std::io::Result<()>| result.unwrap_err().raw_os_error() == Some(libc::EROFS);
+
+    assert!(snap.is_read_only() && !p.is_read_only());
+    assert!(erofs(snap.open("file", FileFlags::O_WRONLY).map(|_| ())));
+    assert!(erofs(snap.open("file", FileFlags::O_RDWR).map(|_| ())));
+    assert!(erofs(snap.open("fifo", FileFlags::O_WRONLY).map(|_| ())));
+    assert!(snap.open("fifo", FileFlags::O_RDONLY).is_ok());
+    assert!(erofs(snap.open("new", FileFlags::O_WRONLY | FileFlags::O_CREAT).map(|_| ())));
+    assert!(erofs(snap.mkdir("dir", 0o755)));
+    assert!(erofs(snap.unlink("file")));
+    assert!(erofs(snap.chmod("file", 0o600)));
+    assert!(erofs(snap.rename("file", "other")));
+    assert_eq!(snap.statfs("/").unwrap().flags & libc::ST_RDONLY, libc::ST_RDONLY);
+
+    // Reading a snapshot doesn't touch the live tree's access times.
+    let atime = p.stat("file").unwrap().atime;
+    let fd = snap.open("file", FileFlags::O_RDONLY).unwrap();
+    snap.read(fd, &mut [0u8; 1]).unwrap();
+    assert_eq!(p.stat("file").unwrap().atime, atime);
+  }
+
+  #[test]
+  fn test_snapshots_copy_nothing_up_front() {
+    let p = Vfs::new();
+    p.mkdir("dir", 0o755).unwrap();
+    let fd = p.open("dir/file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, &[1u8; PAGE_SIZE * 4]).unwrap();
+    let usage = |p: &Vfs| { let stat = p.statfs("/").unwrap(); (stat.bfree, stat.ffree) };
+    let (blocks, inodes) = usage(&p);
+
+    // Only the page and inode a change is made to are copied, the first
+    // time, and only while the snapshot is around to need the old ones.
+    let snap = p.snapshot();
+    assert_eq!(usage(&p), (blocks, inodes));
+    p.pwritev(fd, &[IoSlice::new(b"x")], 0).unwrap();
+    assert_eq!(usage(&p), (blocks - 1, inodes - 1));
+    p.pwritev(fd, &[IoSlice::new(b"y")], 1).unwrap();
+    assert_eq!(usage(&p), (blocks - 1, inodes - 1));
+    assert_eq!(read_all(&snap, "dir/file")[..2], [1, 1]);
+    drop(snap);
+    p.pwritev(fd, &[IoSlice::new(b"z")], 2).unwrap();
+    assert_eq!(usage(&p), (blocks, inodes));
+  }
+
+  #[test]
+  fn test_each_snapshot_keeps_its_own_time() {
+    let p = Vfs::new();
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    let mut snaps = Vec::new();
+    for name in ["one", "two", "three"] {
+      p.pwritev(fd, &[IoSlice::new(name.as_bytes())], 0).unwrap();
+      p.mkdir(name, 0o755).unwrap();
+      snaps.push(p.snapshot());
+    }
+    p.ftruncate(fd, 0).unwrap();
+
+    // Dropping one in between leaves the others as they were.
+    snaps.remove(1);
+    assert_eq!(read_all(&snaps[0], "file"), b"one");
+    assert_eq!(read_all(&snaps[1], "file"), b"three");
+    assert!(snaps[0].stat("two").is_err() && snaps[1].stat("two").is_ok());
+    assert_eq!(read_all(&snaps[1].snapshot(), "file"), b"three");
+    assert!(read_all(&p, "file").is_empty());
+  }
+
+  #[test]
+  fn test_live_reads_leave_snapshot_atimes() {
+    let p = Vfs::new();
+    p.set_atime_policy(AtimePolicy::Strict);
+    let fd = p.open("file", FileFlags::O_RDWR | FileFlags::O_CREAT).unwrap();
+    p.write(fd, b"data").unwrap();
+    let then = UNIX_EPOCH + Duration::from_secs(1000);
+    p.utimens("file", UtimeSpec::Set(then), UtimeSpec::Omit).unwrap();
+    let ino = p.stat("file").unwrap().ino;
+
+    let snap = p.snapshot();
+    read_all(&p, "file");
+    assert_ne!(p.stat("file").unwrap().atime, then);
+    assert_eq!(snap.stat_ino(ino).unwrap().atime, then);
+    assert_eq!(snap.stat("file").unwrap().atime, then);
+  }
+}
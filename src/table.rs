@@ -0,0 +1,105 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::file::{File, WeakFile};
+use crate::snapshot::{Clocks, Frozen};
+use crate::usage::ArcUsage;
+use crate::notify::ArcNotifier;
+
+// The table is swept for dead entries whenever it doubles in size since the
+// last sweep, but never below this many entries.
+const MIN_SWEEP_LEN: usize = 64;
+
+/// Numbers every inode in a filesystem and finds them again by number.
+///
+/// Numbers are handed out in increasing order and never reused, so a number
+/// can't come to mean a different file while anything still holds on to the
+/// old one. The table only holds weak references: it never keeps an inode
+/// alive, and looking up a number whose inode has been freed finds nothing.
+pub struct InodeTable<'r> {
+  inodes: HashMap<u64, WeakFile<'r>>,
+  usage: ArcUsage,
+  notifier: ArcNotifier,
+  clocks: Clocks<'r>,
+  frozen: Option<Arc<Frozen<'r>>>, // Set on a snapshot's, which finds its files there
+  next_ino: u64,
+  sweep_at: usize
+}
+
+impl<'r> Default for InodeTable<'r> {
+  fn default() -> InodeTable<'r> {
+    InodeTable::new()
+  }
+}
+
+impl<'r> InodeTable<'r> {
+  pub fn new() -> InodeTable<'r> {
+    InodeTable {
+      inodes: HashMap::new(),
+      usage: ArcUsage::default(),
+      notifier: ArcNotifier::default(),
+      clocks: Clocks::default(),
+      frozen: None,
+      next_ino: 1,
+      sweep_at: MIN_SWEEP_LEN
+    }
+  }
+
+  /// Gives `file` the next inode number and enters it into the table. From
+  /// then on it counts towards the filesystem's usage, raises its events
+  /// with the filesystem's notifier, and is kept as it was for snapshots.
+  pub fn insert(&mut self, file: &File<'r>) -> u64 {
+    if self.inodes.len() >= self.sweep_at {
+      self.inodes.retain(|_, weak| weak.is_alive());
+      self.sweep_at = cmp::max(MIN_SWEEP_LEN, self.inodes.len() * 2);
+    }
+
+    let ino = self.next_ino;
+    self.next_ino += 1;
+    file.with_inode_mut(|inode| {
+      inode.set_ino(ino);
+      inode.charge_to(&self.usage);
+      inode.set_notifier(&self.notifier);
+    });
+    self.clocks.set(file);
+    self.inodes.insert(ino, file.downgrade());
+    ino
+  }
+
+  /// The table for a snapshot of this table's filesystem, which counts
+  /// towards the same usage and finds its files through `frozen`.
+  pub fn for_snapshot(&self, frozen: Arc<Frozen<'r>>) -> InodeTable<'r> {
+    InodeTable {
+      inodes: HashMap::new(),
+      usage: self.usage.clone(),
+      notifier: self.notifier.clone(),
+      clocks: self.clocks.clone(),
+      frozen: Some(frozen),
+      next_ino: self.next_ino,
+      sweep_at: MIN_SWEEP_LEN
+    }
+  }
+
+  pub fn get(&self, ino: u64) -> Option<File<'r>> {
+    match self.frozen {
+      Some(ref frozen) => frozen.get(ino),
+      None => self.inodes.get(&ino).and_then(WeakFile::upgrade)
+    }
+  }
+
+  pub fn usage(&self) -> &ArcUsage {
+    &self.usage
+  }
+
+  pub fn notifier(&self) -> &ArcNotifier {
+    &self.notifier
+  }
+
+  pub fn clocks(&self) -> &Clocks<'r> {
+    &self.clocks
+  }
+
+  pub fn frozen(&self) -> Option<&Arc<Frozen<'r>>> {
+    self.frozen.as_ref()
+  }
+}
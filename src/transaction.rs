@@ -0,0 +1,412 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::io::{Result, Error};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLockReadGuard};
+use libc::EDEADLK;
+use crate::{Vfs, FileDescriptor, FileFlags, Stat};
+use crate::directory::DirectoryHandle;
+use crate::file::{ArcInode, File, FileHandle};
+use crate::file::File::DataFile;
+use crate::inode::Inode;
+
+// Which Vfs this thread is in an operation on, and how many deep, so that
+// operations calling others, and a transaction's own, don't wait on the
+// gate their thread already holds.
+thread_local!(static ENTERED: Cell<(usize, usize)> = const { Cell::new((0, 0)) });
+
+// Holds a Vfs's transaction gate for one operation, if the thread doesn't
+// already.
+pub(crate) struct Entered<'a> {
+  _gate: Option<RwLockReadGuard<'a, ()>>,
+  counted: bool
+}
+
+impl<'a> Drop for Entered<'a> {
+  fn drop(&mut self) {
+    if self.counted {
+      ENTERED.with(|entered| {
+        let (vfs, depth) = entered.get();
+        entered.set((vfs, depth - 1));
+      });
+    }
+  }
+}
+
+impl<'r> Vfs<'r> {
+  // Whether this thread is already in an operation on this Vfs, or in one
+  // of its transactions.
+  pub(crate) fn entered(&self) -> bool {
+    let (vfs, depth) = ENTERED.get();
+    depth > 0 && vfs == self as *const Vfs as usize
+  }
+
+  // Counts this thread as in an operation on this Vfs.
+  fn count_entered(&self) {
+    let (_, depth) = ENTERED.get();
+    ENTERED.set((self as *const Vfs as usize, depth + 1));
+  }
+
+  /// Waits out any transaction another thread has under way, and keeps new
+  /// ones from starting until the returned guard goes.
+  pub(crate) fn enter(&self) -> Entered<'_> {
+    if self.entered() {
+      self.count_entered();
+      return Entered { _gate: None, counted: true };
+    }
+
+    let gate = self.tx_gate.read().unwrap();
+    // A thread is only ever inside one Vfs, unless a transaction on one
+    // touches another; the other's gate is then held but not counted.
+    let counted = ENTERED.get().1 == 0;
+    if counted {
+      self.count_entered();
+    }
+    Entered { _gate: Some(gate), counted }
+  }
+
+  /// Like `enter`, for I/O through `handle`. Pipes and devices may block,
+  /// and aren't part of any transaction, so only data files wait.
+  pub(crate) fn enter_io(&self, handle: &FileHandle<'r>) -> Option<Entered<'_>> {
+    match handle.file {
+      DataFile(_) => Some(self.enter()),
+      _ => None
+    }
+  }
+
+  /// Runs `f` as a transaction: either everything it does through `tx`
+  /// happens, or, should it fail or panic, none of it does. No other thread
+  /// sees anything in between, as they wait for the transaction to end
+  /// before touching names or file contents. Pipes, devices and locks are
+  /// left out of this and never wait.
+  ///
+  /// Rolling back puts back names and file contents, not timestamps.
+  /// Descriptors `tx` opens are closed once the transaction ends. Anything
+  /// done other than through `tx` isn't rolled back. Starting a transaction
+  /// inside another, on any Vfs, fails with EDEADLK.
+  pub fn transaction<T, F>(&self, f: F) -> Result<T>
+      where F: FnOnce(&mut Transaction<'_, 'r>) -> Result<T> {
+    // The thread may already hold the gate, to this Vfs or another.
+    if ENTERED.get().1 > 0 {
+      return Err(Error::from_raw_os_error(EDEADLK));
+    }
+
+    // The gate is let go before any panic carries on, so as not to poison it.
+    let result = {
+      let _gate = self.tx_gate.write().unwrap();
+      self.count_entered();
+      let _entered = Entered { _gate: None, counted: true };
+
+      let mut tx = Transaction { vfs: self, undo: Vec::new(), saved: HashSet::new(), fds: Vec::new() };
+      let result = panic::catch_unwind(AssertUnwindSafe(|| f(&mut tx)));
+      if !matches!(result, Ok(Ok(_))) {
+        tx.roll_back();
+      }
+      for &fd in &tx.fds {
+        self.close(fd);
+      }
+      result
+    };
+
+    match result {
+      Ok(result) => result,
+      Err(panic) => panic::resume_unwind(panic)
+    }
+  }
+}
+
+// What undoes one change a transaction made. Undos work on directories'
+// entries and inodes directly, so nothing, permissions included, can stop
+// them.
+enum Undo<'r> {
+  Remove(File<'r>, &'r str), // Takes a new file out of a directory
+  Move(File<'r>, &'r str, File<'r>, &'r str), // Moves a renamed file back
+  Relink(File<'r>, &'r str, File<'r>), // Puts a removed file back in a directory
+  Restore(ArcInode, Inode) // Puts back a file's contents
+}
+
+/// The operations a transaction can make; see `Vfs::transaction`. They
+/// work just as the `Vfs` methods of the same names do.
+pub struct Transaction<'a, 'r> {
+  vfs: &'a Vfs<'r>,
+  undo: Vec<Undo<'r>>,
+  saved: HashSet<usize>, // The inodes whose contents are saved in `undo`
+  fds: Vec<FileDescriptor>
+}
+
+impl<'a, 'r> Transaction<'a, 'r> {
+  pub fn open(&mut self, path: &'r str, flags: FileFlags) -> Result<FileDescriptor> {
+    let existed = self.vfs.resolve(path).is_ok();
+    let fd = self.vfs.open(path, flags)?;
+    self.fds.push(fd);
+    if !existed {
+      self.undo.extend(self.parent(path).map(|(dir, name)| Undo::Remove(dir, name)));
+    }
+    Ok(fd)
+  }
+
+  pub fn close(&mut self, fd: FileDescriptor) {
+    self.fds.retain(|&open| open != fd);
+    self.vfs.close(fd);
+  }
+
+  pub fn read(&mut self, fd: FileDescriptor, dst: &mut [u8]) -> Result<usize> {
+    self.vfs.read(fd, dst)
+  }
+
+  pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> Result<usize> {
+    self.save_contents(fd)?;
+    self.vfs.write(fd, src)
+  }
+
+  pub fn ftruncate(&mut self, fd: FileDescriptor, len: usize) -> Result<()> {
+    self.save_contents(fd)?;
+    self.vfs.ftruncate(fd, len)
+  }
+
+  pub fn stat(&mut self, path: &'r str) -> Result<Stat> {
+    self.vfs.stat(path)
+  }
+
+  pub fn mkdir(&mut self, path: &'r str, mode: u32) -> Result<()> {
+    self.vfs.mkdir(path, mode)?;
+    self.undo.extend(self.parent(path).map(|(dir, name)| Undo::Remove(dir, name)));
+    Ok(())
+  }
+
+  pub fn rmdir(&mut self, path: &'r str) -> Result<()> {
+    let removed = self.find(path);
+    self.vfs.rmdir(path)?;
+    self.undo.extend(removed);
+    Ok(())
+  }
+
+  pub fn unlink(&mut self, path: &'r str) -> Result<()> {
+    let removed = self.find(path);
+    self.vfs.unlink(path)?;
+    self.undo.extend(removed);
+    Ok(())
+  }
+
+  pub fn rename(&mut self, old_path: &'r str, new_path: &'r str) -> Result<()> {
+    let replaced = self.find(new_path);
+    let moved = self.find(old_path);
+    let new_parent = self.parent(new_path);
+    self.vfs.rename(old_path, new_path)?;
+    // Renaming a file over itself does nothing, so there's nothing to undo.
+    if let (Some(Undo::Relink(_, _, moved)), Some(Undo::Relink(_, _, replaced))) = (&moved, &replaced) {
+      if moved.is_same(replaced) {
+        return Ok(());
+      }
+    }
+
+    // Moving it back leaves the name free for what it replaced.
+    self.undo.extend(replaced);
+    if let (Some(Undo::Relink(old_dir, old_name, _)), Some((new_dir, new_name))) = (moved, new_parent) {
+      self.undo.push(Undo::Move(new_dir, new_name, old_dir, old_name));
+    }
+    Ok(())
+  }
+
+  // The directory `path` is in, and its name there.
+  fn parent(&self, path: &'r str) -> Option<(File<'r>, &'r str)> {
+    self.vfs.resolve_parent(path).ok()
+  }
+
+  // What would put back the file at `path`, were it removed.
+  fn find(&self, path: &'r str) -> Option<Undo<'r>> {
+    let (dir, name) = self.parent(path)?;
+    let file = dir.get(name)?;
+    Some(Undo::Relink(dir, name, file))
+  }
+
+  // Saves the contents of the data file open at `fd`, the first time it's
+  // changed. The copy shares its pages until they're written to.
+  fn save_contents(&mut self, fd: FileDescriptor) -> Result<()> {
+    let handle = self.vfs.get_handle(fd)?;
+    if let DataFile(ref arc) = handle.file {
+      if self.saved.insert(Arc::as_ptr(arc) as usize) {
+        let mut saved = Inode::new();
+        saved.clone_pages_from(&arc.read().unwrap());
+        self.undo.push(Undo::Restore(arc.clone(), saved));
+      }
+    }
+
+    Ok(())
+  }
+
+  // Undoes everything, last change first, so that each undo finds the tree
+  // as the change left it. Only the transaction has been at the tree since,
+  // but an entry that's somehow gone is passed over rather than panicking
+  // with the gate held.
+  fn roll_back(&mut self) {
+    let _ns = self.vfs.lock_namespace();
+    while let Some(undo) = self.undo.pop() {
+      match undo {
+        Undo::Remove(mut dir, name) => {
+          if let Some(file) = dir.get(name) {
+            dir.remove(name);
+            if file.is_dir() {
+              file.unlink(); // Its "."
+              dir.unlink(); // Its ".."
+            }
+          }
+        }
+        Undo::Move(mut from, from_name, mut to, to_name) => {
+          if let Some(file) = from.get(from_name) {
+            from.remove(from_name);
+            to.insert(to_name, file.clone());
+            // Its ".." goes back to linking to the directory it was in.
+            if file.is_dir() && !from.is_same(&to) {
+              from.unlink();
+              to.link();
+            }
+          }
+        }
+        Undo::Relink(mut dir, name, file) => {
+          dir.insert(name, file.clone());
+          if file.is_dir() {
+            file.link(); // Its "."
+            dir.link(); // Its ".."
+          }
+        }
+        Undo::Restore(arc, saved) => arc.write().unwrap().clone_pages_from(&saved)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{Vfs, Credentials, FileFlags};
+  use std::io::Error;
+  use std::panic::{self, AssertUnwindSafe};
+  use std::sync::{Arc, Barrier};
+  use std::thread;
+
+  fn contents(p: &Vfs<'static>, path: &'static str) -> Vec<u8> {
+    let fd = p.open(path, FileFlags::O_RDONLY).unwrap();
+    let mut buf = vec![0u8; 64];
+    let len = p.read(fd, &mut buf).unwrap();
+    p.close(fd);
+    buf.truncate(len);
+    buf
+  }
+
+  fn setup() -> Vfs<'static> {
+    let p = Vfs::new();
+    p.mkdir("etc", 0o755).unwrap();
+    for (path, data) in [("etc/a", "a=1"), ("etc/b", "b=1"), ("etc/old", "old")] {
+      let fd = p.open(path, FileFlags::O_WRONLY | FileFlags::O_CREAT).unwrap();
+      p.write(fd, data.as_bytes()).unwrap();
+      p.close(fd);
+    }
+    p
+  }
+
+  // Rewrites a, replaces b with a new file, drops old and adds a directory.
+  fn deploy(tx: &mut crate::Transaction<'_, 'static>) -> std::io::Result<()> {
+    let fd = tx.open("etc/a", FileFlags::O_WRONLY)?;
+    tx.ftruncate(fd, 0)?;
+    tx.write(fd, b"a=2")?;
+    let fd = tx.open("etc/b.new", FileFlags::O_WRONLY | FileFlags::O_CREAT)?;
+    tx.write(fd, b"b=2")?;
+    tx.rename("etc/b.new", "etc/b")?;
+    tx.unlink("etc/old")?;
+    tx.mkdir("etc/conf.d", 0o755)
+  }
+
+  #[test]
+  fn test_commit_and_roll_back() {
+    let p = setup();
+    let before: Vec<_> = p.read_dir("etc").unwrap().iter().map(|e| (e.name, e.ino)).collect();
+    let nlink = p.stat("etc").unwrap().nlink;
+
+    let failed = p.transaction(|tx| {
+      deploy(tx)?;
+      Err::<(), _>(Error::from_raw_os_error(libc::EIO))
+    });
+    assert_eq!(failed.unwrap_err().raw_os_error(), Some(libc::EIO));
+    let after: Vec<_> = p.read_dir("etc").unwrap().iter().map(|e| (e.name, e.ino)).collect();
+    assert_eq!(after, before);
+    assert_eq!(p.stat("etc").unwrap().nlink, nlink);
+    assert_eq!(contents(&p, "etc/a"), b"a=1");
+    assert_eq!(contents(&p, "etc/b"), b"b=1");
+
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+      p.transaction(|tx| -> std::io::Result<()> {
+        deploy(tx)?;
+        panic!("deployer crashed");
+      })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(contents(&p, "etc/a"), b"a=1");
+    assert_eq!(contents(&p, "etc/old"), b"old");
+
+    p.transaction(deploy).unwrap();
+    let names: Vec<_> = p.read_dir("etc").unwrap().iter().map(|e| e.name).collect();
+    assert_eq!(names, ["a", "b", "conf.d"]);
+    assert_eq!(contents(&p, "etc/a"), b"a=2");
+    assert_eq!(contents(&p, "etc/b"), b"b=2");
+    assert_eq!(p.transaction(|_| p.transaction(|_| Ok(()))).unwrap_err().raw_os_error(),
+               Some(libc::EDEADLK));
+  }
+
+  #[test]
+  fn test_roll_back_ignores_permissions() {
+    let p = setup();
+    p.chown("etc", Some(1000), Some(1000)).unwrap();
+    p.mkdir("etc/sub", 0o755).unwrap();
+    p.chown("etc/sub", Some(1000), Some(1000)).unwrap();
+    p.set_credentials(Credentials::new(1000, 1000, vec![]));
+    let nlink = p.stat("etc").unwrap().nlink;
+
+    // The directories stop being writable before the changes are undone.
+    let failed = p.transaction(|tx| {
+      tx.open("etc/new", FileFlags::O_WRONLY | FileFlags::O_CREAT)?;
+      tx.mkdir("etc/dir", 0o755)?;
+      tx.rename("etc/sub", "etc/dir/sub")?;
+      tx.unlink("etc/old")?;
+      p.chmod("etc", 0o555)?;
+      p.chmod("etc/dir", 0o555)?;
+      Err::<(), _>(Error::from_raw_os_error(libc::EIO))
+    });
+    assert_eq!(failed.unwrap_err().raw_os_error(), Some(libc::EIO));
+    let names: Vec<_> = p.read_dir("etc").unwrap().iter().map(|e| e.name).collect();
+    assert_eq!(names, ["a", "b", "old", "sub"]);
+    assert_eq!(p.stat("etc").unwrap().nlink, nlink);
+    assert_eq!(contents(&p, "etc/old"), b"old");
+  }
+
+  #[test]
+  fn test_no_partial_states() {
+    let p = Arc::new(setup());
+    let barrier = Arc::new(Barrier::new(2));
+    let reader = {
+      let (p, barrier) = (p.clone(), barrier.clone());
+      thread::spawn(move || {
+        barrier.wait();
+        for _ in 0..1000 {
+          assert!(!contents(&p, "etc/a").contains(&b'?'));
+          assert!(!contents(&p, "etc/b").contains(&b'?'));
+        }
+      })
+    };
+
+    // Each transaction passes through a state nobody may see.
+    barrier.wait();
+    for i in 0..100u8 {
+      p.transaction(|tx| {
+        for path in ["etc/a", "etc/b"] {
+          let fd = tx.open(path, FileFlags::O_WRONLY | FileFlags::O_APPEND)?;
+          tx.write(fd, b"???")?;
+          thread::yield_now();
+          tx.ftruncate(fd, 0)?;
+          tx.write(fd, &[path.as_bytes()[4], b'=', b'0' + i % 10])?;
+        }
+        Ok(())
+      }).unwrap();
+    }
+    reader.join().unwrap();
+  }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub type ArcUsage = Arc<Usage>;
+
+/// How many blocks and inodes a filesystem has allocated. The counts are
+/// kept up to date as inodes and pages come and go, rather than added up
+/// when asked for. Pages shared between cloned files only count once.
+#[derive(Default)]
+pub struct Usage {
+  blocks: AtomicU64,
+  inodes: AtomicU64
+}
+
+impl Usage {
+  pub fn blocks(&self) -> u64 {
+    self.blocks.load(Ordering::Relaxed)
+  }
+
+  pub fn inodes(&self) -> u64 {
+    self.inodes.load(Ordering::Relaxed)
+  }
+
+  pub fn alloc_block(&self) {
+    self.blocks.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn free_block(&self) {
+    self.blocks.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Counts an inode towards its filesystem's usage for as long as it lives.
+pub struct InodeCharge(ArcUsage);
+
+impl InodeCharge {
+  pub fn new(usage: &ArcUsage) -> InodeCharge {
+    usage.inodes.fetch_add(1, Ordering::Relaxed);
+    InodeCharge(usage.clone())
+  }
+
+  pub fn usage(&self) -> &ArcUsage {
+    &self.0
+  }
+}
+
+impl Drop for InodeCharge {
+  fn drop(&mut self) {
+    self.0.inodes.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Filesystem-wide capacity and usage, as returned by `statfs`. Block
+/// counts are in units of `bsize` bytes; `flags` holds `ST_*` mount flags,
+/// as `statvfs(3)`'s `f_flag` does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatFs {
+  pub bsize: u64,
+  pub blocks: u64,
+  pub bfree: u64,
+  pub bavail: u64,
+  pub files: u64,
+  pub ffree: u64,
+  pub namemax: u64,
+  pub flags: u64,
+}
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::io::{Result, Error};
+use libc::{E2BIG, EEXIST, EINVAL, ENODATA, ENOSPC, EOPNOTSUPP, ERANGE};
+
+// Limits match Linux's.
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+pub const XATTR_LIST_MAX: usize = 65536;
+
+const NAMESPACES: [&str; 3] = ["user.", "trusted.", "security."];
+
+bitflags!{
+    pub struct XattrFlags: u32 {
+        const XATTR_CREATE =  0b00000001;
+        const XATTR_REPLACE = 0b00000010;
+    }
+}
+
+/// Namespaced extended attributes attached to an inode. Attributes are kept
+/// sorted by name, so listings come back in a stable order.
+#[derive(Clone, Default, PartialEq)]
+pub struct Xattrs {
+  attrs: BTreeMap<String, Vec<u8>>
+}
+
+fn check_name(name: &str) -> Result<()> {
+  if name.is_empty() || name.len() > XATTR_NAME_MAX {
+    return Err(Error::from_raw_os_error(ERANGE));
+  }
+
+  match NAMESPACES.iter().find(|ns| name.starts_with(*ns)) {
+    Some(ns) if name.len() > ns.len() => Ok(()),
+    _ => Err(Error::from_raw_os_error(EOPNOTSUPP))
+  }
+}
+
+// Mirrors getxattr(2): an empty `dst` asks for the size, a short one is ERANGE.
+fn copy_out(src: &[u8], dst: &mut [u8]) -> Result<usize> {
+  if dst.is_empty() {
+    return Ok(src.len());
+  } else if dst.len() < src.len() {
+    return Err(Error::from_raw_os_error(ERANGE));
+  }
+
+  dst[..src.len()].copy_from_slice(src);
+  Ok(src.len())
+}
+
+impl Xattrs {
+  pub fn set(&mut self, name: &str, value: &[u8], flags: XattrFlags) -> Result<()> {
+    if flags.contains(XattrFlags::XATTR_CREATE | XattrFlags::XATTR_REPLACE) {
+      return Err(Error::from_raw_os_error(EINVAL));
+    }
+
+    check_name(name)?;
+    if value.len() > XATTR_SIZE_MAX {
+      return Err(Error::from_raw_os_error(E2BIG));
+    }
+
+    let exists = self.attrs.contains_key(name);
+    if exists && flags.contains(XattrFlags::XATTR_CREATE) {
+      return Err(Error::from_raw_os_error(EEXIST));
+    } else if !exists && flags.contains(XattrFlags::XATTR_REPLACE) {
+      return Err(Error::from_raw_os_error(ENODATA));
+    } else if !exists && self.list_len() + name.len() + 1 > XATTR_LIST_MAX {
+      return Err(Error::from_raw_os_error(ENOSPC));
+    }
+
+    self.attrs.insert(name.to_string(), value.to_vec());
+    Ok(())
+  }
+
+  pub fn get(&self, name: &str, dst: &mut [u8]) -> Result<usize> {
+    check_name(name)?;
+    match self.attrs.get(name) {
+      Some(value) => copy_out(value, dst),
+      None => Err(Error::from_raw_os_error(ENODATA))
+    }
+  }
+
+  /// Writes every attribute name, each followed by a NUL byte, into `dst`.
+  pub fn list(&self, dst: &mut [u8]) -> Result<usize> {
+    let mut names = Vec::with_capacity(self.list_len());
+    for name in self.attrs.keys() {
+      names.extend_from_slice(name.as_bytes());
+      names.push(b'\0');
+    }
+
+    copy_out(&names, dst)
+  }
+
+  pub fn remove(&mut self, name: &str) -> Result<()> {
+    check_name(name)?;
+    match self.attrs.remove(name) {
+      Some(_) => Ok(()),
+      None => Err(Error::from_raw_os_error(ENODATA))
+    }
+  }
+
+  fn list_len(&self) -> usize {
+    self.attrs.keys().map(|name| name.len() + 1).sum()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Xattrs, XattrFlags, XATTR_NAME_MAX, XATTR_SIZE_MAX};
+  use libc::{E2BIG, EOPNOTSUPP, ERANGE};
+
+  #[test]
+  fn test_xattr_limits() {
+    let mut xattrs = Xattrs::default();
+    let long_name = format!("user.{}", "n".repeat(XATTR_NAME_MAX));
+    let big_value = vec![0u8; XATTR_SIZE_MAX + 1];
+
+    let err = xattrs.set(&long_name, b"v", XattrFlags::empty()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(ERANGE));
+    let err = xattrs.set("user.big", &big_value, XattrFlags::empty()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(E2BIG));
+    assert!(xattrs.set("user.max", &big_value[1..], XattrFlags::empty()).is_ok());
+
+    for name in &["system.posix_acl_access", "user.", "hash"] {
+      let err = xattrs.set(name, b"v", XattrFlags::empty()).unwrap_err();
+      assert_eq!(err.raw_os_error(), Some(EOPNOTSUPP));
+    }
+  }
+}